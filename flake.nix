@@ -48,8 +48,28 @@
           src = craneLib.cleanCargoSource (craneLib.path ./.);
           commonArgs = { inherit src pname version; };
           cargoArtifacts = craneLib.buildDepsOnly commonArgs;
+
+          rime = craneLib.buildPackage (
+            commonArgs
+            // {
+              inherit cargoArtifacts;
+              passthru = { inherit withIndex; };
+            }
+          );
+
+          # Bakes an index written by `rime index build` into the package.
+          withIndex =
+            index:
+            pkgs.symlinkJoin {
+              name = "${pname}-with-index";
+              paths = [ rime ];
+              nativeBuildInputs = [ pkgs.makeWrapper ];
+              postBuild = ''
+                wrapProgram $out/bin/rime --add-flags "--index-dir ${index}"
+              '';
+            };
         in
-        craneLib.buildPackage (commonArgs // { inherit cargoArtifacts; });
+        rime;
 
       overlay = final: prev: {
         rime = mkRime (mkPkgs prev.stdenv.hostPlatform.system);
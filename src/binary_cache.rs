@@ -0,0 +1,433 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::Error;
+use std::time::Duration;
+
+use serde::Serialize;
+use serde_json::{Map, Value, json};
+
+use crate::cache;
+use crate::config;
+use crate::derivation;
+use crate::limits;
+use crate::nix;
+use crate::nix_error::nix_failure;
+use crate::progress;
+
+const NARINFO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The parts of a `.narinfo` file a cache check reports.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct NarInfo {
+    pub(crate) substituter: String,
+    pub(crate) url: Option<String>,
+    pub(crate) compression: Option<String>,
+    pub(crate) file_size: Option<u64>,
+    pub(crate) nar_size: Option<u64>,
+    #[serde(skip)]
+    pub(crate) references: Vec<String>,
+}
+
+/// Returns the substituters nix is configured with.
+pub(crate) fn configured_substituters() -> Result<Vec<String>, Error> {
+    // Run: nix config show substituters
+    let output = nix::command()
+        .args(["config", "show", "substituters"])
+        .output()?;
+    if !output.status.success() {
+        return Err(nix_failure("nix config show", &output));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .map(str::to_string)
+        .collect())
+}
+
+/// Checks which outputs of `installable` and its dependencies are available
+/// from `substituters` by querying their `.narinfo` files, the way nix decides
+/// what to fetch and what to build: a cached output is fetched together with
+/// its runtime references, and an output that is not cached is built, which
+/// needs the outputs of its input derivations. At most `max_queries` paths
+/// are looked up.
+///
+/// Paths that no substituter has are reported separately from paths whose
+/// lookup failed because a substituter was unreachable or overloaded.
+pub(crate) fn check(
+    installable: &str,
+    substituters: &[String],
+    max_queries: usize,
+) -> Result<Value, Error> {
+    let (supported, unsupported): (Vec<String>, Vec<String>) = substituters
+        .iter()
+        .map(|s| s.split_once('?').map_or(s.as_str(), |(url, _)| url))
+        .map(|s| s.trim_end_matches('/').to_string())
+        .partition(|s| ["file://", "http://", "https://"].iter().any(|p| s.starts_with(p)));
+    // Local caches can still be read offline.
+    let remote: Vec<&String> = supported.iter().filter(|s| !s.starts_with("file://")).collect();
+    if config::get().offline && !remote.is_empty() {
+        let remote: Vec<&str> = remote.iter().map(|s| s.as_str()).collect();
+        return Err(cache::offline_error(&format!(
+            "querying the binary caches {}",
+            remote.join(", ")
+        )));
+    }
+
+    let derivations = derivation::show_derivations(installable, true)?;
+    let root = derivation::root(&derivations)
+        .ok_or_else(|| Error::other("nix derivation show printed no derivation"))?
+        .to_string();
+
+    let mut lookups: HashMap<String, Option<NarInfo>> = HashMap::new();
+    let mut will_build: BTreeSet<String> = BTreeSet::new();
+    let mut will_fetch: BTreeSet<String> = BTreeSet::new();
+    let mut missing_references: BTreeSet<String> = BTreeSet::new();
+    // Paths whose lookup failed, and why each substituter could not be asked.
+    let mut unknown: BTreeSet<String> = BTreeSet::new();
+    let mut unreachable: BTreeMap<String, String> = BTreeMap::new();
+    // Output paths to check, with the derivation and output producing them
+    // if known.
+    let mut pending: Vec<(String, Option<String>)> = outputs(&derivations, &root, None)
+        .into_iter()
+        .map(|path| (path, Some(root.clone())))
+        .collect();
+
+    let mut truncated = false;
+    loop {
+        pending.retain(|(path, _)| !lookups.contains_key(store_basename(path)));
+        // Keep the entry that knows the derivation of a path.
+        pending.sort_by(|a, b| {
+            (store_basename(&a.0), a.1.is_none()).cmp(&(store_basename(&b.0), b.1.is_none()))
+        });
+        pending.dedup_by(|a, b| store_basename(&a.0) == store_basename(&b.0));
+        if pending.is_empty() {
+            break;
+        }
+        if lookups.len() + pending.len() > max_queries {
+            truncated = true;
+            pending.truncate(max_queries.saturating_sub(lookups.len()));
+            if pending.is_empty() {
+                break;
+            }
+        }
+        progress::report(format!("querying binary caches for {} paths", pending.len()));
+        let paths: Vec<&str> = pending.iter().map(|(path, _)| path.as_str()).collect();
+        let results = query_all(&paths, &supported);
+
+        let mut next = Vec::new();
+        for ((path, drv), result) in pending.drain(..).zip(results) {
+            let narinfo = match result {
+                Ok(narinfo) => narinfo,
+                Err(failures) => {
+                    for (substituter, error) in failures {
+                        unreachable.entry(substituter).or_insert(error);
+                    }
+                    unknown.insert(path.clone());
+                    lookups.insert(store_basename(&path).to_string(), None);
+                    continue;
+                }
+            };
+            match &narinfo {
+                Some(narinfo) => {
+                    will_fetch.insert(path.clone());
+                    next.extend(narinfo.references.iter().map(|r| (r.clone(), None)));
+                }
+                None => match drv {
+                    Some(drv) => {
+                        if will_build.insert(drv.clone())
+                            && let Some(info) = derivations.get(&drv)
+                        {
+                            for (input, used) in derivation::input_derivations(info) {
+                                next.extend(
+                                    outputs(&derivations, &input, Some(&used))
+                                        .into_iter()
+                                        .map(|path| (path, Some(input.clone()))),
+                                );
+                            }
+                        }
+                    }
+                    None => {
+                        missing_references.insert(path.clone());
+                    }
+                },
+            }
+            lookups.insert(store_basename(&path).to_string(), narinfo);
+        }
+        pending = next;
+    }
+
+    let root_outputs: Map<String, Value> = outputs(&derivations, &root, None)
+        .into_iter()
+        .map(|path| {
+            let narinfo = lookups.get(store_basename(&path)).cloned().flatten();
+            // Whether an output is cached is unknown if its lookup failed.
+            let cached = (!unknown.contains(&path)).then_some(narinfo.is_some());
+            let status = json!({ "cached": cached, "narinfo": narinfo });
+            (path, status)
+        })
+        .collect();
+    let cached = if root_outputs.values().any(|status| status["cached"] == false) {
+        Some(false)
+    } else if root_outputs.values().all(|status| status["cached"] == true) {
+        Some(true)
+    } else {
+        None
+    };
+    let fetched: Vec<&NarInfo> = will_fetch
+        .iter()
+        .filter_map(|path| lookups.get(store_basename(path))?.as_ref())
+        .collect();
+
+    Ok(json!({
+        "installable": installable,
+        "cached": cached,
+        "outputs": root_outputs,
+        "will_build": will_build,
+        "will_fetch": {
+            "paths": fetched.len(),
+            "download_size": fetched.iter().filter_map(|n| n.file_size).sum::<u64>(),
+            "nar_size": fetched.iter().filter_map(|n| n.nar_size).sum::<u64>(),
+        },
+        "missing_references": missing_references,
+        "unknown": unknown,
+        "substituters": supported,
+        "unsupported_substituters": unsupported,
+        "unreachable_substituters": unreachable,
+        "truncated": truncated,
+    }))
+}
+
+/// Returns the output paths of `drv`, or only of the outputs in `used`.
+/// Outputs without a known path, such as floating content-addressed ones, are
+/// left out.
+fn outputs(
+    derivations: &Map<String, Value>,
+    drv: &str,
+    used: Option<&Vec<String>>,
+) -> Vec<String> {
+    let outputs = derivations
+        .get(drv)
+        .and_then(|info| info.get("outputs"))
+        .and_then(Value::as_object);
+    outputs
+        .into_iter()
+        .flatten()
+        .filter(|(name, _)| used.is_none_or(|used| used.contains(name)))
+        .filter_map(|(_, output)| output.get("path")?.as_str())
+        .map(str::to_string)
+        .collect()
+}
+
+fn store_basename(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// Substituters that could not be asked for a path, with the reason.
+type Failures = Vec<(String, String)>;
+
+/// Looks up the `.narinfo` of every path in `substituters`, in parallel.
+fn query_all(paths: &[&str], substituters: &[String]) -> Vec<Result<Option<NarInfo>, Failures>> {
+    let threads = config::get().max_http_requests().clamp(1, 8);
+    let chunk_size = paths.len().div_ceil(threads).max(1);
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = paths
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|path| query(path, substituters))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap_or_default())
+            .collect()
+    })
+}
+
+/// Returns the `.narinfo` of `path` from the first substituter that has it,
+/// or `None` if none has it. Fails if no substituter has it but some could
+/// not be asked.
+fn query(path: &str, substituters: &[String]) -> Result<Option<NarInfo>, Failures> {
+    let Some(hash) = store_basename(path).get(..32) else {
+        return Ok(None);
+    };
+    let mut failures = Vec::new();
+    for substituter in substituters {
+        match fetch_narinfo(substituter, hash) {
+            Ok(Some(body)) => return Ok(Some(parse_narinfo(substituter, &body))),
+            Ok(None) => {}
+            Err(e) => failures.push((substituter.clone(), e.to_string())),
+        }
+    }
+    if failures.is_empty() {
+        Ok(None)
+    } else {
+        Err(failures)
+    }
+}
+
+/// Fetches the `.narinfo` for `hash` from `substituter`, or `None` if the
+/// substituter does not have it.
+fn fetch_narinfo(substituter: &str, hash: &str) -> Result<Option<String>, Error> {
+    if let Some(dir) = substituter.strip_prefix("file://") {
+        return match std::fs::read_to_string(format!("{}/{}.narinfo", dir, hash)) {
+            Ok(body) => Ok(Some(body)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        };
+    }
+    let _permit = limits::http().acquire()?;
+    let response = ureq::AgentBuilder::new()
+        .timeout(NARINFO_TIMEOUT)
+        .build()
+        .get(&format!("{}/{}.narinfo", substituter, hash))
+        .call();
+    match response {
+        Ok(response) => response.into_string().map(Some),
+        // Like nix, treat 403 as missing: S3 buckets answer it for objects
+        // that do not exist.
+        Err(ureq::Error::Status(404 | 403, _)) => Ok(None),
+        Err(e) => Err(Error::other(e.to_string())),
+    }
+}
+
+/// Parses the `Key: value` lines of a `.narinfo` file.
+fn parse_narinfo(substituter: &str, body: &str) -> NarInfo {
+    let fields: BTreeMap<&str, &str> = body
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim(), value.trim()))
+        .collect();
+    let field = |key: &str| fields.get(key).map(|value| value.to_string());
+    let size = |key: &str| fields.get(key).and_then(|value| value.parse().ok());
+    // References are printed as base names; keep the store directory of the
+    // store path so they can be looked up like output paths.
+    let store_dir = fields
+        .get("StorePath")
+        .and_then(|path| path.rsplit_once('/'))
+        .map_or("/nix/store", |(dir, _)| dir);
+    NarInfo {
+        substituter: substituter.to_string(),
+        url: field("URL"),
+        compression: field("Compression"),
+        file_size: size("FileSize"),
+        nar_size: size("NarSize"),
+        references: fields
+            .get("References")
+            .into_iter()
+            .flat_map(|references| references.split_whitespace())
+            .map(|reference| format!("{}/{}", store_dir, reference))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    const HASH: &str = "4jv2wsxbzxmk5w6i7h0mw4jc3bmgvnf2";
+
+    const NARINFO: &str = "\
+StorePath: /nix/store/4jv2wsxbzxmk5w6i7h0mw4jc3bmgvnf2-hello-2.12.1
+URL: nar/1w1fff338fvdw53sqgamddn1b2xgds473pv6y13gizdbqjv4i5p3.nar.xz
+Compression: xz
+FileHash: sha256:1w1fff338fvdw53sqgamddn1b2xgds473pv6y13gizdbqjv4i5p3
+FileSize: 50088
+NarHash: sha256:0yzj6h4qpq5bx3g2r5ajpkbj4kqbdn7zrdj3gb4h4r5z1j7ydl1x
+NarSize: 226560
+References: 4jv2wsxbzxmk5w6i7h0mw4jc3bmgvnf2-hello-2.12.1 a1gqnnz8w6sdrr9jx5l1wzj0wg3bi2qr-glibc-2.39-52
+Deriver: pd2c4lq5k2q6qvvhv0kd4d5dqg6xwy1a-hello-2.12.1.drv
+";
+
+    /// A `file://` binary cache in a temporary directory, removed when
+    /// dropped.
+    struct TempCache(PathBuf);
+
+    impl TempCache {
+        fn new(name: &str) -> TempCache {
+            let dir =
+                std::env::temp_dir().join(format!("rime-cache-{}-{}", std::process::id(), name));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            TempCache(dir)
+        }
+
+        fn url(&self) -> String {
+            format!("file://{}", self.0.display())
+        }
+    }
+
+    impl Drop for TempCache {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn parses_narinfo() {
+        let narinfo = parse_narinfo("https://cache.nixos.org", NARINFO);
+        assert_eq!(narinfo.substituter, "https://cache.nixos.org");
+        assert_eq!(narinfo.compression.as_deref(), Some("xz"));
+        assert_eq!(narinfo.file_size, Some(50088));
+        assert_eq!(narinfo.nar_size, Some(226560));
+        // References become full paths in the store directory of StorePath.
+        assert_eq!(
+            narinfo.references,
+            [
+                "/nix/store/4jv2wsxbzxmk5w6i7h0mw4jc3bmgvnf2-hello-2.12.1",
+                "/nix/store/a1gqnnz8w6sdrr9jx5l1wzj0wg3bi2qr-glibc-2.39-52",
+            ]
+        );
+
+        let narinfo = parse_narinfo(
+            "file:///cache",
+            "StorePath: /custom/store/4jv2wsxbzxmk5w6i7h0mw4jc3bmgvnf2-hello\nReferences: a1gqnnz8w6sdrr9jx5l1wzj0wg3bi2qr-glibc\n",
+        );
+        assert_eq!(
+            narinfo.references,
+            ["/custom/store/a1gqnnz8w6sdrr9jx5l1wzj0wg3bi2qr-glibc"]
+        );
+    }
+
+    #[test]
+    fn queries_file_caches() {
+        let path = format!("/nix/store/{}-hello-2.12.1", HASH);
+        let empty = TempCache::new("empty");
+        let full = TempCache::new("full");
+        std::fs::write(full.0.join(format!("{}.narinfo", HASH)), NARINFO).unwrap();
+
+        assert_eq!(fetch_narinfo(&empty.url(), HASH).unwrap(), None);
+        assert_eq!(
+            fetch_narinfo(&full.url(), HASH).unwrap().as_deref(),
+            Some(NARINFO)
+        );
+
+        // The first cache that has the path answers.
+        let narinfo = query(&path, &[empty.url(), full.url()]).unwrap().unwrap();
+        assert_eq!(narinfo.substituter, full.url());
+        assert!(query(&path, &[empty.url()]).unwrap().is_none());
+    }
+
+    #[test]
+    fn reports_unreadable_caches_as_failures() {
+        let path = format!("/nix/store/{}-hello-2.12.1", HASH);
+        let empty = TempCache::new("missing");
+        let broken = TempCache::new("broken");
+        // A directory where the narinfo should be cannot be read.
+        std::fs::create_dir(broken.0.join(format!("{}.narinfo", HASH))).unwrap();
+
+        assert!(fetch_narinfo(&broken.url(), HASH).is_err());
+        let failures = query(&path, &[empty.url(), broken.url()]).unwrap_err();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].0, broken.url());
+
+        // A cache that has the path makes the failure irrelevant.
+        let full = TempCache::new("available");
+        std::fs::write(full.0.join(format!("{}.narinfo", HASH)), NARINFO).unwrap();
+        assert!(query(&path, &[broken.url(), full.url()]).unwrap().is_some());
+    }
+}
@@ -0,0 +1,103 @@
+use serde_json::{Value, json};
+
+use crate::log::{filter_log, grep_pattern, read_log};
+use crate::nix_error;
+
+/// Lines of a build log that usually explain why the build failed.
+const ERROR_LINE_PATTERN: &str =
+    "error|fatal|failed|undefined reference|no such file|not found|cannot|permission denied";
+
+/// At most this many error lines are extracted from a failed build's log.
+const MAX_ERROR_LINES: usize = 20;
+
+/// Parses the report `nix build --dry-run` prints on stderr, e.g.
+///
+/// ```text
+/// these 2 derivations will be built:
+///   /nix/store/...-foo.drv
+/// these 3 paths will be fetched (1.20 MiB download, 5.10 MiB unpacked):
+///   /nix/store/...-bar
+/// ```
+pub(crate) fn parse_dry_run(stderr: &str) -> Value {
+    let mut will_build = Vec::new();
+    let mut will_fetch = Vec::new();
+    let mut download = None;
+    let mut unpacked = None;
+
+    let mut section: Option<&mut Vec<String>> = None;
+    for line in stderr.lines() {
+        let trimmed = line.trim();
+        if trimmed.ends_with(':') && trimmed.contains(" will be built") {
+            section = Some(&mut will_build);
+        } else if trimmed.ends_with(':') && trimmed.contains(" will be fetched") {
+            if let Some((_, sizes)) = trimmed.split_once('(') {
+                for size in sizes.trim_end_matches("):").split(", ") {
+                    if let Some(size) = size.strip_suffix(" download") {
+                        download = Some(size.to_string());
+                    } else if let Some(size) = size.strip_suffix(" unpacked") {
+                        unpacked = Some(size.to_string());
+                    }
+                }
+            }
+            section = Some(&mut will_fetch);
+        } else if line.starts_with(' ') && trimmed.starts_with('/') {
+            if let Some(paths) = section.as_mut() {
+                paths.push(trimmed.to_string());
+            }
+        } else {
+            section = None;
+        }
+    }
+
+    json!({
+        "will_build": will_build,
+        "will_fetch": will_fetch,
+        "download_size": download,
+        "unpacked_size": unpacked,
+    })
+}
+
+/// Returns the derivation whose builder failed, as reported by nix in the
+/// stderr of a failed build.
+pub(crate) fn failed_derivation(stderr: &str) -> Option<String> {
+    let error_lines = stderr.lines().filter(|line| line.contains("error"));
+    let reported = error_lines
+        .clone()
+        .filter(|line| line.contains("builder for") || line.contains("Cannot build"))
+        .chain(error_lines);
+
+    reported.flat_map(drv_paths).next()
+}
+
+fn drv_paths(line: &str) -> impl Iterator<Item = String> + '_ {
+    line.split(|c: char| c.is_whitespace() || "'‘’\"`".contains(c))
+        .filter(|word| word.starts_with('/') && word.ends_with(".drv"))
+        .map(str::to_string)
+}
+
+/// Explains a failed `nix build`: the parsed error, the failing derivation,
+/// the tail of its log and the lines of the log that look like errors.
+pub(crate) fn triage(stderr: &str, status: Option<i32>, log_lines: usize) -> Value {
+    let drv = failed_derivation(stderr);
+
+    // Logs of remote or never-started builds are not available locally; nix
+    // includes the last lines of the log in its error message instead.
+    let log = drv
+        .as_deref()
+        .and_then(|drv| read_log(drv).ok())
+        .unwrap_or_else(|| stderr.to_string());
+
+    let log_tail = filter_log(&log, None, 0, Some(log_lines));
+    let errors = grep_pattern(ERROR_LINE_PATTERN)
+        .map(|pattern| filter_log(&log, Some(&pattern), 0, Some(MAX_ERROR_LINES)))
+        .unwrap_or_default();
+    let error = nix_error::parse("nix build", status, stderr);
+
+    json!({
+        "success": false,
+        "error": error,
+        "failed_derivation": drv,
+        "error_lines": errors.lines().collect::<Vec<_>>(),
+        "log_tail": log_tail,
+    })
+}
@@ -0,0 +1,109 @@
+use std::fs;
+use std::io::Error;
+use std::path::{Path, PathBuf};
+
+use crate::config;
+
+/// Maximum length of an encoded cache key before it is shortened.
+const CACHE_KEY_LIMIT: usize = 180;
+
+/// Returns the cached answer for `key` in `namespace`, or runs `fetch` and
+/// caches its result.
+///
+/// Online, `fetch` always runs so that answers stay fresh; the cache is only
+/// written. Offline, the cache and the prebuilt index are the only sources and
+/// a miss fails fast with a "not available offline" error instead of touching
+/// the network.
+pub(crate) fn cached<F>(namespace: &str, key: &str, fetch: F) -> Result<String, Error>
+where
+    F: FnOnce() -> Result<String, Error>,
+{
+    if config::get().offline {
+        return read(namespace, key)
+            .or_else(|| read_index(namespace, key))
+            .ok_or_else(|| offline_error(&format!("{namespace} '{key}'")));
+    }
+
+    let body = fetch()?;
+    // Caching is best-effort; a read-only or missing cache directory must not
+    // turn a successful answer into an error.
+    let _ = write(namespace, key, &body);
+    Ok(body)
+}
+
+/// Returns the error reported for answers that would need the network while
+/// running offline.
+pub(crate) fn offline_error(what: &str) -> Error {
+    Error::other(format!(
+        "{what} is not available offline: no cached answer exists (run rime once while online to populate the cache)"
+    ))
+}
+
+/// Returns the entry for `key` in `namespace` from the prebuilt index, if one
+/// is configured and contains it.
+pub(crate) fn read_index(namespace: &str, key: &str) -> Option<String> {
+    let dir = config::get().index_dir()?;
+    fs::read_to_string(entry_path(&dir, namespace, key)).ok()
+}
+
+/// Writes `body` as the entry for `key` in `namespace` below `root`.
+pub(crate) fn write_entry(root: &Path, namespace: &str, key: &str, body: &str) -> Result<(), Error> {
+    let path = entry_path(root, namespace, key);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // Write to a temporary file first so that concurrent readers never see a
+    // partially written entry.
+    let tmp = path.with_extension(format!("tmp.{}", std::process::id()));
+    fs::write(&tmp, body)?;
+    fs::rename(&tmp, &path)
+}
+
+fn read(namespace: &str, key: &str) -> Option<String> {
+    let dir = config::get().cache_dir()?;
+    fs::read_to_string(entry_path(&dir, namespace, key)).ok()
+}
+
+fn write(namespace: &str, key: &str, body: &str) -> Result<(), Error> {
+    match config::get().cache_dir() {
+        Some(dir) => write_entry(&dir, namespace, key, body),
+        None => Ok(()),
+    }
+}
+
+/// Cache directories and indexes share this layout, so an index is simply a
+/// cache that is never written at runtime.
+fn entry_path(root: &Path, namespace: &str, key: &str) -> PathBuf {
+    root.join(namespace).join(encode_key(key))
+}
+
+/// Encodes `key` into a portable file name. Unreserved characters are kept
+/// as-is so entries remain readable; everything else is percent-encoded.
+fn encode_key(key: &str) -> String {
+    let mut out = String::with_capacity(key.len());
+    for &b in key.as_bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'~') {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+
+    if out.len() > CACHE_KEY_LIMIT {
+        // Keep long keys (e.g. URLs) below common file name limits while
+        // staying deterministic across runs and machines.
+        out.truncate(CACHE_KEY_LIMIT);
+        out.push_str(&format!("-{:016x}", fnv1a(key.as_bytes())));
+    }
+    out
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &b in bytes {
+        hash ^= u64::from(b);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
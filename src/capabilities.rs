@@ -0,0 +1,263 @@
+use std::fmt;
+use std::sync::OnceLock;
+use std::thread;
+use std::time::Duration;
+
+use rust_mcp_sdk::schema::Tool;
+
+use crate::config;
+use crate::nix;
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+/// The first nix version with the `flakes` experimental feature.
+const FIRST_FLAKES_VERSION: (u32, u32) = (2, 4);
+const NETWORK_PROBE_URL: &str = "https://cache.nixos.org/nix-cache-info";
+const NETWORK_PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+static CAPABILITIES: OnceLock<Capabilities> = OnceLock::new();
+
+/// What the environment rime runs in supports, detected once at startup.
+#[derive(Debug)]
+pub(crate) struct Capabilities {
+    /// The nix found on PATH, or `None` if running `nix --version` failed.
+    pub(crate) nix: Option<NixVersion>,
+    /// Experimental features nix enables on its own configuration, without
+    /// the ones rime passes to its invocations.
+    pub(crate) experimental_features: Vec<String>,
+    /// Whether the nix store (or daemon) answered a ping.
+    pub(crate) store: bool,
+    /// Whether the network was reachable. Always `false` when offline.
+    pub(crate) network: bool,
+}
+
+#[derive(Debug)]
+pub(crate) struct NixVersion {
+    pub(crate) implementation: NixImplementation,
+    pub(crate) version: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum NixImplementation {
+    CppNix,
+    Lix,
+    Unknown,
+}
+
+/// Something a tool needs from its environment to work.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Requirement {
+    Nix,
+    Flakes,
+    Store,
+    Network,
+}
+
+impl fmt::Display for NixImplementation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NixImplementation::CppNix => write!(f, "Nix"),
+            NixImplementation::Lix => write!(f, "Lix"),
+            NixImplementation::Unknown => write!(f, "unknown nix implementation"),
+        }
+    }
+}
+
+/// Probes the environment and installs the result process-wide. The probes
+/// run concurrently so startup waits for the slowest one only, except for
+/// those taking a nix subprocess slot: they run one after another, since
+/// under a limit of one slot without a queue they would reject each other as
+/// overloaded.
+pub(crate) fn detect() -> &'static Capabilities {
+    let offline = config::get().offline;
+
+    let version_and_store = thread::spawn(|| (probe_nix_version(), probe_store()));
+    let features = thread::spawn(probe_experimental_features);
+    let network = thread::spawn(move || !offline && probe_network());
+
+    let (version, store) = version_and_store.join().unwrap_or((None, false));
+    let capabilities = Capabilities {
+        nix: version,
+        experimental_features: features.join().unwrap_or_default(),
+        store,
+        network: network.join().unwrap_or(false),
+    };
+    CAPABILITIES.get_or_init(|| capabilities)
+}
+
+/// Returns the detected capabilities, or `None` if detection did not run.
+pub(crate) fn get() -> Option<&'static Capabilities> {
+    CAPABILITIES.get()
+}
+
+/// Returns what the tool named `tool` needs from its environment.
+pub(crate) fn requirements(tool: &str) -> &'static [Requirement] {
+    use Requirement::*;
+
+    match tool {
+        "nix_evaluate" | "nix_packages_search" | "nix_flakes_show" | "nix_flakes_metadata"
+        | "nix_derivation_show" | "nix_derivation_diff" | "nix_flakes_lock"
+        | "nix_registry_list" | "nix_hash_mismatch" => {
+            &[Nix, Flakes]
+        }
+        "nix_log" => &[Nix, Store],
+        "nix_flakes_check" => &[Nix, Flakes, Store],
+        "nix_packages_why_depends" | "nix_build" | "nix_path_info" | "nix_closure_graph"
+        | "nix_store_diff_closures" | "nix_store_ls" | "nix_store_cat" => &[Nix, Flakes, Store],
+        "nix_config_check" | "nix_config_show" | "nix_repl" => &[Nix],
+        "manix_search" | "nvf_options_search" | "nixpkgs_options_search" | "nix_prefetch"
+        | "nix_cache_check" | "nix_flakes_update_preview" | "nix_registry_resolve"
+        | "nix_registry_pin" => {
+            &[Nix, Flakes, Network]
+        }
+        "nixos_channels" | "nix_manual_list" | "nix_manual_read" | "nixos_wiki_search"
+        | "nixos_wiki_read" | "nixhub_package_versions" | "home_manager_options_search"
+        | "nvf_manual_list" | "nvf_manual_read" => &[Network],
+        _ => &[],
+    }
+}
+
+impl Capabilities {
+    /// Returns why the tool named `tool` cannot work at all, if it cannot.
+    /// Such tools are hidden from the tool list.
+    pub(crate) fn missing(&self, tool: &str) -> Option<String> {
+        let requirements = requirements(tool);
+        if requirements.contains(&Requirement::Nix) && self.nix.is_none() {
+            return Some("nix was not found on PATH".to_string());
+        }
+        if requirements.contains(&Requirement::Flakes) && !self.supports_flakes() {
+            return Some("the 'flakes' experimental feature is not supported by this nix".to_string());
+        }
+        None
+    }
+
+    /// Returns a warning for tools that might fail in this environment. Such
+    /// tools stay listed but carry the warning in their description.
+    pub(crate) fn degraded(&self, tool: &str) -> Option<String> {
+        let requirements = requirements(tool);
+        if requirements.contains(&Requirement::Store) && !self.store {
+            return Some("the nix store was not accessible at startup".to_string());
+        }
+        // Offline, network tools answer from the cache instead.
+        if requirements.contains(&Requirement::Network) && !self.network && !config::get().offline
+        {
+            return Some("the network was unreachable at startup".to_string());
+        }
+        None
+    }
+
+    /// Hides tools that cannot work and annotates those that might not.
+    pub(crate) fn filter_tools(&self, tools: Vec<Tool>) -> Vec<Tool> {
+        tools
+            .into_iter()
+            .filter(|tool| self.missing(&tool.name).is_none())
+            .map(|mut tool| {
+                if let Some(warning) = self.degraded(&tool.name) {
+                    let description = tool.description.unwrap_or_default();
+                    tool.description = Some(format!(
+                        "{} (Warning: {}; this tool may fail.)",
+                        description, warning
+                    ));
+                }
+                tool
+            })
+            .collect()
+    }
+
+    /// Returns a one-line summary suitable for the server instructions.
+    pub(crate) fn summary(&self) -> String {
+        let nix = match &self.nix {
+            Some(nix) => format!("{} {}", nix.implementation, nix.version),
+            None => "nix not found".to_string(),
+        };
+        let store = if self.store { "accessible" } else { "not accessible" };
+        let network = if config::get().offline {
+            "offline mode"
+        } else if self.network {
+            "reachable"
+        } else {
+            "unreachable"
+        };
+        format!("Detected at startup: {nix}; store {store}; network {network}.")
+    }
+
+    /// Returns whether rime's invocations can use flakes: rime enables the
+    /// feature itself, so it is enough that nix has it, either enabled
+    /// already or as a version that knows it.
+    fn supports_flakes(&self) -> bool {
+        if self.experimental_features.iter().any(|f| f == "flakes") {
+            return true;
+        }
+        let Some(nix) = &self.nix else {
+            return false;
+        };
+        let mut parts = nix.version.split('.').map(|part| part.parse::<u32>());
+        match (parts.next(), parts.next()) {
+            (Some(Ok(major)), Some(Ok(minor))) => (major, minor) >= FIRST_FLAKES_VERSION,
+            // Assume an unrecognized version is recent.
+            _ => true,
+        }
+    }
+}
+
+fn probe_nix_version() -> Option<NixVersion> {
+    let output = nix::command()
+        .args(["--version"])
+        .output_timeout(PROBE_TIMEOUT)
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    parse_nix_version(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Parses the output of `nix --version`, e.g. `nix (Nix) 2.24.9` or
+/// `nix (Lix, like Nix) 2.91.1`.
+fn parse_nix_version(output: &str) -> Option<NixVersion> {
+    let line = output.lines().next()?.trim();
+    let version = line.rsplit(' ').next()?.to_string();
+    let implementation = if line.contains("Lix") {
+        NixImplementation::Lix
+    } else if line.contains("(Nix)") {
+        NixImplementation::CppNix
+    } else {
+        NixImplementation::Unknown
+    };
+    Some(NixVersion {
+        implementation,
+        version,
+    })
+}
+
+fn probe_experimental_features() -> Vec<String> {
+    // Ask nix without rime's own `--extra-experimental-features`, which would
+    // always list the features rime passes. This fails unless nix-command is
+    // enabled.
+    let mut command = nix::bare_command();
+    command.args(["config", "show", "experimental-features"]);
+    let Ok(output) = nix::run_with_timeout(&mut command, PROBE_TIMEOUT) else {
+        return Vec::new();
+    };
+    if !output.status.success() {
+        return Vec::new();
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .map(str::to_string)
+        .collect()
+}
+
+fn probe_store() -> bool {
+    nix::command()
+        .args(["store", "ping"])
+        .output_timeout(PROBE_TIMEOUT)
+        .is_ok_and(|output| output.status.success())
+}
+
+fn probe_network() -> bool {
+    ureq::AgentBuilder::new()
+        .timeout(NETWORK_PROBE_TIMEOUT)
+        .build()
+        .head(NETWORK_PROBE_URL)
+        .call()
+        .is_ok()
+}
@@ -0,0 +1,584 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use serde_json::{Value, json};
+
+use crate::store_path;
+
+/// How many children of a node the text tree lists before summarizing the
+/// rest.
+const TREE_MAX_CHILDREN: usize = 20;
+
+/// Size changes of a package below this many bytes are not reported unless
+/// its versions changed too, like in `nix store diff-closures`.
+const MIN_SIZE_DELTA: i64 = 8 * 1024;
+
+/// The runtime dependency graph of one or more store paths.
+pub(crate) struct Graph {
+    paths: Vec<String>,
+    nar_sizes: Vec<u64>,
+    closure_sizes: Vec<u64>,
+    /// References of each path, without self-references.
+    references: Vec<Vec<usize>>,
+    roots: Vec<usize>,
+    /// Distance of each path from the nearest root.
+    depths: Vec<usize>,
+    /// The size each path adds to the closure: the NAR sizes of everything
+    /// that is only reachable through it, including itself.
+    added_sizes: Vec<u64>,
+    /// Children of each path in the dominator tree.
+    dominated: Vec<Vec<usize>>,
+}
+
+impl Graph {
+    /// Builds the graph from the path info of a closure, as returned by
+    /// [`crate::path_info::path_info`], and its root paths.
+    pub(crate) fn new(closure: &BTreeMap<String, Value>, roots: &[&str]) -> Graph {
+        let paths: Vec<String> = closure.keys().cloned().collect();
+        // References may be printed relative to the store directory.
+        let mut index: HashMap<&str, usize> = HashMap::new();
+        for (i, path) in paths.iter().enumerate() {
+            index.insert(path, i);
+            index.insert(base_name(path), i);
+        }
+        let lookup = |path: &str| index.get(path).or_else(|| index.get(base_name(path))).copied();
+
+        let size = |info: &Value, field: &str| info.get(field).and_then(Value::as_u64).unwrap_or(0);
+        let nar_sizes = closure.values().map(|info| size(info, "narSize")).collect();
+        let closure_sizes = closure.values().map(|info| size(info, "closureSize")).collect();
+        let references = closure
+            .values()
+            .enumerate()
+            .map(|(i, info)| {
+                let mut references: Vec<usize> = info
+                    .get("references")
+                    .and_then(Value::as_array)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(Value::as_str)
+                    .filter_map(lookup)
+                    .filter(|&reference| reference != i)
+                    .collect();
+                references.sort_unstable();
+                references.dedup();
+                references
+            })
+            .collect();
+        let roots = roots.iter().filter_map(|root| lookup(root)).collect();
+
+        let mut graph = Graph {
+            paths,
+            nar_sizes,
+            closure_sizes,
+            references,
+            roots,
+            depths: Vec::new(),
+            added_sizes: Vec::new(),
+            dominated: Vec::new(),
+        };
+        graph.depths = graph.depths();
+        graph.dominated = graph.dominator_tree();
+        graph.added_sizes = graph.added_sizes();
+        graph
+    }
+
+    /// The total NAR size of everything reachable from the roots.
+    pub(crate) fn total_size(&self) -> u64 {
+        (0..self.paths.len())
+            .filter(|&i| self.depths[i] != usize::MAX)
+            .map(|i| self.nar_sizes[i])
+            .sum()
+    }
+
+    fn depths(&self) -> Vec<usize> {
+        let mut depths = vec![usize::MAX; self.paths.len()];
+        let mut queue = std::collections::VecDeque::new();
+        for &root in &self.roots {
+            depths[root] = 0;
+            queue.push_back(root);
+        }
+        while let Some(node) = queue.pop_front() {
+            for &reference in &self.references[node] {
+                if depths[reference] == usize::MAX {
+                    depths[reference] = depths[node] + 1;
+                    queue.push_back(reference);
+                }
+            }
+        }
+        depths
+    }
+
+    /// Computes the dominator tree of the graph below a virtual node
+    /// referring to all roots, with the iterative algorithm of Cooper, Harvey
+    /// and Kennedy. Roots are children of the virtual node and not listed.
+    fn dominator_tree(&self) -> Vec<Vec<usize>> {
+        let n = self.paths.len();
+        let virtual_root = n;
+        let successors = |node: usize| -> &[usize] {
+            if node == virtual_root {
+                &self.roots
+            } else {
+                &self.references[node]
+            }
+        };
+
+        // Reverse postorder from the virtual root.
+        let mut order = Vec::new();
+        let mut visited = vec![false; n + 1];
+        let mut stack = vec![(virtual_root, 0)];
+        visited[virtual_root] = true;
+        while let Some((node, next)) = stack.pop() {
+            if let Some(&child) = successors(node).get(next) {
+                stack.push((node, next + 1));
+                if !visited[child] {
+                    visited[child] = true;
+                    stack.push((child, 0));
+                }
+            } else {
+                order.push(node);
+            }
+        }
+        order.reverse();
+        let mut position = vec![usize::MAX; n + 1];
+        for (i, &node) in order.iter().enumerate() {
+            position[node] = i;
+        }
+
+        let mut predecessors = vec![Vec::new(); n + 1];
+        for &node in &order {
+            for &child in successors(node) {
+                predecessors[child].push(node);
+            }
+        }
+
+        let mut idom = vec![usize::MAX; n + 1];
+        idom[virtual_root] = virtual_root;
+        let intersect = |idom: &[usize], mut a: usize, mut b: usize| {
+            while a != b {
+                while position[a] > position[b] {
+                    a = idom[a];
+                }
+                while position[b] > position[a] {
+                    b = idom[b];
+                }
+            }
+            a
+        };
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &node in order.iter().skip(1) {
+                let mut new_idom = usize::MAX;
+                for &predecessor in &predecessors[node] {
+                    if idom[predecessor] == usize::MAX {
+                        continue;
+                    }
+                    new_idom = if new_idom == usize::MAX {
+                        predecessor
+                    } else {
+                        intersect(&idom, predecessor, new_idom)
+                    };
+                }
+                if idom[node] != new_idom {
+                    idom[node] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+
+        let mut dominated = vec![Vec::new(); n];
+        for &node in order.iter().skip(1) {
+            if idom[node] != virtual_root {
+                dominated[idom[node]].push(node);
+            }
+        }
+        dominated
+    }
+
+    fn added_sizes(&self) -> Vec<u64> {
+        fn added(graph: &Graph, node: usize, sizes: &mut Vec<u64>) -> u64 {
+            let mut size = graph.nar_sizes[node];
+            for &child in &graph.dominated[node] {
+                size += added(graph, child, sizes);
+            }
+            sizes[node] = size;
+            size
+        }
+        let mut sizes = vec![0; self.paths.len()];
+        for &root in &self.roots {
+            added(self, root, &mut sizes);
+        }
+        sizes
+    }
+
+    /// Returns whether `node` is reachable within `max_depth` of a root.
+    fn included(&self, node: usize, max_depth: Option<usize>) -> bool {
+        let depth = self.depths[node];
+        depth != usize::MAX && max_depth.is_none_or(|max| depth <= max)
+    }
+
+    /// Returns the graph as JSON nodes and edges, leaving out paths further
+    /// than `max_depth` from a root.
+    pub(crate) fn to_json(&self, max_depth: Option<usize>) -> Value {
+        let nodes: Vec<Value> = (0..self.paths.len())
+            .filter(|&i| self.included(i, max_depth))
+            .map(|i| {
+                json!({
+                    "path": self.paths[i],
+                    "name": name(&self.paths[i]),
+                    "depth": self.depths[i],
+                    "nar_size": self.nar_sizes[i],
+                    "closure_size": self.closure_sizes[i],
+                    "added_size": self.added_sizes[i],
+                })
+            })
+            .collect();
+        let edges: Vec<Value> = self
+            .edges(max_depth)
+            .map(|(from, to)| json!({ "from": self.paths[from], "to": self.paths[to] }))
+            .collect();
+        json!({
+            "roots": self.roots.iter().map(|&i| &self.paths[i]).collect::<Vec<_>>(),
+            "total_size": self.total_size(),
+            "nodes": nodes,
+            "edges": edges,
+        })
+    }
+
+    /// Returns the graph in Graphviz DOT format, leaving out paths further
+    /// than `max_depth` from a root.
+    pub(crate) fn to_dot(&self, max_depth: Option<usize>) -> String {
+        let mut dot = String::from("digraph closure {\n  rankdir=LR;\n  node [shape=box];\n");
+        for i in (0..self.paths.len()).filter(|&i| self.included(i, max_depth)) {
+            dot.push_str(&format!(
+                "  \"{}\" [label=\"{}\\n{} / {}\"];\n",
+                self.paths[i],
+                name(&self.paths[i]),
+                format_size(self.nar_sizes[i]),
+                format_size(self.closure_sizes[i])
+            ));
+        }
+        for (from, to) in self.edges(max_depth) {
+            dot.push_str(&format!("  \"{}\" -> \"{}\";\n", self.paths[from], self.paths[to]));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    fn edges(&self, max_depth: Option<usize>) -> impl Iterator<Item = (usize, usize)> + '_ {
+        (0..self.paths.len())
+            .filter(move |&i| self.included(i, max_depth))
+            .flat_map(move |from| self.references[from].iter().map(move |&to| (from, to)))
+            .filter(move |&(_, to)| self.included(to, max_depth))
+    }
+
+    /// Returns a text tree like nix-tree's: every path appears once, below
+    /// the path it is only reachable through, with the size it adds to the
+    /// closure. Children are sorted by added size and listed `max_depth`
+    /// levels deep.
+    pub(crate) fn to_tree(&self, max_depth: Option<usize>) -> String {
+        let mut tree = format!(
+            "Total closure size: {} in {} paths\n",
+            format_size(self.total_size()),
+            (0..self.paths.len()).filter(|&i| self.included(i, None)).count()
+        );
+        for &root in &self.roots {
+            tree.push_str(&format!(
+                "{} ({} closure, {} own)\n",
+                name(&self.paths[root]),
+                format_size(self.closure_sizes[root]),
+                format_size(self.nar_sizes[root])
+            ));
+            self.write_children(&mut tree, root, "", 1, max_depth);
+        }
+        tree
+    }
+
+    fn write_children(
+        &self,
+        tree: &mut String,
+        node: usize,
+        prefix: &str,
+        depth: usize,
+        max_depth: Option<usize>,
+    ) {
+        if max_depth.is_some_and(|max| depth > max) {
+            return;
+        }
+        let mut children = self.dominated[node].clone();
+        children.sort_by_key(|&child| Reverse(self.added_sizes[child]));
+        let hidden = children.split_off(children.len().min(TREE_MAX_CHILDREN));
+
+        let count = children.len();
+        for (i, child) in children.into_iter().enumerate() {
+            let last = i + 1 == count && hidden.is_empty();
+            let (branch, indent) = if last { ("└── ", "    ") } else { ("├── ", "│   ") };
+            tree.push_str(&format!(
+                "{}{}{} (+{}, {} closure)\n",
+                prefix,
+                branch,
+                name(&self.paths[child]),
+                format_size(self.added_sizes[child]),
+                format_size(self.closure_sizes[child])
+            ));
+            self.write_children(tree, child, &format!("{}{}", prefix, indent), depth + 1, max_depth);
+        }
+        if !hidden.is_empty() {
+            let size: u64 = hidden.iter().map(|&child| self.added_sizes[child]).sum();
+            tree.push_str(&format!(
+                "{}└── … {} more (+{})\n",
+                prefix,
+                hidden.len(),
+                format_size(size)
+            ));
+        }
+    }
+}
+
+/// Compares two closures, as returned by [`crate::path_info::path_info`],
+/// like `nix store diff-closures`: paths are grouped by package name, and each
+/// package reports its versions before and after and the change in size.
+pub(crate) fn diff(before: &BTreeMap<String, Value>, after: &BTreeMap<String, Value>) -> Value {
+    let (before_packages, after_packages) = (packages(before), packages(after));
+    let names: BTreeSet<&String> = before_packages.keys().chain(after_packages.keys()).collect();
+
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+    let mut changed = Vec::new();
+    for name in names {
+        match (before_packages.get(name), after_packages.get(name)) {
+            (None, Some(package)) => added.push(json!({
+                "name": name,
+                "versions": package.versions,
+                "size": package.size,
+            })),
+            (Some(package), None) => removed.push(json!({
+                "name": name,
+                "versions": package.versions,
+                "size": package.size,
+            })),
+            (Some(old), Some(new)) => {
+                let delta = new.size as i64 - old.size as i64;
+                if old.versions != new.versions || delta.abs() >= MIN_SIZE_DELTA {
+                    changed.push(json!({
+                        "name": name,
+                        "old_versions": old.versions,
+                        "new_versions": new.versions,
+                        "size_delta": delta,
+                    }));
+                }
+            }
+            (None, None) => {}
+        }
+    }
+
+    let total = |closure: &BTreeMap<String, Value>| -> u64 {
+        closure
+            .values()
+            .filter_map(|info| info.get("narSize").and_then(Value::as_u64))
+            .sum()
+    };
+    let (old_size, new_size) = (total(before), total(after));
+    json!({
+        "added": added,
+        "removed": removed,
+        "changed": changed,
+        "old_size": old_size,
+        "new_size": new_size,
+        "size_delta": new_size as i64 - old_size as i64,
+    })
+}
+
+#[derive(Default)]
+struct Package {
+    versions: BTreeSet<String>,
+    size: u64,
+}
+
+/// Groups the paths of a closure by package name, leaving out output names
+/// such as `-dev` from the versions.
+fn packages(closure: &BTreeMap<String, Value>) -> BTreeMap<String, Package> {
+    let mut packages: BTreeMap<String, Package> = BTreeMap::new();
+    for (path, info) in closure {
+        let Ok(parsed) = store_path::parse(path) else {
+            continue;
+        };
+        let version = match &parsed.output {
+            Some(output) => parsed
+                .version
+                .strip_suffix(output.as_str())
+                .map_or(parsed.version.as_str(), |version| version.trim_end_matches('-')),
+            None => parsed.version.as_str(),
+        };
+        let package = packages.entry(parsed.pname).or_default();
+        if !version.is_empty() {
+            package.versions.insert(version.to_string());
+        }
+        package.size += info.get("narSize").and_then(Value::as_u64).unwrap_or(0);
+    }
+    packages
+}
+
+fn base_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// Returns the name of a store path without its hash.
+fn name(path: &str) -> &str {
+    let base = base_name(path);
+    match base.split_once('-') {
+        Some((hash, name)) if hash.len() == 32 => name,
+        _ => base,
+    }
+}
+
+/// Formats a size in bytes with a binary unit, e.g. `1.5 MiB`.
+pub(crate) fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the path info of a closure from `(name, nar size, references)`
+    /// entries, with references given by name.
+    fn closure(entries: &[(&str, u64, &[&str])]) -> BTreeMap<String, Value> {
+        let path = |name: &str| format!("/nix/store/{}-{}", "0".repeat(32), name);
+        entries
+            .iter()
+            .map(|(name, size, references)| {
+                let references: Vec<String> =
+                    references.iter().map(|reference| path(reference)).collect();
+                (
+                    path(name),
+                    json!({ "narSize": size, "references": references }),
+                )
+            })
+            .collect()
+    }
+
+    /// Returns the names of the paths `name` dominates and the size it adds.
+    fn dominated(graph: &Graph, name: &str) -> (Vec<String>, u64) {
+        let node = graph
+            .paths
+            .iter()
+            .position(|path| super::name(path) == name)
+            .unwrap();
+        let mut children: Vec<String> = graph.dominated[node]
+            .iter()
+            .map(|&child| super::name(&graph.paths[child]).to_string())
+            .collect();
+        children.sort();
+        (children, graph.added_sizes[node])
+    }
+
+    #[test]
+    fn shared_dependencies_belong_to_their_common_dominator() {
+        // app references lib-a and lib-b, which both reference glibc; only
+        // lib-a references zlib. glibc also references itself.
+        let closure = closure(&[
+            ("app", 100, &["lib-a", "lib-b"]),
+            ("lib-a", 10, &["glibc", "zlib"]),
+            ("lib-b", 20, &["glibc"]),
+            ("zlib", 1, &[]),
+            ("glibc", 1000, &["glibc"]),
+        ]);
+        let roots: Vec<&str> = closure
+            .keys()
+            .filter(|path| path.ends_with("-app"))
+            .map(String::as_str)
+            .collect();
+        let graph = Graph::new(&closure, &roots);
+
+        assert_eq!(graph.total_size(), 1131);
+        assert_eq!(
+            dominated(&graph, "app"),
+            (vec!["glibc".into(), "lib-a".into(), "lib-b".into()], 1131)
+        );
+        assert_eq!(dominated(&graph, "lib-a"), (vec!["zlib".into()], 11));
+        assert_eq!(dominated(&graph, "lib-b"), (vec![], 20));
+        assert_eq!(dominated(&graph, "glibc"), (vec![], 1000));
+    }
+
+    #[test]
+    fn dependencies_shared_by_roots_are_not_attributed_to_either() {
+        let closure = closure(&[
+            ("app", 100, &["glibc"]),
+            ("tool", 10, &["glibc"]),
+            ("glibc", 1000, &[]),
+        ]);
+        let roots: Vec<&str> = closure
+            .keys()
+            .filter(|path| path.ends_with("-app") || path.ends_with("-tool"))
+            .map(String::as_str)
+            .collect();
+        let graph = Graph::new(&closure, &roots);
+
+        assert_eq!(graph.total_size(), 1110);
+        assert_eq!(dominated(&graph, "app"), (vec![], 100));
+        assert_eq!(dominated(&graph, "tool"), (vec![], 10));
+    }
+
+    #[test]
+    fn diff_groups_outputs_and_versions_by_package() {
+        const MIB: u64 = 1024 * 1024;
+        let before = closure(&[
+            ("openssl-3.0.13", 5 * MIB, &[]),
+            ("openssl-3.0.13-dev", MIB, &[]),
+            ("glibc-2.39-52", 30 * MIB, &[]),
+            ("hello-2.12.1", 100_000, &[]),
+            ("bash-5.2p26", MIB, &[]),
+            ("zlib-1.3.1", 120_000, &[]),
+        ]);
+        let after = closure(&[
+            ("openssl-3.0.14", 5 * MIB + 20 * 1024, &[]),
+            ("openssl-3.0.14-dev", MIB, &[]),
+            // Same version, but grown beyond MIN_SIZE_DELTA.
+            ("glibc-2.39-52", 30 * MIB + 16 * 1024, &[]),
+            // Same version, grown less than MIN_SIZE_DELTA.
+            ("hello-2.12.1", 104_096, &[]),
+            ("bash-5.2p26", MIB, &[]),
+            ("curl-8.7.1", 700_000, &[]),
+        ]);
+
+        let diff = diff(&before, &after);
+        assert_eq!(
+            diff["added"],
+            json!([{ "name": "curl", "versions": ["8.7.1"], "size": 700_000 }])
+        );
+        assert_eq!(
+            diff["removed"],
+            json!([{ "name": "zlib", "versions": ["1.3.1"], "size": 120_000 }])
+        );
+        assert_eq!(
+            diff["changed"],
+            json!([
+                {
+                    "name": "glibc",
+                    "old_versions": ["2.39-52"],
+                    "new_versions": ["2.39-52"],
+                    "size_delta": 16 * 1024,
+                },
+                {
+                    "name": "openssl",
+                    "old_versions": ["3.0.13"],
+                    "new_versions": ["3.0.14"],
+                    "size_delta": 20 * 1024,
+                },
+            ])
+        );
+        let delta = 20 * 1024 + 16 * 1024 + 4096 + 700_000 - 120_000;
+        assert_eq!(diff["size_delta"], delta);
+    }
+}
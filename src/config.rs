@@ -0,0 +1,150 @@
+use std::fmt;
+use std::io::Error;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use serde::Deserialize;
+
+use crate::nix::NixSettings;
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    /// Answer exclusively from caches and local indexes, never touching the
+    /// network. Nix subprocesses are run with `--offline`.
+    pub(crate) offline: bool,
+
+    /// What tools may change on this machine (default: read-only).
+    pub(crate) profile: Profile,
+
+    /// Directory in which fetched documents and search results are cached.
+    ///
+    /// Defaults to `$XDG_CACHE_HOME/rime` (or `~/.cache/rime`).
+    pub(crate) cache_dir: Option<PathBuf>,
+
+    /// Directory holding a prebuilt index written by `rime index build`.
+    ///
+    /// Defaults to `$XDG_DATA_HOME/rime/index` (or `~/.local/share/rime/index`).
+    pub(crate) index_dir: Option<PathBuf>,
+
+    /// Maximum number of concurrently running nix subprocesses (default: 4).
+    pub(crate) max_nix_processes: Option<usize>,
+
+    /// Maximum number of concurrent outbound HTTP requests (default: 8).
+    pub(crate) max_http_requests: Option<usize>,
+
+    /// Maximum number of callers queued for a nix or HTTP slot before new
+    /// callers are rejected as overloaded (default: 32).
+    pub(crate) max_queue_length: Option<usize>,
+
+    /// How nix is invoked: binary, global arguments, environment and
+    /// working directory.
+    pub(crate) nix: NixSettings,
+}
+
+/// Tool-safety profiles, from least to most permissive. Each profile allows
+/// everything the previous ones allow.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Profile {
+    /// Tools only inspect; nothing is built or written.
+    #[default]
+    ReadOnly,
+    /// Tools may build and substitute store paths.
+    Build,
+    /// Tools may also change nix configuration such as the flake registry.
+    Write,
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Profile::ReadOnly => write!(f, "read-only"),
+            Profile::Build => write!(f, "build"),
+            Profile::Write => write!(f, "write"),
+        }
+    }
+}
+
+impl Config {
+    /// Loads the configuration from `path`, or from the default location
+    /// (`$XDG_CONFIG_HOME/rime/config.json`) if no path is given. A missing
+    /// default configuration file yields the default configuration.
+    pub(crate) fn load(path: Option<&Path>) -> Result<Config, Error> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match default_config_path() {
+                Some(path) if path.exists() => path,
+                _ => return Ok(Config::default()),
+            },
+        };
+
+        let body = std::fs::read_to_string(&path).map_err(|e| {
+            Error::other(format!("failed to read config {}: {}", path.display(), e))
+        })?;
+        serde_json::from_str(&body).map_err(|e| {
+            Error::other(format!("failed to parse config {}: {}", path.display(), e))
+        })
+    }
+
+    pub(crate) fn cache_dir(&self) -> Option<PathBuf> {
+        self.cache_dir
+            .clone()
+            .or_else(|| xdg_dir("XDG_CACHE_HOME", ".cache").map(|dir| dir.join("rime")))
+    }
+
+    pub(crate) fn max_nix_processes(&self) -> usize {
+        self.max_nix_processes.unwrap_or(4)
+    }
+
+    pub(crate) fn max_http_requests(&self) -> usize {
+        self.max_http_requests.unwrap_or(8)
+    }
+
+    pub(crate) fn max_queue_length(&self) -> usize {
+        self.max_queue_length.unwrap_or(32)
+    }
+
+    pub(crate) fn index_dir(&self) -> Option<PathBuf> {
+        self.index_dir.clone().or_else(|| {
+            xdg_dir("XDG_DATA_HOME", ".local/share").map(|dir| dir.join("rime").join("index"))
+        })
+    }
+}
+
+/// Installs the process-wide configuration. Must be called before any tool
+/// runs; later calls are ignored.
+pub(crate) fn init(config: Config) {
+    let _ = CONFIG.set(config);
+}
+
+/// Returns the process-wide configuration.
+pub(crate) fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+/// Fails unless the configured profile allows `action`, which needs at least
+/// the `needed` profile.
+pub(crate) fn require_profile(needed: Profile, action: &str) -> Result<(), Error> {
+    let profile = get().profile;
+    if profile >= needed {
+        return Ok(());
+    }
+    Err(Error::other(format!(
+        r#"{} is not allowed by the {} profile; set "profile": "{}" in the config file or pass --profile {}"#,
+        action, profile, needed, needed
+    )))
+}
+
+fn default_config_path() -> Option<PathBuf> {
+    xdg_dir("XDG_CONFIG_HOME", ".config").map(|dir| dir.join("rime").join("config.json"))
+}
+
+fn xdg_dir(var: &str, home_fallback: &str) -> Option<PathBuf> {
+    match std::env::var_os(var) {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => std::env::var_os("HOME").map(|home| PathBuf::from(home).join(home_fallback)),
+    }
+}
@@ -0,0 +1,521 @@
+use std::collections::{BTreeMap, HashSet};
+use std::io::Error;
+
+use serde_json::{Map, Value, json};
+
+use crate::nix;
+use crate::nix_error::nix_failure;
+use crate::store_path;
+
+/// Phases run by stdenv's `genericBuild`, each with the list variables of
+/// extra phases that run before it.
+const STDENV_PHASES: [(&str, &str); 9] = [
+    ("unpackPhase", "prePhases"),
+    ("patchPhase", ""),
+    ("configurePhase", "preConfigurePhases"),
+    ("buildPhase", "preBuildPhases"),
+    ("checkPhase", ""),
+    ("installPhase", "preInstallPhases"),
+    ("fixupPhase", "preFixupPhases"),
+    ("installCheckPhase", ""),
+    ("distPhase", "preDistPhases"),
+];
+
+/// Returns the derivations of `installable` (an installable or `.drv` path)
+/// as printed by `nix derivation show`, keyed by derivation path. With
+/// `recursive`, all derivations it depends on are included.
+pub(crate) fn show_derivations(
+    installable: &str,
+    recursive: bool,
+) -> Result<Map<String, Value>, Error> {
+    // Run: nix derivation show [--recursive] <installable>
+    let mut command = nix::command();
+    command.args(["derivation", "show"]);
+    if recursive {
+        command.args(["--recursive"]);
+    }
+    let output = command.args([installable]).output()?;
+    if !output.status.success() {
+        return Err(nix_failure("nix derivation show", &output));
+    }
+
+    let shown: Map<String, Value> = serde_json::from_slice(&output.stdout)
+        .map_err(|e| Error::other(format!("failed to parse nix output: {}", e)))?;
+    // Newer nix versions wrap the derivations and print paths relative to
+    // the store directory.
+    match shown.get("derivations") {
+        Some(Value::Object(derivations)) => Ok(derivations.clone()),
+        _ => Ok(shown),
+    }
+}
+
+/// Returns the input derivations of `drv` with the outputs used from each.
+/// Handles both the `{ "<drv>": ["out"] }` and the newer
+/// `{ "<drv>": { "outputs": ["out"] } }` formats.
+pub(crate) fn input_derivations(drv: &Value) -> BTreeMap<String, Vec<String>> {
+    let mut inputs = BTreeMap::new();
+    let Some(input_drvs) = drv.get("inputDrvs").and_then(Value::as_object) else {
+        return inputs;
+    };
+    for (path, used) in input_drvs {
+        let outputs = used.get("outputs").unwrap_or(used);
+        let outputs = outputs
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+            .map(str::to_string)
+            .collect();
+        inputs.insert(path.clone(), outputs);
+    }
+    inputs
+}
+
+/// Returns the environment of `drv`.
+pub(crate) fn env(drv: &Value) -> Map<String, Value> {
+    drv.get("env")
+        .and_then(Value::as_object)
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Summarizes a derivation: builder, system, outputs, input derivations
+/// grouped by package name, input sources, environment and stdenv phases.
+/// Environment values are included if `env_values` is set, truncated to
+/// `max_value_length` characters like the builder arguments.
+pub(crate) fn summarize(
+    drv_path: &str,
+    drv: &Value,
+    env_values: bool,
+    max_value_length: usize,
+) -> Value {
+    let env = env(drv);
+    let str_field = |name: &str| drv.get(name).and_then(Value::as_str);
+
+    let outputs: Map<String, Value> = drv
+        .get("outputs")
+        .and_then(Value::as_object)
+        .into_iter()
+        .flatten()
+        .map(|(name, output)| (name.clone(), output.get("path").cloned().unwrap_or(Value::Null)))
+        .collect();
+
+    let mut inputs: BTreeMap<String, Vec<Value>> = BTreeMap::new();
+    for (path, used_outputs) in input_derivations(drv) {
+        let (pname, version) = match store_path::parse(&path) {
+            Ok(parsed) => (parsed.pname, parsed.version),
+            Err(_) => (path.clone(), String::new()),
+        };
+        inputs.entry(pname).or_default().push(json!({
+            "version": version,
+            "outputs": used_outputs,
+            "path": path,
+        }));
+    }
+
+    let input_sources: Vec<&Value> = drv
+        .get("inputSrcs")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .collect();
+
+    let args: Vec<String> = drv
+        .get("args")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .map(|arg| truncate(arg, max_value_length))
+        .collect();
+
+    let env_summary = if env_values {
+        Value::Object(
+            env.iter()
+                .map(|(name, value)| {
+                    let value = value.as_str().unwrap_or_default();
+                    (name.clone(), Value::String(truncate(value, max_value_length)))
+                })
+                .collect(),
+        )
+    } else {
+        Value::Array(env.keys().map(|name| Value::String(name.clone())).collect())
+    };
+
+    let overridden_phases: Vec<&String> = env
+        .iter()
+        .filter(|(name, value)| {
+            name.ends_with("Phase") && value.as_str().is_some_and(|v| !v.is_empty())
+        })
+        .map(|(name, _)| name)
+        .collect();
+
+    json!({
+        "drv_path": drv_path,
+        "name": str_field("name").or_else(|| env.get("name").and_then(Value::as_str)),
+        "system": str_field("system"),
+        "builder": str_field("builder"),
+        "args": args,
+        "outputs": outputs,
+        "input_derivations": inputs,
+        "input_sources": input_sources,
+        "env": env_summary,
+        "phases": stdenv_phases(&env),
+        "overridden_phases": overridden_phases,
+    })
+}
+
+/// Returns the phases stdenv's `genericBuild` would run for `env`, or `None`
+/// if the derivation does not use stdenv.
+fn stdenv_phases(env: &Map<String, Value>) -> Option<Vec<String>> {
+    env.get("stdenv")?;
+    let var = |name: &str| env.get(name).and_then(Value::as_str).unwrap_or("");
+    let enabled = |name: &str| !var(name).is_empty();
+
+    if enabled("phases") {
+        return Some(var("phases").split_whitespace().map(str::to_string).collect());
+    }
+
+    let mut phases = Vec::new();
+    for (phase, extra) in STDENV_PHASES {
+        if !extra.is_empty() {
+            phases.extend(var(extra).split_whitespace().map(str::to_string));
+        }
+        let skipped = match phase {
+            "unpackPhase" => enabled("dontUnpack"),
+            "configurePhase" => enabled("dontConfigure"),
+            "buildPhase" => enabled("dontBuild"),
+            "checkPhase" => !enabled("doCheck"),
+            "installPhase" => enabled("dontInstall"),
+            "fixupPhase" => enabled("dontFixup"),
+            "installCheckPhase" => !enabled("doInstallCheck"),
+            "distPhase" => !enabled("doDist"),
+            _ => false,
+        };
+        if !skipped {
+            phases.push(phase.to_string());
+        }
+    }
+    phases.extend(var("postPhases").split_whitespace().map(str::to_string));
+    Some(phases)
+}
+
+/// Shortens `value` to at most `max` characters, noting how much was cut.
+pub(crate) fn truncate(value: &str, max: usize) -> String {
+    let len = value.chars().count();
+    if len <= max {
+        return value.to_string();
+    }
+    let kept: String = value.chars().take(max).collect();
+    format!("{}… ({} more characters)", kept, len - max)
+}
+
+/// Compares the derivations of `left` and `right` recursively and returns a
+/// tree of the differences that explain why they differ. Each node lists what
+/// changed in that derivation itself (builder, arguments, sources, environment
+/// and added or removed inputs) and recurses into inputs that changed. Store
+/// hashes are ignored when comparing values, so a node only reports what
+/// differs besides the inputs it depends on.
+pub(crate) fn diff(left: &str, right: &str, max_value_length: usize) -> Result<Value, Error> {
+    let left_drvs = show_derivations(left, true)?;
+    let right_drvs = show_derivations(right, true)?;
+    let (Some(left_root), Some(right_root)) = (root(&left_drvs), root(&right_drvs)) else {
+        return Err(Error::other("nix derivation show printed no derivation"));
+    };
+
+    let mut differ = Differ {
+        left: &left_drvs,
+        right: &right_drvs,
+        max_value_length,
+        seen: HashSet::new(),
+    };
+    Ok(differ
+        .diff(left_root, right_root)
+        .unwrap_or_else(|| json!({ "identical": true, "drv_path": left_root })))
+}
+
+/// Returns the derivation of a recursive `nix derivation show`, which is the
+/// one no other derivation depends on.
+pub(crate) fn root(drvs: &Map<String, Value>) -> Option<&str> {
+    let inputs: HashSet<String> = drvs
+        .values()
+        .flat_map(|drv| input_derivations(drv).into_keys())
+        .collect();
+    drvs.keys()
+        .find(|path| !inputs.contains(*path))
+        .map(String::as_str)
+}
+
+struct Differ<'a> {
+    left: &'a Map<String, Value>,
+    right: &'a Map<String, Value>,
+    max_value_length: usize,
+    /// Pairs already compared, which are shown only once.
+    seen: HashSet<(String, String)>,
+}
+
+impl Differ<'_> {
+    fn diff(&mut self, left_path: &str, right_path: &str) -> Option<Value> {
+        if left_path == right_path {
+            return None;
+        }
+        let mut node = Map::new();
+        node.insert("name".into(), json!(path_name(left_path)));
+        node.insert("left".into(), json!(left_path));
+        node.insert("right".into(), json!(right_path));
+        if !self.seen.insert((left_path.to_string(), right_path.to_string())) {
+            node.insert("already_shown".into(), json!(true));
+            return Some(Value::Object(node));
+        }
+        let (Some(left), Some(right)) = (self.left.get(left_path), self.right.get(right_path))
+        else {
+            return Some(Value::Object(node));
+        };
+
+        for field in ["system", "builder"] {
+            let (l, r) = (string(left, field), string(right, field));
+            if normalize(l) != normalize(r) {
+                node.insert(field.into(), json!({ "left": l, "right": r }));
+            }
+        }
+
+        let (left_args, right_args) = (strings(left, "args"), strings(right, "args"));
+        let normalized = |args: &[String]| args.iter().map(|a| normalize(a)).collect::<Vec<_>>();
+        if normalized(&left_args) != normalized(&right_args) {
+            let truncated = |args: Vec<String>| {
+                args.iter()
+                    .map(|a| truncate(a, self.max_value_length))
+                    .collect::<Vec<_>>()
+            };
+            node.insert(
+                "args".into(),
+                json!({ "left": truncated(left_args), "right": truncated(right_args) }),
+            );
+        }
+
+        let outputs = |drv: &Value| -> Vec<String> {
+            drv.get("outputs")
+                .and_then(Value::as_object)
+                .map(|outputs| outputs.keys().cloned().collect())
+                .unwrap_or_default()
+        };
+        if outputs(left) != outputs(right) {
+            node.insert(
+                "outputs".into(),
+                json!({ "left": outputs(left), "right": outputs(right) }),
+            );
+        }
+
+        let sources = self.diff_sources(&strings(left, "inputSrcs"), &strings(right, "inputSrcs"));
+        if !sources.is_empty() {
+            node.insert("sources".into(), Value::Object(sources));
+        }
+
+        let env = self.diff_env(&env(left), &env(right));
+        if !env.is_empty() {
+            node.insert("env".into(), Value::Object(env));
+        }
+
+        let (changed, added, removed) =
+            pair_inputs(input_derivations(left), input_derivations(right));
+        if !added.is_empty() {
+            node.insert("added_inputs".into(), json!(added));
+        }
+        if !removed.is_empty() {
+            node.insert("removed_inputs".into(), json!(removed));
+        }
+        let inputs: Vec<Value> = changed
+            .iter()
+            .filter_map(|(l, r)| self.diff(l, r))
+            .collect();
+        if !inputs.is_empty() {
+            node.insert("inputs".into(), Value::Array(inputs));
+        }
+
+        Some(Value::Object(node))
+    }
+
+    /// Compares input sources by name: sources only on one side are added or
+    /// removed, and sources whose hash differs changed content.
+    fn diff_sources(&self, left: &[String], right: &[String]) -> Map<String, Value> {
+        let by_name = |paths: &[String]| -> BTreeMap<String, String> {
+            paths.iter().map(|p| (path_name(p), p.clone())).collect()
+        };
+        let (left, right) = (by_name(left), by_name(right));
+        let mut diff = Map::new();
+        let added: Vec<&String> = right
+            .iter()
+            .filter(|(name, _)| !left.contains_key(*name))
+            .map(|(_, path)| path)
+            .collect();
+        let removed: Vec<&String> = left
+            .iter()
+            .filter(|(name, _)| !right.contains_key(*name))
+            .map(|(_, path)| path)
+            .collect();
+        let changed: Vec<Value> = left
+            .iter()
+            .filter_map(|(name, l)| {
+                let r = right.get(name)?;
+                (l != r).then(|| json!({ "left": l, "right": r }))
+            })
+            .collect();
+        for (key, values) in [
+            ("added", json!(added)),
+            ("removed", json!(removed)),
+            ("changed", json!(changed)),
+        ] {
+            if values.as_array().is_some_and(|v| !v.is_empty()) {
+                diff.insert(key.into(), values);
+            }
+        }
+        diff
+    }
+
+    /// Compares environment variables, ignoring differences in store hashes.
+    /// Changed multi-line values are shown from their first differing line.
+    fn diff_env(&self, left: &Map<String, Value>, right: &Map<String, Value>) -> Map<String, Value> {
+        let value = |v: &Value| v.as_str().unwrap_or_default().to_string();
+        let max = self.max_value_length;
+        let mut added = Map::new();
+        let mut removed = Map::new();
+        let mut changed = Map::new();
+        for (name, l) in left {
+            let l = value(l);
+            match right.get(name).map(value) {
+                None => {
+                    removed.insert(name.clone(), json!(truncate(&l, max)));
+                }
+                Some(r) if normalize(&l) != normalize(&r) => {
+                    let (line, l, r) = first_difference(&l, &r);
+                    let mut change = json!({ "left": truncate(l, max), "right": truncate(r, max) });
+                    if line > 1 {
+                        change["line"] = json!(line);
+                    }
+                    changed.insert(name.clone(), change);
+                }
+                Some(_) => {}
+            }
+        }
+        for (name, r) in right {
+            if !left.contains_key(name) {
+                added.insert(name.clone(), json!(truncate(&value(r), max)));
+            }
+        }
+
+        let mut diff = Map::new();
+        for (key, values) in [("added", added), ("removed", removed), ("changed", changed)] {
+            if !values.is_empty() {
+                diff.insert(key.into(), Value::Object(values));
+            }
+        }
+        diff
+    }
+}
+
+/// Pairs the input derivations of two derivations, first by name and then by
+/// package name, so that version bumps are compared too. Returns the changed
+/// pairs and the unpaired added and removed inputs.
+fn pair_inputs(
+    left: BTreeMap<String, Vec<String>>,
+    right: BTreeMap<String, Vec<String>>,
+) -> (Vec<(String, String)>, Vec<String>, Vec<String>) {
+    let mut removed: Vec<String> = left
+        .keys()
+        .filter(|path| !right.contains_key(*path))
+        .cloned()
+        .collect();
+    let mut added: Vec<String> = right
+        .into_keys()
+        .filter(|path| !left.contains_key(path))
+        .collect();
+
+    let mut pairs: Vec<(String, String)> = Vec::new();
+    let keys: [fn(&str) -> String; 2] = [path_name, package_name];
+    for key in keys {
+        let group = |paths: &[String]| {
+            let mut groups: BTreeMap<String, Vec<String>> = BTreeMap::new();
+            for path in paths {
+                groups.entry(key(path)).or_default().push(path.clone());
+            }
+            groups
+        };
+        let right_groups = group(&added);
+        // Only unambiguous matches are paired.
+        for (name, lefts) in group(&removed) {
+            if let ([l], Some([r])) = (&lefts[..], right_groups.get(&name).map(Vec::as_slice)) {
+                pairs.push((l.clone(), r.clone()));
+            }
+        }
+        removed.retain(|path| !pairs.iter().any(|(l, _)| l == path));
+        added.retain(|path| !pairs.iter().any(|(_, r)| r == path));
+    }
+    (pairs, added, removed)
+}
+
+/// Returns the name of a store path without its hash and `.drv` suffix.
+fn path_name(path: &str) -> String {
+    match store_path::parse(path) {
+        Ok(parsed) => parsed.name.trim_end_matches(".drv").to_string(),
+        Err(_) => path.to_string(),
+    }
+}
+
+/// Returns the package name of a store path, without its version.
+fn package_name(path: &str) -> String {
+    match store_path::parse(path) {
+        Ok(parsed) => parsed.pname,
+        Err(_) => path.to_string(),
+    }
+}
+
+fn string<'a>(drv: &'a Value, field: &str) -> &'a str {
+    drv.get(field).and_then(Value::as_str).unwrap_or_default()
+}
+
+fn strings(drv: &Value, field: &str) -> Vec<String> {
+    drv.get(field)
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .map(str::to_string)
+        .collect()
+}
+
+/// Blanks out the hashes of store paths in `value`, so that values differing
+/// only in the store paths they refer to compare equal.
+fn normalize(value: &str) -> String {
+    let mut normalized = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(i) = rest.find('/') {
+        normalized.push_str(&rest[..=i]);
+        rest = &rest[i + 1..];
+        if let Some(hash) = rest.get(..32)
+            && rest[32..].starts_with('-')
+            && hash.bytes().all(|b| b.is_ascii_digit() || b.is_ascii_lowercase())
+        {
+            rest = &rest[32..];
+        }
+    }
+    normalized.push_str(rest);
+    normalized
+}
+
+/// Returns the first line on which `left` and `right` differ (counting from
+/// 1) and both values from that line on.
+fn first_difference<'a>(left: &'a str, right: &'a str) -> (usize, &'a str, &'a str) {
+    let mut offsets = (0, 0);
+    for (index, (l, r)) in left
+        .split_inclusive('\n')
+        .zip(right.split_inclusive('\n'))
+        .enumerate()
+    {
+        if normalize(l) != normalize(r) {
+            return (index + 1, &left[offsets.0..], &right[offsets.1..]);
+        }
+        offsets = (offsets.0 + l.len(), offsets.1 + r.len());
+    }
+    let lines = left[..offsets.0].lines().count();
+    (lines + 1, &left[offsets.0..], &right[offsets.1..])
+}
@@ -0,0 +1,225 @@
+use serde_json::Value;
+
+/// Attribute sets with more attributes than this are rendered with their
+/// attribute names only, so that e.g. `pkgs` does not force every package.
+const MAX_RENDERED_ATTRS: usize = 100;
+
+/// Returns the `--apply` function that turns a value into a JSON-serializable
+/// tree for [`render_nix`], descending at most `depth` levels into attribute
+/// sets and lists. Functions and derivations become tagged placeholders, as
+/// do values that `throw` or fail an `assert`. Other errors, such as `abort`,
+/// missing attributes or type errors, cannot be caught by `builtins.tryEval`
+/// and still fail the whole evaluation. If `apply` is given, it is applied to
+/// the value first.
+pub(crate) fn nix_syntax_apply(depth: u32, apply: Option<&str>) -> String {
+    let apply = apply.unwrap_or("value: value");
+    format!(
+        r#"
+value:
+let
+  maxDepth = {depth};
+  render = depth: value:
+    let
+      forced = builtins.tryEval value;
+      v = forced.value;
+      type = builtins.typeOf v;
+      isDerivation =
+        let t = builtins.tryEval ((v.type or null) == "derivation");
+        in t.success && t.value;
+    in
+    if !forced.success then {{ __rime = "error"; }}
+    else if type == "lambda" then {{ __rime = "lambda"; }}
+    else if type == "path" then {{ __rime = "path"; value = toString v; }}
+    else if type == "set" then
+      if isDerivation then {{
+        __rime = "derivation";
+        name = let t = builtins.tryEval (v.name or null); in if t.success then t.value else null;
+      }}
+      else if depth >= maxDepth then {{ __rime = "omitted-set"; count = builtins.length (builtins.attrNames v); }}
+      else if builtins.length (builtins.attrNames v) > {max_attrs} then {{ __rime = "names"; names = builtins.attrNames v; }}
+      else {{ __rime = "set"; attrs = builtins.mapAttrs (_: render (depth + 1)) v; }}
+    else if type == "list" then
+      if depth >= maxDepth then {{ __rime = "omitted-list"; count = builtins.length v; }}
+      else map (render (depth + 1)) v
+    else v;
+in
+render 0 (({apply}) value)
+"#,
+        depth = depth,
+        max_attrs = MAX_RENDERED_ATTRS,
+        apply = apply,
+    )
+}
+
+/// Renders the output of [`nix_syntax_apply`] in Nix syntax.
+pub(crate) fn render_nix(value: &Value) -> String {
+    let mut out = String::new();
+    render(value, 0, &mut out);
+    out
+}
+
+fn render(value: &Value, indent: usize, out: &mut String) {
+    match value {
+        Value::Null => out.push_str("null"),
+        Value::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        Value::Number(n) => out.push_str(&n.to_string()),
+        Value::String(s) => out.push_str(&quote_string(s)),
+        Value::Array(items) if items.is_empty() => out.push_str("[ ]"),
+        Value::Array(items) => {
+            out.push_str("[\n");
+            for item in items {
+                push_indent(indent + 1, out);
+                render(item, indent + 1, out);
+                out.push('\n');
+            }
+            push_indent(indent, out);
+            out.push(']');
+        }
+        Value::Object(object) => {
+            let field = |name: &str| object.get(name);
+            let count = field("count").and_then(Value::as_u64).unwrap_or(0);
+            match field("__rime").and_then(Value::as_str) {
+                Some("error") => out.push_str("«error»"),
+                Some("lambda") => out.push_str("«lambda»"),
+                Some("path") => {
+                    out.push_str(field("value").and_then(Value::as_str).unwrap_or(""))
+                }
+                Some("derivation") => match field("name").and_then(Value::as_str) {
+                    Some(name) => out.push_str(&format!("«derivation {}»", name)),
+                    None => out.push_str("«derivation»"),
+                },
+                Some("omitted-set") => out.push_str(&format!("{{ /* {} attributes */ }}", count)),
+                Some("omitted-list") => out.push_str(&format!("[ /* {} elements */ ]", count)),
+                Some("names") => {
+                    let names = field("names").and_then(Value::as_array);
+                    let names = names.into_iter().flatten().filter_map(Value::as_str);
+                    out.push_str("{\n");
+                    for name in names {
+                        push_indent(indent + 1, out);
+                        out.push_str(&format!("{} = «…»;\n", quote_attr(name)));
+                    }
+                    push_indent(indent, out);
+                    out.push('}');
+                }
+                Some("set") => {
+                    let attrs = field("attrs").and_then(Value::as_object);
+                    match attrs {
+                        Some(attrs) if !attrs.is_empty() => {
+                            out.push_str("{\n");
+                            for (name, value) in attrs {
+                                push_indent(indent + 1, out);
+                                out.push_str(&quote_attr(name));
+                                out.push_str(" = ");
+                                render(value, indent + 1, out);
+                                out.push_str(";\n");
+                            }
+                            push_indent(indent, out);
+                            out.push('}');
+                        }
+                        _ => out.push_str("{ }"),
+                    }
+                }
+                // Not produced by `nix_syntax_apply`, which tags every set.
+                _ => out.push_str(&serde_json::to_string(value).unwrap_or_default()),
+            }
+        }
+    }
+}
+
+fn push_indent(indent: usize, out: &mut String) {
+    for _ in 0..indent {
+        out.push_str("  ");
+    }
+}
+
+fn quote_string(s: &str) -> String {
+    let escaped = s
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace("${", "\\${")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+        .replace('\t', "\\t");
+    format!("\"{}\"", escaped)
+}
+
+/// Quotes `name` unless it is a valid bare attribute name.
+fn quote_attr(name: &str) -> String {
+    let mut chars = name.chars();
+    let bare = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || "_'-".contains(c))
+        && !matches!(
+            name,
+            "if" | "then" | "else" | "assert" | "with" | "let" | "in" | "rec" | "inherit" | "or"
+        );
+    if bare { name.to_string() } else { quote_string(name) }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn renders_tagged_values() {
+        let value = json!({
+            "__rime": "set",
+            "attrs": {
+                "broken": { "__rime": "error" },
+                "drv": { "__rime": "derivation", "name": "hello-2.12.1" },
+                "f": { "__rime": "lambda" },
+                "list": [1, true, null],
+                "many": { "__rime": "omitted-set", "count": 3 },
+                "src": { "__rime": "path", "value": "/src/project" },
+                "text": "say \"${hi}\"\n",
+            },
+        });
+        assert_eq!(
+            render_nix(&value),
+            r#"{
+  broken = «error»;
+  drv = «derivation hello-2.12.1»;
+  f = «lambda»;
+  list = [
+    1
+    true
+    null
+  ];
+  many = { /* 3 attributes */ };
+  src = /src/project;
+  text = "say \"\${hi}\"\n";
+}"#
+        );
+    }
+
+    #[test]
+    fn renders_empty_and_summarized_values() {
+        assert_eq!(render_nix(&json!([])), "[ ]");
+        assert_eq!(render_nix(&json!({ "__rime": "set", "attrs": {} })), "{ }");
+        assert_eq!(
+            render_nix(&json!({ "__rime": "omitted-list", "count": 2 })),
+            "[ /* 2 elements */ ]"
+        );
+        assert_eq!(
+            render_nix(&json!({ "__rime": "names", "names": ["a", "b-c"] })),
+            "{\n  a = «…»;\n  b-c = «…»;\n}"
+        );
+    }
+
+    #[test]
+    fn quotes_attribute_names_only_when_needed() {
+        assert_eq!(quote_attr("foo"), "foo");
+        assert_eq!(quote_attr("_private"), "_private");
+        assert_eq!(quote_attr("foo-bar'"), "foo-bar'");
+        assert_eq!(quote_attr("in"), "\"in\"");
+        assert_eq!(quote_attr("inherit"), "\"inherit\"");
+        assert_eq!(quote_attr("x86_64-linux"), "x86_64-linux");
+        assert_eq!(quote_attr("1password"), "\"1password\"");
+        assert_eq!(quote_attr("-foo"), "\"-foo\"");
+        assert_eq!(quote_attr("a.b"), "\"a.b\"");
+        assert_eq!(quote_attr(""), "\"\"");
+    }
+}
@@ -0,0 +1,429 @@
+use std::io::Error;
+
+use serde_json::{Map, Value, json};
+
+use crate::build::failed_derivation;
+use crate::log::{filter_log, read_log};
+use crate::nix;
+use crate::nix_error;
+use crate::store_path;
+
+/// Errors that only summarize earlier ones.
+const SUMMARY_ERRORS: [&str; 3] = [
+    "some errors were encountered",
+    "dependencies of derivation",
+    "failed to build",
+];
+
+/// The per-system outputs `nix flake check` checks, with the kind of check it
+/// reports for their attributes.
+const PER_SYSTEM_OUTPUTS: [(&str, &str); 5] = [
+    ("checks", "derivation"),
+    ("packages", "derivation"),
+    ("apps", "app"),
+    ("devShells", "derivation"),
+    ("formatter", "derivation"),
+];
+
+/// How to run `nix flake check`.
+pub(crate) struct CheckOptions {
+    /// Build the checks instead of only evaluating them.
+    pub(crate) build: bool,
+    pub(crate) system: Option<String>,
+    pub(crate) all_systems: bool,
+    pub(crate) keep_going: bool,
+    /// How many lines of a failed build's log to return.
+    pub(crate) log_lines: usize,
+}
+
+/// A flake output attribute nix reported checking, e.g. `derivation
+/// 'checks.x86_64-linux.fmt'`.
+struct Check {
+    kind: String,
+    attr: String,
+    error: Option<Value>,
+    /// Whether nix skipped the check because it is for another system.
+    skipped: bool,
+}
+
+/// Runs `nix flake check` on `flake` and returns the result of every check
+/// nix reported, grouped by flake output. Checks are `passed`, `failed`,
+/// `skipped` when they are for a system nix did not check or, when a build
+/// failed that cannot be attributed to a check, `unknown`. Returns `success`
+/// false if any check failed.
+pub(crate) fn check(flake: &str, options: &CheckOptions) -> Result<Value, Error> {
+    // Run: nix flake check [--no-build] [--system <system>] [--all-systems] [--keep-going] <flake>
+    let mut command = nix::command();
+    command.args(["flake", "check"]);
+    if !options.build {
+        command.args(["--no-build"]);
+    }
+    if let Some(system) = &options.system {
+        command.args(["--system", system]);
+    }
+    if options.all_systems {
+        command.args(["--all-systems"]);
+    }
+    if options.keep_going {
+        command.args(["--keep-going"]);
+    }
+    let output = command.args([flake]).output()?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let success = output.status.success();
+
+    let mut checks: Vec<Check> = Vec::new();
+    let mut skipped_systems = Vec::new();
+    let mut warnings = Vec::new();
+    let mut errors = Vec::new();
+    let mut build_failures = Vec::new();
+
+    for line in stderr.lines() {
+        if let Some((kind, attr)) = parse_checking(line) {
+            checks.push(Check {
+                kind,
+                attr,
+                error: None,
+                skipped: false,
+            });
+        } else if let Some(warning) = line.strip_prefix("warning:") {
+            let warning = warning.trim();
+            if let Some((_, systems)) = warning.split_once("incompatible systems:") {
+                skipped_systems.extend(
+                    systems
+                        .split(',')
+                        .map(|system| system.trim().to_string())
+                        .filter(|system| !system.is_empty()),
+                );
+            } else {
+                warnings.push(warning.to_string());
+            }
+        }
+    }
+
+    for block in error_blocks(&stderr) {
+        let first_line = block.lines().next().unwrap_or_default();
+        if SUMMARY_ERRORS.iter().any(|summary| first_line.contains(summary)) {
+            continue;
+        }
+        if let Some(drv) = failed_derivation(&block) {
+            // Nix includes the last lines of the log in its error message if
+            // the full log is not available locally.
+            let log = read_log(&drv).unwrap_or_else(|_| {
+                block
+                    .lines()
+                    .filter_map(|line| line.trim_start().strip_prefix("> "))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            });
+            let failure = json!({
+                "derivation": drv,
+                "log_tail": filter_log(&log, None, 0, Some(options.log_lines)),
+            });
+            match checks.iter_mut().find(|check| builds(check, &drv)) {
+                Some(check) => check.error = Some(failure),
+                None => build_failures.push(failure),
+            }
+            continue;
+        }
+
+        let error = nix_error::parse("nix flake check", output.status.code(), &block);
+        let summary = json!({
+            "kind": error.kind,
+            "message": error.message,
+            "position": error.position,
+            "snippet": error.snippet,
+        });
+        // The innermost "while checking ..." frame names the failing check.
+        let attr = error
+            .trace
+            .iter()
+            .rev()
+            .filter(|frame| frame.description.starts_with("while checking"))
+            .find_map(|frame| quoted(&frame.description));
+        match attr {
+            Some(attr) => match checks.iter_mut().find(|check| check.attr == attr) {
+                Some(check) => check.error = Some(summary),
+                None => checks.push(Check {
+                    kind: "flake output".to_string(),
+                    attr,
+                    error: Some(summary),
+                    skipped: false,
+                }),
+            },
+            None => errors.push(summary),
+        }
+    }
+
+    if !skipped_systems.is_empty() {
+        checks.extend(skipped_checks(flake, &skipped_systems));
+    }
+
+    let mut outputs: Map<String, Value> = Map::new();
+    let (mut passed, mut failed, mut skipped, mut unknown) = (0, 0, 0, 0);
+    for check in checks {
+        let status = if check.skipped {
+            skipped += 1;
+            "skipped"
+        } else if check.error.is_some() {
+            failed += 1;
+            "failed"
+        } else if !build_failures.is_empty() && check.kind == "derivation" {
+            unknown += 1;
+            "unknown"
+        } else {
+            passed += 1;
+            "passed"
+        };
+        let output = check.attr.split('.').next().unwrap_or_default().to_string();
+        let mut result = json!({ "attr": check.attr, "kind": check.kind, "status": status });
+        if let Some(error) = check.error {
+            result["error"] = error;
+        }
+        if let Value::Array(results) = outputs.entry(output).or_insert_with(|| json!([])) {
+            results.push(result);
+        }
+    }
+
+    Ok(json!({
+        "success": success,
+        "flake": flake,
+        "mode": if options.build { "build" } else { "no_build" },
+        // Without --keep-going nix stops at the first failure.
+        "complete": success || options.keep_going,
+        "passed": passed,
+        "failed": failed,
+        "skipped": skipped,
+        "unknown": unknown,
+        "outputs": outputs,
+        "errors": errors,
+        "build_failures": build_failures,
+        "skipped_systems": skipped_systems,
+        "warnings": warnings,
+    }))
+}
+
+/// Lists the checks nix skipped because they are for one of `systems`, from
+/// the attribute names of the per-system outputs of `flake`. The checks
+/// themselves are not evaluated; outputs that fail to list are left out.
+fn skipped_checks(flake: &str, systems: &[String]) -> Vec<Check> {
+    let mut checks = Vec::new();
+    for (output, kind) in PER_SYSTEM_OUTPUTS {
+        // `formatter` holds one derivation per system, the others sets of them.
+        let apply = match output {
+            "formatter" => "builtins.attrNames",
+            _ => "builtins.mapAttrs (_: builtins.attrNames)",
+        };
+        // Run: nix eval --json <flake>#<output> --apply <attribute names>
+        let result = nix::command()
+            .args(["eval", "--json", &format!("{}#{}", flake, output), "--apply", apply])
+            .output();
+        let Ok(result) = result else {
+            continue;
+        };
+        if !result.status.success() {
+            continue;
+        }
+        let Ok(names) = serde_json::from_slice::<Value>(&result.stdout) else {
+            continue;
+        };
+        checks.extend(per_system_attrs(output, &names, systems).into_iter().map(|attr| Check {
+            kind: kind.to_string(),
+            attr,
+            error: None,
+            skipped: true,
+        }));
+    }
+    checks
+}
+
+/// Returns the attributes of `output` for `systems`, given the attribute
+/// names of the output: a list of systems, or the names below each system.
+fn per_system_attrs(output: &str, names: &Value, systems: &[String]) -> Vec<String> {
+    let for_system = |system: &str| systems.iter().any(|skipped| skipped == system);
+    match names {
+        Value::Array(names) => names
+            .iter()
+            .filter_map(Value::as_str)
+            .filter(|system| for_system(system))
+            .map(|system| format!("{}.{}", output, system))
+            .collect(),
+        Value::Object(by_system) => by_system
+            .iter()
+            .filter(|(system, _)| for_system(system))
+            .flat_map(|(system, names)| {
+                names
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(Value::as_str)
+                    .map(move |name| format!("{}.{}.{}", output, system, name))
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Parses `checking derivation 'checks.x86_64-linux.fmt'...` into the kind
+/// and attribute of the check. Older nix versions do not quote the attribute.
+fn parse_checking(line: &str) -> Option<(String, String)> {
+    let what = line.trim().strip_prefix("checking ")?.strip_suffix("...")?;
+    if let Some(attr) = quoted(what) {
+        let kind = what.split_once('\'').map_or("", |(kind, _)| kind.trim());
+        return Some((kind.to_string(), attr));
+    }
+    let (kind, attr) = what.rsplit_once(' ')?;
+    Some((kind.to_string(), attr.to_string()))
+}
+
+/// Returns the first single-quoted part of `text`.
+fn quoted(text: &str) -> Option<String> {
+    let (_, rest) = text.split_once('\'')?;
+    let (quoted, _) = rest.split_once('\'')?;
+    Some(quoted.to_string())
+}
+
+/// Splits stderr into its top-level `error:` messages.
+fn error_blocks(stderr: &str) -> Vec<String> {
+    let mut blocks: Vec<String> = Vec::new();
+    let mut current: Option<String> = None;
+    for line in stderr.lines() {
+        if line.starts_with("error:") {
+            blocks.extend(current.take());
+            current = Some(format!("{}\n", line));
+        } else if let Some(block) = current.as_mut() {
+            if line.starts_with(' ') || line.is_empty() {
+                block.push_str(line);
+                block.push('\n');
+            } else {
+                blocks.extend(current.take());
+            }
+        }
+    }
+    blocks.extend(current);
+    blocks
+}
+
+/// Returns whether the derivation check `check` builds `drv`, judging by the
+/// derivation's name.
+fn builds(check: &Check, drv: &str) -> bool {
+    let Some(name) = check.attr.rsplit('.').next() else {
+        return false;
+    };
+    let Ok(parsed) = store_path::parse(drv) else {
+        return false;
+    };
+    check.kind == "derivation" && (parsed.pname == name || parsed.name.trim_end_matches(".drv") == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STDERR: &str = "\
+evaluating flake...
+checking flake output 'checks'...
+checking derivation 'checks.x86_64-linux.fmt'...
+checking derivation 'checks.x86_64-linux.tests'...
+checking flake output 'packages'...
+checking derivation 'packages.x86_64-linux.default'...
+warning: The check omitted these incompatible systems: aarch64-darwin, x86_64-darwin
+Use '--all-systems' to check all.
+error: builder for '/nix/store/4jv2wsxbzxmk5w6i7h0mw4jc3bmgvnf2-tests.drv' failed with exit code 1;
+       last 2 log lines:
+       > running tests
+       > FAIL: test_add
+       For full logs, run 'nix log /nix/store/4jv2wsxbzxmk5w6i7h0mw4jc3bmgvnf2-tests.drv'.
+error: 1 dependencies of derivation '/nix/store/a1gqnnz8w6sdrr9jx5l1wzj0wg3bi2qr-check.drv' failed to build
+";
+
+    fn check(kind: &str, attr: &str) -> Check {
+        Check {
+            kind: kind.to_string(),
+            attr: attr.to_string(),
+            error: None,
+            skipped: false,
+        }
+    }
+
+    #[test]
+    fn parses_checking_lines() {
+        let parse = |line| parse_checking(line).map(|(kind, attr)| format!("{}|{}", kind, attr));
+        assert_eq!(
+            parse("checking derivation 'checks.x86_64-linux.fmt'...").as_deref(),
+            Some("derivation|checks.x86_64-linux.fmt")
+        );
+        // Older nix versions do not quote the attribute.
+        assert_eq!(
+            parse("checking derivation checks.x86_64-linux.fmt...").as_deref(),
+            Some("derivation|checks.x86_64-linux.fmt")
+        );
+        assert_eq!(
+            parse("checking flake output 'checks'...").as_deref(),
+            Some("flake output|checks")
+        );
+        assert_eq!(
+            parse("checking NixOS module 'nixosModules.default'...").as_deref(),
+            Some("NixOS module|nixosModules.default")
+        );
+        assert_eq!(parse("evaluating flake..."), None);
+    }
+
+    #[test]
+    fn splits_top_level_errors() {
+        let blocks = error_blocks(STDERR);
+        assert_eq!(blocks.len(), 2);
+        assert!(blocks[0].starts_with(
+            "error: builder for '/nix/store/4jv2wsxbzxmk5w6i7h0mw4jc3bmgvnf2-tests.drv'"
+        ));
+        assert!(blocks[0].contains("       > FAIL: test_add\n"));
+        assert!(blocks[0].ends_with(
+            "For full logs, run 'nix log /nix/store/4jv2wsxbzxmk5w6i7h0mw4jc3bmgvnf2-tests.drv'.\n"
+        ));
+        assert!(blocks[1].contains("dependencies of derivation"));
+
+        // An unindented line ends an error.
+        let blocks = error_blocks("error: first\n  detail\nwarning: unrelated\nerror: second\n");
+        assert_eq!(blocks, ["error: first\n  detail\n", "error: second\n"]);
+    }
+
+    #[test]
+    fn attributes_builds_to_derivation_checks() {
+        let drv = "/nix/store/4jv2wsxbzxmk5w6i7h0mw4jc3bmgvnf2-tests.drv";
+        assert!(builds(
+            &check("derivation", "checks.x86_64-linux.tests"),
+            drv
+        ));
+        assert!(!builds(
+            &check("derivation", "checks.x86_64-linux.fmt"),
+            drv
+        ));
+        assert!(!builds(&check("app", "apps.x86_64-linux.tests"), drv));
+        // Package names match without their version.
+        assert!(builds(
+            &check("derivation", "packages.x86_64-linux.hello"),
+            "/nix/store/pd2c4lq5k2q6qvvhv0kd4d5dqg6xwy1a-hello-2.12.1.drv"
+        ));
+        assert!(!builds(
+            &check("derivation", "checks.x86_64-linux.tests"),
+            "not a store path"
+        ));
+    }
+
+    #[test]
+    fn lists_attributes_of_skipped_systems() {
+        let systems = ["aarch64-darwin".to_string()];
+        let by_system = json!({
+            "x86_64-linux": ["fmt", "tests"],
+            "aarch64-darwin": ["fmt", "tests"],
+        });
+        assert_eq!(
+            per_system_attrs("checks", &by_system, &systems),
+            ["checks.aarch64-darwin.fmt", "checks.aarch64-darwin.tests"]
+        );
+        let formatter = json!(["x86_64-linux", "aarch64-darwin"]);
+        assert_eq!(
+            per_system_attrs("formatter", &formatter, &systems),
+            ["formatter.aarch64-darwin"]
+        );
+    }
+}
@@ -0,0 +1,494 @@
+use std::collections::{BTreeMap, HashSet};
+use std::io::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{Map, Value, json};
+
+use crate::nix;
+use crate::nix_error::nix_failure;
+
+/// Inputs nested deeper than this are not listed, which also stops cycles.
+const MAX_INPUT_DEPTH: usize = 8;
+
+/// Returns the lock file of `flake`: read directly from a local flake's
+/// `flake.lock`, or from `nix flake metadata` for other flake references.
+pub(crate) fn read_lock(flake: &str) -> Result<Value, Error> {
+    let dir = flake.strip_prefix("path:").unwrap_or(flake);
+    // Relative flakes are relative to where nix runs, not to rime.
+    let lock_file = nix::resolve_path(dir).join("flake.lock");
+    if lock_file.is_file() {
+        let contents = std::fs::read_to_string(&lock_file)
+            .map_err(|e| Error::other(format!("failed to read {}: {}", lock_file.display(), e)))?;
+        return serde_json::from_str(&contents)
+            .map_err(|e| Error::other(format!("failed to parse {}: {}", lock_file.display(), e)));
+    }
+
+    // Run: nix flake metadata --json --no-write-lock-file <flake>
+    let output = nix::command()
+        .args(["flake", "metadata", "--json", "--no-write-lock-file", flake])
+        .output()?;
+    if !output.status.success() {
+        return Err(nix_failure("nix flake metadata", &output));
+    }
+    let metadata: Value = serde_json::from_slice(&output.stdout)
+        .map_err(|e| Error::other(format!("failed to parse nix output: {}", e)))?;
+    metadata
+        .get("locks")
+        .cloned()
+        .ok_or_else(|| Error::other("nix flake metadata printed no locks"))
+}
+
+/// An input of the flake or of one of its inputs, e.g. `home-manager/nixpkgs`.
+struct Input {
+    /// The input names from the root flake, joined by `/`.
+    path: String,
+    /// The lock file node the input resolves to.
+    node: String,
+    /// The input path it follows, if it was declared with `follows`.
+    follows: Option<String>,
+}
+
+/// Inspects a lock file: every input with its locked revision and age, the
+/// lock file nodes that are copies of the same flake, and the `follows`
+/// declarations that would remove the copies. Inputs last modified more than
+/// `stale_after_days` days ago are marked stale.
+pub(crate) fn inspect(lock: &Value, stale_after_days: u64) -> Result<Value, Error> {
+    let nodes = lock
+        .get("nodes")
+        .and_then(Value::as_object)
+        .ok_or_else(|| Error::other("lock file has no nodes"))?;
+    let root = lock.get("root").and_then(Value::as_str).unwrap_or("root");
+
+    let mut inputs = Vec::new();
+    collect_inputs(nodes, root, root, "", 0, &mut inputs);
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    let listed: Vec<Value> = inputs
+        .iter()
+        .map(|input| {
+            let node = nodes.get(&input.node);
+            let locked = node.and_then(|node| node.get("locked"));
+            let last_modified = locked
+                .and_then(|locked| locked.get("lastModified"))
+                .and_then(Value::as_u64);
+            let age_days = last_modified.map(|modified| now.saturating_sub(modified) / 86400);
+            json!({
+                "path": input.path,
+                "node": input.node,
+                "follows": input.follows,
+                "flake": node.and_then(|node| node.get("original")).map(flake_ref),
+                "rev": locked.and_then(|locked| locked.get("rev")),
+                "last_modified": last_modified.map(format_date),
+                "age_days": age_days,
+                "stale": age_days.map(|age| age > stale_after_days),
+            })
+        })
+        .collect();
+
+    let duplicates = duplicates(nodes, &inputs);
+    Ok(json!({
+        "inputs": listed,
+        "duplicates": duplicates,
+        "suggested_follows": duplicates
+            .iter()
+            .flat_map(|duplicate| duplicate["suggested_follows"].as_array().cloned().unwrap_or_default())
+            .collect::<Vec<_>>(),
+    }))
+}
+
+/// Returns the lock file node of every input that does not follow another
+/// one, keyed by input path, e.g. `home-manager/nixpkgs`.
+pub(crate) fn input_nodes(lock: &Value) -> Result<BTreeMap<String, Value>, Error> {
+    let nodes = lock
+        .get("nodes")
+        .and_then(Value::as_object)
+        .ok_or_else(|| Error::other("lock file has no nodes"))?;
+    let root = lock.get("root").and_then(Value::as_str).unwrap_or("root");
+
+    let mut inputs = Vec::new();
+    collect_inputs(nodes, root, root, "", 0, &mut inputs);
+    Ok(inputs
+        .into_iter()
+        .filter(|input| input.follows.is_none())
+        .filter_map(|input| Some((input.path, nodes.get(&input.node)?.clone())))
+        .collect())
+}
+
+/// Lists the inputs of `node` and, recursively, their inputs. Inputs that
+/// follow another input are listed but not descended into.
+fn collect_inputs(
+    nodes: &Map<String, Value>,
+    root: &str,
+    node: &str,
+    prefix: &str,
+    depth: usize,
+    inputs: &mut Vec<Input>,
+) {
+    if depth >= MAX_INPUT_DEPTH {
+        return;
+    }
+    let Some(node_inputs) = nodes
+        .get(node)
+        .and_then(|node| node.get("inputs"))
+        .and_then(Value::as_object)
+    else {
+        return;
+    };
+    for (name, reference) in node_inputs {
+        let path = format!("{}{}", prefix, name);
+        match reference {
+            Value::String(target) => {
+                inputs.push(Input {
+                    path: path.clone(),
+                    node: target.clone(),
+                    follows: None,
+                });
+                collect_inputs(nodes, root, target, &format!("{}/", path), depth + 1, inputs);
+            }
+            Value::Array(follows) => {
+                let follows: Vec<&str> = follows.iter().filter_map(Value::as_str).collect();
+                if let Some(target) = resolve_follows(nodes, root, &follows, 0) {
+                    inputs.push(Input {
+                        path,
+                        node: target,
+                        follows: Some(follows.join("/")),
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Resolves a `follows` path, which starts at the root flake and may pass
+/// through other `follows` declarations, to a lock file node.
+fn resolve_follows(
+    nodes: &Map<String, Value>,
+    root: &str,
+    path: &[&str],
+    depth: usize,
+) -> Option<String> {
+    if depth >= MAX_INPUT_DEPTH {
+        return None;
+    }
+    let mut node = root.to_string();
+    for name in path {
+        let reference = nodes.get(&node)?.get("inputs")?.get(*name)?;
+        node = match reference {
+            Value::String(target) => target.clone(),
+            Value::Array(follows) => {
+                let follows: Vec<&str> = follows.iter().filter_map(Value::as_str).collect();
+                resolve_follows(nodes, root, &follows, depth + 1)?
+            }
+            _ => return None,
+        };
+    }
+    Some(node)
+}
+
+/// Groups lock file nodes that are copies of the same flake and suggests a
+/// `follows` declaration for every input that pulls in an extra copy.
+fn duplicates(nodes: &Map<String, Value>, inputs: &[Input]) -> Vec<Value> {
+    let mut groups: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    let mut seen = HashSet::new();
+    for input in inputs {
+        if !seen.insert(input.node.as_str()) {
+            continue;
+        }
+        if let Some(identity) = nodes
+            .get(&input.node)
+            .and_then(|node| node.get("original"))
+            .map(identity)
+        {
+            groups.entry(identity).or_default().push(&input.node);
+        }
+    }
+
+    groups
+        .into_iter()
+        .filter(|(_, copies)| copies.len() > 1)
+        .map(|(flake, copies)| {
+            // Prefer the copy the root flake uses directly, or else the one
+            // reached by the shortest path.
+            let paths_to = |node: &str| -> Vec<&Input> {
+                inputs.iter().filter(|input| input.node == node).collect()
+            };
+            let canonical = inputs
+                .iter()
+                .filter(|input| copies.contains(&input.node.as_str()))
+                .min_by_key(|input| (input.path.matches('/').count(), input.follows.is_some()))
+                .map(|input| input.path.clone())
+                .unwrap_or_default();
+            let canonical_node = inputs
+                .iter()
+                .find(|input| input.path == canonical)
+                .map(|input| input.node.as_str())
+                .unwrap_or_default();
+
+            let suggested: Vec<String> = copies
+                .iter()
+                .filter(|node| **node != canonical_node)
+                .flat_map(|node| paths_to(node))
+                .filter(|input| input.follows.is_none())
+                .map(|input| {
+                    let attr: Vec<String> = input
+                        .path
+                        .split('/')
+                        .map(|name| format!("inputs.{}", name))
+                        .collect();
+                    format!("{}.follows = \"{}\";", attr.join("."), canonical)
+                })
+                .collect();
+
+            let copies: Vec<Value> = copies
+                .iter()
+                .map(|node| {
+                    let info = nodes.get(*node);
+                    json!({
+                        "node": node,
+                        "paths": paths_to(node).iter().map(|input| &input.path).collect::<Vec<_>>(),
+                        "original": info.and_then(|info| info.get("original")).map(flake_ref),
+                        "rev": info.and_then(|info| info.get("locked")).and_then(|locked| locked.get("rev")),
+                    })
+                })
+                .collect();
+            json!({
+                "flake": flake,
+                "copies": copies,
+                "keep": canonical,
+                "suggested_follows": suggested,
+            })
+        })
+        .collect()
+}
+
+/// Returns what identifies the flake a lock file reference points to,
+/// ignoring its branch, tag or revision.
+fn identity(reference: &Value) -> String {
+    let field = |name: &str| reference.get(name).and_then(Value::as_str).unwrap_or("");
+    match field("type") {
+        kind @ ("github" | "gitlab" | "sourcehut") => format!(
+            "{}:{}/{}",
+            kind,
+            field("owner").to_lowercase(),
+            field("repo").to_lowercase()
+        ),
+        "indirect" => format!("flake:{}", field("id")),
+        "path" => format!("path:{}", field("path")),
+        kind => {
+            let url = field("url");
+            format!("{}:{}", kind, url.split_once('?').map_or(url, |(url, _)| url))
+        }
+    }
+}
+
+/// Formats a lock file reference as a flake reference, e.g.
+/// `github:NixOS/nixpkgs/nixos-unstable`.
+pub(crate) fn flake_ref(reference: &Value) -> String {
+    let field = |name: &str| reference.get(name).and_then(Value::as_str);
+    let suffix = field("ref").or(field("rev"));
+    match field("type").unwrap_or_default() {
+        kind @ ("github" | "gitlab" | "sourcehut") => {
+            let mut flake_ref = format!(
+                "{}:{}/{}",
+                kind,
+                field("owner").unwrap_or_default(),
+                field("repo").unwrap_or_default()
+            );
+            if let Some(suffix) = suffix {
+                flake_ref.push('/');
+                flake_ref.push_str(suffix);
+            }
+            flake_ref
+        }
+        "indirect" => {
+            let mut flake_ref = field("id").unwrap_or_default().to_string();
+            if let Some(suffix) = suffix {
+                flake_ref.push('/');
+                flake_ref.push_str(suffix);
+            }
+            flake_ref
+        }
+        "path" => format!("path:{}", field("path").unwrap_or_default()),
+        kind => {
+            let url = field("url").unwrap_or_default();
+            let mut flake_ref = match kind {
+                "git" | "hg" | "tarball" | "file" if !url.starts_with(&format!("{}+", kind)) => {
+                    format!("{}+{}", kind, url)
+                }
+                _ => url.to_string(),
+            };
+            if let Some(reference) = field("ref") {
+                flake_ref.push_str(&format!("?ref={}", reference));
+            }
+            flake_ref
+        }
+    }
+}
+
+/// Formats seconds since the Unix epoch as a UTC date, e.g. `2024-05-31`.
+pub(crate) fn format_date(timestamp: u64) -> String {
+    // Howard Hinnant's days-to-civil algorithm.
+    let days = (timestamp / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A lock file whose root uses nixpkgs, home-manager (following the
+    /// root's nixpkgs) and nixvim, which brings a second copy of nixpkgs. The
+    /// `nixpkgs-lib` input of nixvim's flake-parts follows nixvim's nixpkgs,
+    /// and the root's `pkgs` follows `home-manager/nixpkgs`, itself a follows.
+    fn lock() -> Value {
+        let github = |rev: &str, last_modified: u64| {
+            json!({
+                "locked": {
+                    "type": "github",
+                    "owner": "NixOS",
+                    "repo": "nixpkgs",
+                    "rev": rev,
+                    "lastModified": last_modified,
+                },
+                "original": { "type": "github", "owner": "NixOS", "repo": "nixpkgs", "ref": "nixos-unstable" },
+            })
+        };
+        json!({
+            "version": 7,
+            "root": "root",
+            "nodes": {
+                "root": {
+                    "inputs": {
+                        "nixpkgs": "nixpkgs",
+                        "home-manager": "home-manager",
+                        "nixvim": "nixvim",
+                        "pkgs": ["home-manager", "nixpkgs"],
+                    },
+                },
+                "nixpkgs": github("aaaa", 1709164800),
+                "nixpkgs_2": github("bbbb", 1703980800),
+                "home-manager": {
+                    "inputs": { "nixpkgs": ["nixpkgs"] },
+                    "original": { "type": "github", "owner": "nix-community", "repo": "home-manager" },
+                },
+                "nixvim": {
+                    "inputs": { "nixpkgs": "nixpkgs_2", "flake-parts": "flake-parts" },
+                    "original": { "type": "github", "owner": "nix-community", "repo": "nixvim" },
+                },
+                "flake-parts": {
+                    "inputs": { "nixpkgs-lib": ["nixvim", "nixpkgs"] },
+                    "original": { "type": "github", "owner": "hercules-ci", "repo": "flake-parts" },
+                },
+                "cycle-a": { "inputs": { "next": ["cycle-b"] } },
+                "cycle-b": { "inputs": { "next": ["cycle-a"] } },
+            },
+        })
+    }
+
+    fn resolve(lock: &Value, root: &str, path: &[&str]) -> Option<String> {
+        let nodes = lock["nodes"].as_object().unwrap();
+        resolve_follows(nodes, root, path, 0)
+    }
+
+    #[test]
+    fn resolves_nested_follows() {
+        let lock = lock();
+        assert_eq!(
+            resolve(&lock, "root", &["nixvim", "nixpkgs"]).as_deref(),
+            Some("nixpkgs_2")
+        );
+        // Through home-manager's own follows.
+        assert_eq!(
+            resolve(&lock, "root", &["home-manager", "nixpkgs"]).as_deref(),
+            Some("nixpkgs")
+        );
+        assert_eq!(
+            resolve(&lock, "root", &["pkgs"]).as_deref(),
+            Some("nixpkgs")
+        );
+        // Through flake-parts' follows of nixvim's nixpkgs.
+        assert_eq!(
+            resolve(&lock, "root", &["nixvim", "flake-parts", "nixpkgs-lib"]).as_deref(),
+            Some("nixpkgs_2")
+        );
+        assert_eq!(resolve(&lock, "root", &["missing"]), None);
+        // Cyclic follows give up at the depth limit.
+        assert_eq!(resolve(&lock, "cycle-a", &["next"]), None);
+    }
+
+    #[test]
+    fn lists_inputs_that_do_not_follow() {
+        let nodes = input_nodes(&lock()).unwrap();
+        let paths: Vec<&str> = nodes.keys().map(String::as_str).collect();
+        assert_eq!(
+            paths,
+            [
+                "home-manager",
+                "nixpkgs",
+                "nixvim",
+                "nixvim/flake-parts",
+                "nixvim/nixpkgs"
+            ]
+        );
+        assert_eq!(nodes["nixvim/nixpkgs"]["locked"]["rev"], "bbbb");
+    }
+
+    #[test]
+    fn suggests_follows_for_duplicate_nixpkgs() {
+        let report = inspect(&lock(), 30).unwrap();
+        let duplicates = report["duplicates"].as_array().unwrap();
+        assert_eq!(duplicates.len(), 1);
+
+        let duplicate = &duplicates[0];
+        assert_eq!(duplicate["flake"], "github:nixos/nixpkgs");
+        assert_eq!(duplicate["keep"], "nixpkgs");
+        let mut copies: Vec<&str> = duplicate["copies"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|copy| copy["node"].as_str())
+            .collect();
+        copies.sort();
+        assert_eq!(copies, ["nixpkgs", "nixpkgs_2"]);
+        // flake-parts/nixpkgs-lib already follows nixvim/nixpkgs, so only
+        // nixvim's own input needs a follows.
+        assert_eq!(
+            report["suggested_follows"],
+            json!(["inputs.nixvim.inputs.nixpkgs.follows = \"nixpkgs\";"])
+        );
+
+        let nixpkgs = report["inputs"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|input| input["path"] == "nixvim/nixpkgs")
+            .unwrap();
+        assert_eq!(nixpkgs["last_modified"], "2023-12-31");
+        assert_eq!(nixpkgs["stale"], true);
+    }
+
+    #[test]
+    fn formats_dates() {
+        assert_eq!(format_date(0), "1970-01-01");
+        assert_eq!(format_date(1703980800), "2023-12-31");
+        // Leap days, including the century leap year 2000.
+        assert_eq!(format_date(951782400), "2000-02-29");
+        assert_eq!(format_date(1709164800), "2024-02-29");
+        assert_eq!(format_date(1709164800 + 86399), "2024-02-29");
+        assert_eq!(format_date(1709251200), "2024-03-01");
+        // 2100 is not a leap year.
+        assert_eq!(format_date(4107456000), "2100-02-28");
+        assert_eq!(format_date(4107542400), "2100-03-01");
+    }
+}
@@ -0,0 +1,310 @@
+use std::collections::BTreeSet;
+use std::io::Error;
+
+use serde_json::{Map, Value, json};
+
+use crate::nix;
+use crate::nix_error::nix_failure;
+
+/// The operating systems of the systems flakes are commonly built for.
+const SYSTEM_KERNELS: [&str; 7] = ["linux", "darwin", "freebsd", "netbsd", "openbsd", "windows", "none"];
+
+/// What part of a flake's outputs to show.
+pub(crate) struct ShowOptions {
+    /// Only show outputs below this attribute path, e.g.
+    /// `legacyPackages.x86_64-linux.python3Packages`.
+    pub(crate) attr_path: Option<String>,
+    /// Only show outputs for this system.
+    pub(crate) system: Option<String>,
+    pub(crate) all_systems: bool,
+    /// Attribute sets nested deeper than this are summarized.
+    pub(crate) max_depth: Option<usize>,
+    /// Also show `legacyPackages`, which nix skips by default.
+    pub(crate) legacy: bool,
+}
+
+/// Lists the outputs of `flake` with their type and, for derivations, name
+/// and description. Without an attribute path the outputs come from `nix
+/// flake show`; with one only that attribute is evaluated, one level at a
+/// time up to `max_depth`, which also works below `legacyPackages`.
+pub(crate) fn show(flake: &str, options: &ShowOptions) -> Result<Value, Error> {
+    let (tree, prefix) = match &options.attr_path {
+        Some(attr_path) => (evaluate(flake, attr_path, options.max_depth.unwrap_or(1))?, attr_path.clone()),
+        None => (flake_show(flake, options)?, String::new()),
+    };
+
+    let mut outputs = Vec::new();
+    let mut omitted = BTreeSet::new();
+    flatten(&tree, &prefix, 0, options, &mut outputs, &mut omitted);
+    Ok(json!({
+        "flake": flake,
+        "attr_path": options.attr_path,
+        "outputs": outputs,
+        // Outputs nix did not evaluate: other systems, and legacyPackages
+        // without `legacy`.
+        "omitted": omitted,
+    }))
+}
+
+/// Runs `nix flake show`, evaluating other systems only when needed.
+fn flake_show(flake: &str, options: &ShowOptions) -> Result<Value, Error> {
+    let other_system = match &options.system {
+        Some(system) if !options.all_systems => current_system()? != *system,
+        _ => false,
+    };
+
+    // Run: nix flake show --json [--all-systems] [--legacy] <flake>
+    let mut command = nix::command();
+    command.args(["flake", "show", "--json"]);
+    if options.all_systems || other_system {
+        command.args(["--all-systems"]);
+    }
+    if options.legacy {
+        command.args(["--legacy"]);
+    }
+    let output = command.args([flake]).output()?;
+    if !output.status.success() {
+        return Err(nix_failure("nix flake show", &output));
+    }
+    serde_json::from_slice(&output.stdout)
+        .map_err(|e| Error::other(format!("failed to parse nix output: {}", e)))
+}
+
+/// Evaluates `attr_path` of `flake` into the shape `nix flake show --json`
+/// prints, descending at most `max_depth` levels.
+fn evaluate(flake: &str, attr_path: &str, max_depth: usize) -> Result<Value, Error> {
+    // Attribute sets at the depth limit only report how many attributes they
+    // have, so their attributes are never evaluated.
+    let describe = format!(
+        r#"value: let
+  try = x: let r = builtins.tryEval x; in if r.success then r.value else null;
+  describe = depth: value: let r = builtins.tryEval value; v = r.value; in
+    if !r.success then {{ type = "error"; }}
+    else if builtins.isAttrs v && (v.type or null) == "derivation" then {{
+      type = "derivation";
+      name = try (v.name or null);
+      description = try (v.meta.description or null);
+    }}
+    else if builtins.isAttrs v then
+      (if depth <= 0 then {{ type = "attrset"; attrs = builtins.length (builtins.attrNames v); }}
+       else builtins.mapAttrs (_: describe (depth - 1)) v)
+    else if builtins.isFunction v then {{ type = "function"; }}
+    else {{ type = builtins.typeOf v; }};
+in describe {} value"#,
+        max_depth
+    );
+
+    // Run: nix eval --json <flake>#<attr path> --apply <describe>
+    let output = nix::command()
+        .args([
+            "eval",
+            "--json",
+            &format!("{}#{}", flake, attr_path),
+            "--apply",
+            &describe,
+        ])
+        .output()?;
+    if !output.status.success() {
+        return Err(nix_failure("nix eval", &output));
+    }
+    serde_json::from_slice(&output.stdout)
+        .map_err(|e| Error::other(format!("failed to parse nix output: {}", e)))
+}
+
+fn current_system() -> Result<String, Error> {
+    // Run: nix config show system
+    let output = nix::command().args(["config", "show", "system"]).output()?;
+    if !output.status.success() {
+        return Err(nix_failure("nix config show", &output));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Lists the leaves of `tree`, the output of `nix flake show --json`, with
+/// their attribute paths. Attribute sets at the depth limit are summarized,
+/// and other systems than `options.system` are skipped.
+fn flatten(
+    tree: &Value,
+    path: &str,
+    depth: usize,
+    options: &ShowOptions,
+    outputs: &mut Vec<Value>,
+    omitted: &mut BTreeSet<String>,
+) {
+    let Some(attrs) = tree.as_object() else {
+        return;
+    };
+    if let Some(kind) = attrs.get("type").and_then(Value::as_str) {
+        outputs.push(entry(path, kind, attrs));
+        return;
+    }
+    let name = path.rsplit('.').next().unwrap_or_default();
+    if attrs.is_empty() && is_system(name) {
+        omitted.insert(path.to_string());
+        return;
+    }
+    let children: Vec<(&String, &Value)> = attrs
+        .iter()
+        .filter(|(name, _)| match &options.system {
+            Some(system) => !is_system(name) || *name == system,
+            None => true,
+        })
+        .collect();
+    if options.max_depth.is_some_and(|max_depth| depth >= max_depth) && !path.is_empty() {
+        outputs.push(json!({ "path": path, "type": "attrset", "attrs": children.len() }));
+        return;
+    }
+    for (name, child) in children {
+        let child_path = if path.is_empty() { name.clone() } else { format!("{}.{}", path, name) };
+        flatten(child, &child_path, depth + 1, options, outputs, omitted);
+    }
+}
+
+fn entry(path: &str, kind: &str, attrs: &Map<String, Value>) -> Value {
+    let mut entry = json!({ "path": path, "type": kind });
+    for field in ["name", "description", "attrs"] {
+        if let Some(value) = attrs.get(field).filter(|value| !value.is_null()) {
+            entry[field] = value.clone();
+        }
+    }
+    entry
+}
+
+/// Returns whether `name` looks like a nix system such as `x86_64-linux`.
+fn is_system(name: &str) -> bool {
+    name.split_once('-').is_some_and(|(arch, kernel)| {
+        !arch.is_empty()
+            && arch.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            && SYSTEM_KERNELS.contains(&kernel)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `nix flake show --json` of a flake with packages for two systems,
+    /// evaluated on x86_64-linux.
+    const TREE: &str = r#"{
+        "checks": {
+            "aarch64-darwin": {},
+            "x86_64-linux": {"fmt": {"type": "derivation", "name": "fmt-check"}}
+        },
+        "legacyPackages": {"x86_64-linux": {}},
+        "nixosModules": {"default": {"type": "nixos-module"}},
+        "packages": {
+            "aarch64-darwin": {},
+            "x86_64-linux": {
+                "default": {"type": "derivation", "name": "hello-2.12.1", "description": "A program that produces a familiar, friendly greeting"},
+                "tools": {"a": {"type": "derivation", "name": "a"}, "b": {"type": "derivation", "name": "b"}}
+            }
+        }
+    }"#;
+
+    fn options(system: Option<&str>, max_depth: Option<usize>) -> ShowOptions {
+        ShowOptions {
+            attr_path: None,
+            system: system.map(str::to_string),
+            all_systems: false,
+            max_depth,
+            legacy: false,
+        }
+    }
+
+    fn flattened(options: &ShowOptions) -> (Vec<Value>, BTreeSet<String>) {
+        let tree: Value = serde_json::from_str(TREE).unwrap();
+        let (mut outputs, mut omitted) = (Vec::new(), BTreeSet::new());
+        flatten(&tree, "", 0, options, &mut outputs, &mut omitted);
+        (outputs, omitted)
+    }
+
+    fn paths(outputs: &[Value]) -> Vec<&str> {
+        outputs
+            .iter()
+            .map(|output| output["path"].as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn recognizes_systems() {
+        for system in [
+            "x86_64-linux",
+            "aarch64-darwin",
+            "i686-windows",
+            "riscv64-none",
+        ] {
+            assert!(is_system(system), "{}", system);
+        }
+        for name in [
+            "default",
+            "x86_64",
+            "hello-world",
+            "-linux",
+            "x86-64-linux",
+            "x86_64-linux-gnu",
+        ] {
+            assert!(!is_system(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn lists_leaves_and_omitted_systems() {
+        let (outputs, omitted) = flattened(&options(None, None));
+        assert_eq!(
+            paths(&outputs),
+            [
+                "checks.x86_64-linux.fmt",
+                "nixosModules.default",
+                "packages.x86_64-linux.default",
+                "packages.x86_64-linux.tools.a",
+                "packages.x86_64-linux.tools.b",
+            ]
+        );
+        assert_eq!(outputs[2]["name"], "hello-2.12.1");
+        assert_eq!(
+            outputs[2]["description"],
+            "A program that produces a familiar, friendly greeting"
+        );
+        assert_eq!(
+            omitted.into_iter().collect::<Vec<_>>(),
+            [
+                "checks.aarch64-darwin",
+                "legacyPackages.x86_64-linux",
+                "packages.aarch64-darwin"
+            ]
+        );
+    }
+
+    #[test]
+    fn summarizes_attribute_sets_at_the_depth_limit() {
+        let (outputs, _) = flattened(&options(None, Some(3)));
+        assert_eq!(
+            outputs[3],
+            json!({"path": "packages.x86_64-linux.tools", "type": "attrset", "attrs": 2})
+        );
+
+        let (outputs, omitted) = flattened(&options(None, Some(1)));
+        assert_eq!(
+            paths(&outputs),
+            ["checks", "legacyPackages", "nixosModules", "packages"]
+        );
+        assert_eq!(outputs[0]["attrs"], 2);
+        assert!(omitted.is_empty());
+    }
+
+    #[test]
+    fn skips_other_systems() {
+        let (outputs, omitted) = flattened(&options(Some("aarch64-darwin"), None));
+        assert_eq!(paths(&outputs), ["nixosModules.default"]);
+        assert_eq!(
+            omitted.into_iter().collect::<Vec<_>>(),
+            ["checks.aarch64-darwin", "packages.aarch64-darwin"]
+        );
+
+        let (outputs, _) = flattened(&options(Some("x86_64-linux"), Some(2)));
+        assert_eq!(
+            outputs[0],
+            json!({"path": "checks.x86_64-linux", "type": "attrset", "attrs": 1})
+        );
+    }
+}
@@ -0,0 +1,319 @@
+use std::collections::BTreeSet;
+use std::io::Error;
+use std::path::PathBuf;
+use std::process::Output;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{Value, json};
+
+use crate::closure;
+use crate::config::{self, Profile};
+use crate::flake_lock;
+use crate::nix::{self, NixCommand};
+use crate::nix_error::nix_failure;
+use crate::path_info;
+use crate::progress;
+
+/// A lock file in the temporary directory, removed when dropped.
+struct TempLock(PathBuf);
+
+impl TempLock {
+    fn new() -> TempLock {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.subsec_nanos());
+        let name = format!("rime-{}-{}.lock", std::process::id(), nanos);
+        TempLock(std::env::temp_dir().join(name))
+    }
+
+    fn path(&self) -> &str {
+        self.0.to_str().unwrap_or_default()
+    }
+}
+
+impl Drop for TempLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Previews updating `inputs` of `flake`, or all of its inputs if empty,
+/// without touching its lock file: the updated lock file is written to a
+/// temporary file instead. Reports the old and new revision and date of every
+/// input that changed, with a compare URL for GitHub inputs.
+///
+/// With `output`, a flake output attribute, also reports how its name and
+/// store path change. With `closure` as well, both versions of the output are
+/// realised, which needs the `build` profile, and their closures compared.
+/// Comparing outputs needs a nix that supports `--reference-lock-file`.
+pub(crate) fn preview(
+    flake: &str,
+    inputs: &[String],
+    output: Option<&str>,
+    closure: bool,
+) -> Result<Value, Error> {
+    if closure {
+        config::require_profile(Profile::Build, "comparing closures")?;
+    }
+    let old_lock = flake_lock::read_lock(flake)?;
+
+    let new_lock_file = TempLock::new();
+    progress::report(match inputs {
+        [] => format!("updating all inputs of {}", flake),
+        inputs => format!("updating {}", inputs.join(", ")),
+    });
+    update(flake, inputs, new_lock_file.path())?;
+    let contents = std::fs::read_to_string(&new_lock_file.0)
+        .map_err(|e| Error::other(format!("nix flake update wrote no lock file: {}", e)))?;
+    let new_lock: Value = serde_json::from_str(&contents)
+        .map_err(|e| Error::other(format!("failed to parse updated lock file: {}", e)))?;
+
+    let old_inputs = flake_lock::input_nodes(&old_lock)?;
+    let new_inputs = flake_lock::input_nodes(&new_lock)?;
+    let paths: BTreeSet<&String> = old_inputs.keys().chain(new_inputs.keys()).collect();
+
+    let mut updated = Vec::new();
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+    let mut unchanged = 0;
+    for path in paths {
+        match (old_inputs.get(path), new_inputs.get(path)) {
+            (Some(old), Some(new)) if locked(old) == locked(new) => unchanged += 1,
+            (Some(old), Some(new)) => {
+                let mut update = json!({
+                    "input": path,
+                    "flake": new.get("original").map(flake_lock::flake_ref),
+                    "old": revision(old),
+                    "new": revision(new),
+                });
+                if let Some(url) = compare_url(old, new) {
+                    update["compare_url"] = json!(url);
+                }
+                updated.push(update);
+            }
+            (None, Some(new)) => added.push(json!({ "input": path, "new": revision(new) })),
+            (Some(old), None) => removed.push(json!({ "input": path, "old": revision(old) })),
+            (None, None) => {}
+        }
+    }
+
+    let mut report = json!({
+        "flake": flake,
+        "updated": updated,
+        "added": added,
+        "removed": removed,
+        "unchanged": unchanged,
+    });
+    if let Some(output) = output {
+        let installable = format!("{}#{}", flake, output);
+        let old = describe(&installable, None)?;
+        let new = describe(&installable, Some(new_lock_file.path()))?;
+        let mut comparison = json!({
+            "installable": installable,
+            "changed": old.get("outPath") != new.get("outPath"),
+            "old": old,
+            "new": new,
+        });
+        if closure {
+            let old_path = realise(&installable, None)?;
+            let new_path = realise(&installable, Some(new_lock_file.path()))?;
+            let before = path_info::path_info(&old_path, true)?;
+            let after = path_info::path_info(&new_path, true)?;
+            comparison["closure"] = closure::diff(&before, &after);
+        }
+        report["output"] = comparison;
+    }
+    Ok(report)
+}
+
+/// Writes the lock file of `flake` with `inputs` updated to `lock_file`.
+fn update(flake: &str, inputs: &[String], lock_file: &str) -> Result<(), Error> {
+    // Run: nix flake update --flake <flake> --output-lock-file <lock file> [inputs...]
+    let output = nix::command()
+        .args(["flake", "update", "--flake", flake, "--output-lock-file", lock_file])
+        .args(inputs)
+        .output()?;
+    if output.status.success() {
+        return Ok(());
+    }
+
+    // Nix before 2.19 takes the flake as argument and updates single inputs
+    // with `nix flake lock --update-input`.
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !stderr.contains("unrecognised flag '--flake'") {
+        return Err(nix_failure("nix flake update", &output));
+    }
+    // Run: nix flake lock --update-input <input>... --output-lock-file <lock file> <flake>
+    // or:  nix flake update --output-lock-file <lock file> <flake>
+    let mut command = nix::command();
+    if inputs.is_empty() {
+        command.args(["flake", "update"]);
+    } else {
+        command.args(["flake", "lock"]);
+        for input in inputs {
+            command.args(["--update-input", input]);
+        }
+    }
+    let output = command
+        .args(["--output-lock-file", lock_file, flake])
+        .output()?;
+    if !output.status.success() {
+        return Err(nix_failure("nix flake update", &output));
+    }
+    Ok(())
+}
+
+/// What a lock file node is locked to, to tell whether an input changed.
+fn locked(node: &Value) -> Option<&Value> {
+    let locked = node.get("locked")?;
+    locked.get("rev").or(locked.get("narHash"))
+}
+
+fn revision(node: &Value) -> Value {
+    let locked = node.get("locked");
+    let field = |name: &str| locked.and_then(|locked| locked.get(name));
+    json!({
+        "rev": field("rev"),
+        "last_modified": field("lastModified").and_then(Value::as_u64).map(flake_lock::format_date),
+    })
+}
+
+/// Returns the URL comparing the old and new revision of a GitHub input.
+fn compare_url(old: &Value, new: &Value) -> Option<String> {
+    let (old, new) = (old.get("locked")?, new.get("locked")?);
+    let field = |locked: &Value, name: &str| locked.get(name).and_then(Value::as_str).map(str::to_string);
+    if field(new, "type")? != "github" {
+        return None;
+    }
+    let (owner, repo) = (field(new, "owner")?, field(new, "repo")?);
+    // Only compare revisions of the same repository.
+    if field(old, "owner")?.to_lowercase() != owner.to_lowercase()
+        || field(old, "repo")?.to_lowercase() != repo.to_lowercase()
+    {
+        return None;
+    }
+    let host = field(new, "host").unwrap_or_else(|| "github.com".to_string());
+    Some(format!(
+        "https://{}/{}/{}/compare/{}...{}",
+        host,
+        owner,
+        repo,
+        field(old, "rev")?,
+        field(new, "rev")?
+    ))
+}
+
+/// Adds `--reference-lock-file` to `command` when evaluating against an
+/// updated lock file.
+fn with_lock_file<'a>(command: &'a mut NixCommand, lock_file: Option<&str>) -> &'a mut NixCommand {
+    command.args(["--no-write-lock-file"]);
+    if let Some(lock_file) = lock_file {
+        command.args(["--reference-lock-file", lock_file]);
+    }
+    command
+}
+
+/// Returns the error for a failed invocation with [`with_lock_file`],
+/// explaining when nix is too old for `--reference-lock-file`.
+fn lock_file_failure(command: &str, output: &Output) -> Error {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if stderr.contains("unrecognised flag '--reference-lock-file'") {
+        return Error::other(
+            "comparing outputs needs `--reference-lock-file`, which this nix does not support; \
+             update nix or leave out `output`",
+        );
+    }
+    nix_failure(command, output)
+}
+
+/// Returns the name and store path of `installable`.
+fn describe(installable: &str, lock_file: Option<&str>) -> Result<Value, Error> {
+    // Run: nix eval --json <installable> --apply <name and out path> --no-write-lock-file [--reference-lock-file <lock file>]
+    let mut command = nix::command();
+    command.args([
+        "eval",
+        "--json",
+        installable,
+        "--apply",
+        "out: { name = out.name or null; outPath = out.outPath or null; }",
+    ]);
+    let output = with_lock_file(&mut command, lock_file).output()?;
+    if !output.status.success() {
+        return Err(lock_file_failure("nix eval", &output));
+    }
+    serde_json::from_slice(&output.stdout)
+        .map_err(|e| Error::other(format!("failed to parse nix output: {}", e)))
+}
+
+/// Builds or substitutes `installable` and returns its store path.
+fn realise(installable: &str, lock_file: Option<&str>) -> Result<String, Error> {
+    progress::report(match lock_file {
+        Some(_) => format!("realising updated {}", installable),
+        None => format!("realising {}", installable),
+    });
+    // Run: nix build --no-link --print-out-paths <installable> --no-write-lock-file [--reference-lock-file <lock file>]
+    let mut command = nix::command();
+    command.args(["build", "--no-link", "--print-out-paths", installable]);
+    let output = with_lock_file(&mut command, lock_file).output()?;
+    if !output.status.success() {
+        return Err(lock_file_failure("nix build", &output));
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .map(str::to_string)
+        .ok_or_else(|| Error::other(format!("{} has no store path", installable)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn github(owner: &str, repo: &str, rev: &str) -> Value {
+        json!({"locked": {"type": "github", "owner": owner, "repo": repo, "rev": rev}})
+    }
+
+    #[test]
+    fn compares_github_revisions() {
+        let old = github("NixOS", "nixpkgs", "aaa");
+        let new = github("nixos", "Nixpkgs", "bbb");
+        assert_eq!(
+            compare_url(&old, &new).as_deref(),
+            Some("https://github.com/nixos/Nixpkgs/compare/aaa...bbb")
+        );
+
+        let mut enterprise = github("team", "repo", "bbb");
+        enterprise["locked"]["host"] = json!("git.example.com");
+        assert_eq!(
+            compare_url(&github("team", "repo", "aaa"), &enterprise).as_deref(),
+            Some("https://git.example.com/team/repo/compare/aaa...bbb")
+        );
+    }
+
+    #[test]
+    fn compares_only_the_same_github_repository() {
+        let old = github("NixOS", "nixpkgs", "aaa");
+        assert_eq!(compare_url(&old, &github("NixOS", "nix", "bbb")), None);
+        assert_eq!(
+            compare_url(&old, &github("someone", "nixpkgs", "bbb")),
+            None
+        );
+
+        let git =
+            json!({"locked": {"type": "git", "url": "https://example.com/repo.git", "rev": "bbb"}});
+        assert_eq!(compare_url(&old, &git), None);
+        assert_eq!(compare_url(&old, &json!({})), None);
+    }
+
+    #[test]
+    fn locks_by_revision_or_hash() {
+        assert_eq!(
+            locked(&github("NixOS", "nixpkgs", "aaa")),
+            Some(&json!("aaa"))
+        );
+        let tarball = json!({"locked": {"type": "tarball", "narHash": "sha256-abc"}});
+        assert_eq!(locked(&tarball), Some(&json!("sha256-abc")));
+        assert_eq!(locked(&json!({"original": {}})), None);
+    }
+}
@@ -7,6 +7,8 @@ use rust_mcp_sdk::schema::{
 };
 use rust_mcp_sdk::{McpServer, mcp_server::ServerHandler};
 
+use crate::capabilities;
+use crate::progress::{self, Reporter};
 use crate::tools::RimeTools;
 
 pub struct RimeServerHandler;
@@ -18,42 +20,99 @@ impl ServerHandler for RimeServerHandler {
         _request: Option<PaginatedRequestParams>,
         _runtime: Arc<dyn McpServer>,
     ) -> std::result::Result<ListToolsResult, RpcError> {
+        let tools = match capabilities::get() {
+            Some(capabilities) => capabilities.filter_tools(RimeTools::tools()),
+            None => RimeTools::tools(),
+        };
+
         Ok(ListToolsResult {
             meta: None,
             next_cursor: None,
-            tools: RimeTools::tools(),
+            tools,
         })
     }
 
     async fn handle_call_tool_request(
         &self,
         params: CallToolRequestParams,
-        _runtime: Arc<dyn McpServer>,
+        runtime: Arc<dyn McpServer>,
     ) -> std::result::Result<CallToolResult, CallToolError> {
+        // Over stdio there is a single, unnamed session.
+        let session = runtime.session_id().unwrap_or_default();
+        let reporter = params
+            .meta
+            .as_ref()
+            .and_then(|meta| meta.progress_token.clone())
+            .map(|token| Reporter::forward(runtime, token));
+
+        if let Some(reason) =
+            capabilities::get().and_then(|capabilities| capabilities.missing(&params.name))
+        {
+            return Err(CallToolError::from_message(format!(
+                "{} is unavailable: {}",
+                params.name, reason
+            )));
+        }
+
         let tool_params: RimeTools =
             RimeTools::try_from(params).map_err(CallToolError::new)?;
 
-        match tool_params {
-            RimeTools::NixEvaluateTool(tool) => tool.call_tool(),
-            RimeTools::NixLogTool(tool) => tool.call_tool(),
-            RimeTools::NixPackagesSearchTool(tool) => tool.call_tool(),
-            RimeTools::NixPackagesWhyDepends(tool) => tool.call_tool(),
-            RimeTools::NixFlakesShowTool(tool) => tool.call_tool(),
-            RimeTools::NixFlakesMetadataTool(tool) => tool.call_tool(),
-            RimeTools::NixConfigShowTool(tool) => tool.call_tool(),
-            RimeTools::NixOSChannelsTool(tool) => tool.call_tool(),
-            RimeTools::NixManualListTool(tool) => tool.call_tool(),
-            RimeTools::NixManualReadTool(tool) => tool.call_tool(),
-            RimeTools::NixOSWikiSearchTool(tool) => tool.call_tool(),
-            RimeTools::NixOSWikiReadTool(tool) => tool.call_tool(),
-            RimeTools::NixConfigCheckTool(tool) => tool.call_tool(),
-            RimeTools::ManixSearchTool(tool) => tool.call_tool(),
-            RimeTools::NixHubPackageVersionsTool(tool) => tool.call_tool(),
-            RimeTools::HomeManagerOptionsSearch(tool) => tool.call_tool(),
-            RimeTools::NvfOptionsSearchTool(tool) => tool.call_tool(),
-            RimeTools::NvfManualListTool(tool) => tool.call_tool(),
-            RimeTools::NvfManualReadTool(tool) => tool.call_tool(),
-            RimeTools::NixpkgsOptionsSearchTool(tool) => tool.call_tool(),
-        }
+        // Tools block on subprocesses, HTTP requests and concurrency limits,
+        // so they must not run on the async executor. Errors are converted on
+        // the blocking thread because `CallToolError` cannot cross threads.
+        tokio::task::spawn_blocking(move || {
+            progress::with_reporter(reporter, || call_tool(tool_params, &session))
+                .unwrap_or_else(CallToolResult::from)
+        })
+        .await
+        .map_err(CallToolError::new)
+    }
+}
+
+fn call_tool(
+    tool_params: RimeTools,
+    session: &str,
+) -> std::result::Result<CallToolResult, CallToolError> {
+    match tool_params {
+        RimeTools::NixEvaluateTool(tool) => tool.call_tool(),
+        RimeTools::NixLogTool(tool) => tool.call_tool(),
+        RimeTools::NixReplTool(tool) => tool.call_tool(session),
+        RimeTools::NixBuildTool(tool) => tool.call_tool(),
+        RimeTools::NixHashMismatchTool(tool) => tool.call_tool(),
+        RimeTools::NixPrefetchTool(tool) => tool.call_tool(),
+        RimeTools::NixHashConvertTool(tool) => tool.call_tool(),
+        RimeTools::NixStorePathParseTool(tool) => tool.call_tool(),
+        RimeTools::NixDerivationShowTool(tool) => tool.call_tool(),
+        RimeTools::NixDerivationDiffTool(tool) => tool.call_tool(),
+        RimeTools::NixPathInfoTool(tool) => tool.call_tool(),
+        RimeTools::NixClosureGraphTool(tool) => tool.call_tool(),
+        RimeTools::NixStoreDiffClosuresTool(tool) => tool.call_tool(),
+        RimeTools::NixStoreLsTool(tool) => tool.call_tool(),
+        RimeTools::NixStoreCatTool(tool) => tool.call_tool(),
+        RimeTools::NixCacheCheckTool(tool) => tool.call_tool(),
+        RimeTools::NixPackagesSearchTool(tool) => tool.call_tool(),
+        RimeTools::NixPackagesWhyDepends(tool) => tool.call_tool(),
+        RimeTools::NixFlakesShowTool(tool) => tool.call_tool(),
+        RimeTools::NixFlakesMetadataTool(tool) => tool.call_tool(),
+        RimeTools::NixFlakesCheckTool(tool) => tool.call_tool(),
+        RimeTools::NixFlakesLockTool(tool) => tool.call_tool(),
+        RimeTools::NixFlakesUpdatePreviewTool(tool) => tool.call_tool(),
+        RimeTools::NixRegistryListTool(tool) => tool.call_tool(),
+        RimeTools::NixRegistryResolveTool(tool) => tool.call_tool(),
+        RimeTools::NixRegistryPinTool(tool) => tool.call_tool(),
+        RimeTools::NixConfigShowTool(tool) => tool.call_tool(),
+        RimeTools::NixOSChannelsTool(tool) => tool.call_tool(),
+        RimeTools::NixManualListTool(tool) => tool.call_tool(),
+        RimeTools::NixManualReadTool(tool) => tool.call_tool(),
+        RimeTools::NixOSWikiSearchTool(tool) => tool.call_tool(),
+        RimeTools::NixOSWikiReadTool(tool) => tool.call_tool(),
+        RimeTools::NixConfigCheckTool(tool) => tool.call_tool(),
+        RimeTools::ManixSearchTool(tool) => tool.call_tool(),
+        RimeTools::NixHubPackageVersionsTool(tool) => tool.call_tool(),
+        RimeTools::HomeManagerOptionsSearch(tool) => tool.call_tool(),
+        RimeTools::NvfOptionsSearchTool(tool) => tool.call_tool(),
+        RimeTools::NvfManualListTool(tool) => tool.call_tool(),
+        RimeTools::NvfManualReadTool(tool) => tool.call_tool(),
+        RimeTools::NixpkgsOptionsSearchTool(tool) => tool.call_tool(),
     }
 }
@@ -0,0 +1,288 @@
+use std::io::Error;
+
+use serde::Serialize;
+
+/// The alphabet of Nix's base32 encoding, which omits `e`, `o`, `u` and `t`.
+const NIX32_ALPHABET: &[u8; 32] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Hash algorithms supported by Nix and their digest sizes in bytes.
+const ALGORITHMS: [(&str, usize); 4] = [("md5", 16), ("sha1", 20), ("sha256", 32), ("sha512", 64)];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Encoding {
+    Base16,
+    Nix32,
+    Base64,
+}
+
+/// A hash in every encoding Nix understands.
+#[derive(Debug, Serialize)]
+pub(crate) struct ConvertedHash {
+    pub(crate) algorithm: String,
+    /// The encoding the hash was given in.
+    pub(crate) input_encoding: Encoding,
+    pub(crate) base16: String,
+    pub(crate) nix32: String,
+    pub(crate) base64: String,
+    pub(crate) sri: String,
+}
+
+/// Parses `hash` in any format Nix accepts (`<algo>-<base64>` SRI,
+/// `<algo>:<digest>` or a bare base16, nix32 or base64 digest) and returns it
+/// in every encoding. `algorithm` is needed for bare digests whose length is
+/// ambiguous.
+pub(crate) fn convert(hash: &str, algorithm: Option<&str>) -> Result<ConvertedHash, Error> {
+    let hash = hash.trim();
+    let (prefix, digest, sri) = if let Some((algo, digest)) = split_prefix(hash, '-') {
+        (Some(algo), digest, true)
+    } else if let Some((algo, digest)) = split_prefix(hash, ':') {
+        (Some(algo), digest, false)
+    } else {
+        (None, hash, false)
+    };
+
+    if let (Some(prefix), Some(algorithm)) = (prefix, algorithm)
+        && prefix != algorithm
+    {
+        return Err(Error::other(format!(
+            "hash is {} but algorithm {} was given",
+            prefix, algorithm
+        )));
+    }
+
+    let candidates: Vec<(&str, usize)> = match prefix.or(algorithm) {
+        Some(name) => {
+            let algorithm = ALGORITHMS
+                .iter()
+                .find(|(algo, _)| *algo == name)
+                .ok_or_else(|| {
+                    Error::other(format!(
+                        "unknown hash algorithm {}: expected md5, sha1, sha256 or sha512",
+                        name
+                    ))
+                })?;
+            vec![*algorithm]
+        }
+        None => ALGORITHMS.to_vec(),
+    };
+
+    // SRI hashes are always base64; otherwise the encoding follows from the
+    // digest length.
+    let mut matches = Vec::new();
+    for (algo, size) in candidates {
+        let encodings: &[Encoding] = if sri {
+            &[Encoding::Base64]
+        } else {
+            &[Encoding::Base16, Encoding::Nix32, Encoding::Base64]
+        };
+        for &encoding in encodings {
+            if digest.len() != encoded_len(encoding, size) {
+                continue;
+            }
+            if let Some(bytes) = decode(encoding, digest, size) {
+                matches.push((algo, encoding, bytes));
+            }
+        }
+    }
+
+    let (algo, encoding, bytes) = match matches.len() {
+        0 => {
+            return Err(Error::other(format!(
+                "{} is not a valid md5, sha1, sha256 or sha512 hash in base16, nix32, base64 or SRI format",
+                hash
+            )));
+        }
+        1 => matches.remove(0),
+        _ => {
+            let options: Vec<String> = matches
+                .iter()
+                .map(|(algo, encoding, _)| format!("{} in {:?}", algo, encoding).to_lowercase())
+                .collect();
+            return Err(Error::other(format!(
+                "{} is ambiguous ({}); pass the algorithm",
+                hash,
+                options.join(" or ")
+            )));
+        }
+    };
+
+    let base64 = encode_base64(&bytes);
+    Ok(ConvertedHash {
+        algorithm: algo.to_string(),
+        input_encoding: encoding,
+        base16: encode_base16(&bytes),
+        nix32: encode_nix32(&bytes),
+        sri: format!("{}-{}", algo, base64),
+        base64,
+    })
+}
+
+fn split_prefix(hash: &str, separator: char) -> Option<(&str, &str)> {
+    let (algo, digest) = hash.split_once(separator)?;
+    ALGORITHMS
+        .iter()
+        .any(|(name, _)| *name == algo)
+        .then_some((algo, digest))
+}
+
+fn encoded_len(encoding: Encoding, size: usize) -> usize {
+    match encoding {
+        Encoding::Base16 => size * 2,
+        Encoding::Nix32 => (size * 8).div_ceil(5),
+        Encoding::Base64 => size.div_ceil(3) * 4,
+    }
+}
+
+fn decode(encoding: Encoding, digest: &str, size: usize) -> Option<Vec<u8>> {
+    match encoding {
+        Encoding::Base16 => decode_base16(digest),
+        Encoding::Nix32 => decode_nix32(digest, size),
+        Encoding::Base64 => decode_base64(digest).filter(|bytes| bytes.len() == size),
+    }
+}
+
+fn encode_base16(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_base16(digest: &str) -> Option<Vec<u8>> {
+    // `from_str_radix` alone would also accept a leading `+`.
+    if !digest.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    digest
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            let hex = std::str::from_utf8(pair).ok()?;
+            u8::from_str_radix(hex, 16).ok()
+        })
+        .collect()
+}
+
+/// Encodes `bytes` the way Nix does: least significant bits first, with the
+/// last character printed first.
+fn encode_nix32(bytes: &[u8]) -> String {
+    let len = (bytes.len() * 8).div_ceil(5);
+    (0..len)
+        .rev()
+        .map(|n| {
+            let bit = n * 5;
+            let (i, j) = (bit / 8, bit % 8);
+            let low = u16::from(bytes[i]) >> j;
+            let high = bytes.get(i + 1).map_or(0, |b| u16::from(*b) << (8 - j));
+            NIX32_ALPHABET[usize::from((low | high) & 0x1f)] as char
+        })
+        .collect()
+}
+
+fn decode_nix32(digest: &str, size: usize) -> Option<Vec<u8>> {
+    let mut bytes = vec![0u8; size];
+    for (n, c) in digest.bytes().rev().enumerate() {
+        let digit = NIX32_ALPHABET.iter().position(|&a| a == c)? as u16;
+        let bit = n * 5;
+        let (i, j) = (bit / 8, bit % 8);
+        bytes[i] |= (digit << j) as u8;
+        let carry = digit >> (8 - j);
+        match bytes.get_mut(i + 1) {
+            Some(next) => *next |= carry as u8,
+            None if carry != 0 => return None,
+            None => {}
+        }
+    }
+    Some(bytes)
+}
+
+fn encode_base64(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        for k in 0..4 {
+            if k <= chunk.len() {
+                out.push(BASE64_ALPHABET[((n >> (18 - 6 * k)) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn decode_base64(digest: &str) -> Option<Vec<u8>> {
+    let trimmed = digest.trim_end_matches('=');
+    if !digest.len().is_multiple_of(4) || digest.len() - trimmed.len() > 2 {
+        return None;
+    }
+    let mut bits = 0u32;
+    let mut count = 0;
+    let mut bytes = Vec::new();
+    for c in trimmed.bytes() {
+        let value = BASE64_ALPHABET.iter().position(|&a| a == c)? as u32;
+        bits = (bits << 6) | value;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            bytes.push((bits >> count) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The sha256 of the empty string.
+    const EMPTY_SHA256_BASE16: &str =
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+    const EMPTY_SHA256_NIX32: &str = "0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73";
+    const EMPTY_SHA256_SRI: &str = "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=";
+
+    #[test]
+    fn converts_sha256_between_encodings() {
+        for (input, encoding) in [
+            (EMPTY_SHA256_SRI, Encoding::Base64),
+            (EMPTY_SHA256_NIX32, Encoding::Nix32),
+            (EMPTY_SHA256_BASE16, Encoding::Base16),
+        ] {
+            let hash = convert(input, Some("sha256")).unwrap();
+            assert_eq!(hash.input_encoding, encoding, "{}", input);
+            assert_eq!(hash.algorithm, "sha256");
+            assert_eq!(hash.base16, EMPTY_SHA256_BASE16);
+            assert_eq!(hash.nix32, EMPTY_SHA256_NIX32);
+            assert_eq!(hash.sri, EMPTY_SHA256_SRI);
+        }
+    }
+
+    #[test]
+    fn converts_prefixed_hashes_without_algorithm() {
+        let hash = convert(&format!("sha256:{}", EMPTY_SHA256_NIX32), None).unwrap();
+        assert_eq!(hash.sri, EMPTY_SHA256_SRI);
+
+        let hash = convert("sha1-2jmj7l5rSw0yVb/vlWAYkK/YBwk=", None).unwrap();
+        assert_eq!(hash.base16, "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(hash.nix32, "143xibwh31h9bvxzalr0sjvbbvpa6ffs");
+    }
+
+    #[test]
+    fn converts_fake_hash() {
+        // `lib.fakeHash` and `lib.fakeSha256` from nixpkgs.
+        let hash = convert("sha256-AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=", None).unwrap();
+        assert_eq!(hash.base16, "0".repeat(64));
+        assert_eq!(hash.nix32, "0".repeat(52));
+    }
+
+    #[test]
+    fn rejects_invalid_digests() {
+        let plus = format!("+{}", &EMPTY_SHA256_BASE16[1..]);
+        assert!(convert(&plus, Some("sha256")).is_err());
+        assert!(convert(&EMPTY_SHA256_NIX32.replace('0', "e"), Some("sha256")).is_err());
+        assert!(convert("sha256-47DEQpj8", None).is_err());
+        assert!(convert(EMPTY_SHA256_SRI, Some("sha512")).is_err());
+    }
+}
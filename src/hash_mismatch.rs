@@ -0,0 +1,326 @@
+use std::io::Error;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::hash;
+use crate::nix;
+use crate::nix_error::nix_failure;
+
+/// Attributes that hold the hash of a fixed-output derivation.
+const HASH_ATTRIBUTES: [&str; 8] = [
+    "hash",
+    "sha256",
+    "outputHash",
+    "vendorHash",
+    "cargoHash",
+    "npmDepsHash",
+    "pnpmDeps.hash",
+    "sha512",
+];
+
+/// Placeholder values people put in a hash attribute before the first build.
+const PLACEHOLDERS: [&str; 4] = ["lib.fakeHash", "lib.fakeSha256", "fakeHash", "\"\""];
+
+/// How far below the `src` position a hash attribute is looked for. Without
+/// a position the whole file is searched.
+const MAX_SEARCH_LINES: usize = 60;
+
+/// A `hash mismatch in fixed-output derivation` error.
+#[derive(Debug, Serialize)]
+pub(crate) struct HashMismatch {
+    pub(crate) derivation: Option<String>,
+    pub(crate) specified: String,
+    pub(crate) got: String,
+}
+
+/// A replacement of `old` by `new` on line `line` of `file`.
+#[derive(Debug, Serialize)]
+pub(crate) struct Edit {
+    pub(crate) file: String,
+    pub(crate) line: usize,
+    pub(crate) old: String,
+    pub(crate) new: String,
+}
+
+/// Extracts every hash mismatch reported in `output`, e.g.
+///
+/// ```text
+/// error: hash mismatch in fixed-output derivation '/nix/store/...-source.drv':
+///          specified: sha256-AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=
+///             got:    sha256-3OqA/5kH4IyUL0PzLvgKCm2Bw3H1FtbpwZTXdgzQIo0=
+/// ```
+pub(crate) fn parse_mismatches(output: &str) -> Vec<HashMismatch> {
+    let mut mismatches = Vec::new();
+    let mut derivation = None;
+    let mut specified = None;
+    for line in output.lines() {
+        let line = line.trim().trim_start_matches(">").trim();
+        if line.contains("hash mismatch") {
+            derivation = line
+                .split(|c: char| "'‘’\"".contains(c))
+                .find(|word| word.starts_with('/') && word.ends_with(".drv"))
+                .map(str::to_string);
+            specified = None;
+        } else if let Some(hash) = line.strip_prefix("specified:") {
+            specified = Some(hash.trim().to_string());
+        } else if let Some(hash) = line.strip_prefix("got:")
+            && let Some(specified) = specified.take()
+        {
+            mismatches.push(HashMismatch {
+                derivation: derivation.take(),
+                specified,
+                got: hash.trim().to_string(),
+            });
+        }
+    }
+    mismatches
+}
+
+/// Returns where the source of `installable` is defined, from the position of
+/// its `src` attribute or else its `meta.position`. Positions inside a copy of
+/// a local flake are mapped back to the flake's directory.
+pub(crate) fn source_position(
+    installable: &str,
+) -> Result<Option<(PathBuf, Option<usize>)>, Error> {
+    // Run: nix eval --json <installable> --apply <position>
+    let output = nix::command()
+        .args([
+            "eval",
+            "--json",
+            installable,
+            "--apply",
+            r#"pkg: let pos = builtins.unsafeGetAttrPos "src" pkg; in if pos != null then "${pos.file}:${toString pos.line}" else pkg.meta.position or null"#,
+        ])
+        .output()?;
+    if !output.status.success() {
+        return Err(nix_failure("nix eval", &output));
+    }
+
+    let position: Option<String> = serde_json::from_slice(&output.stdout)
+        .map_err(|e| Error::other(format!("failed to parse nix output: {}", e)))?;
+    let Some(position) = position else {
+        return Ok(None);
+    };
+    let (file, line) = match position.rsplit_once(':') {
+        Some((file, line)) if let Ok(line) = line.parse() => (file, Some(line)),
+        _ => (position.as_str(), None),
+    };
+
+    let file = match local_flake_source(installable, file)? {
+        Some(local) => local,
+        None => PathBuf::from(file),
+    };
+    Ok(Some((file, line)))
+}
+
+/// Maps `file` inside the store copy of a local flake to the same file in the
+/// flake's directory, so that edits land in the user's checkout.
+fn local_flake_source(installable: &str, file: &str) -> Result<Option<PathBuf>, Error> {
+    let flake_ref = installable.split_once('#').map_or(installable, |(flake, _)| flake);
+    let dir = flake_ref
+        .strip_prefix("path:")
+        .or_else(|| flake_ref.strip_prefix("git+file://"))
+        .unwrap_or(flake_ref);
+    let dir = dir.split_once('?').map_or(dir, |(dir, _)| dir);
+    let dir = Path::new(if dir.is_empty() { "." } else { dir });
+    if !dir.join("flake.nix").exists() {
+        return Ok(None);
+    }
+
+    // Run: nix flake metadata --json <flake>
+    let output = nix::command()
+        .args(["flake", "metadata", "--json", flake_ref])
+        .output()?;
+    if !output.status.success() {
+        return Err(nix_failure("nix flake metadata", &output));
+    }
+    let metadata: serde_json::Value = serde_json::from_slice(&output.stdout)
+        .map_err(|e| Error::other(format!("failed to parse nix output: {}", e)))?;
+
+    let Some(store_path) = metadata.get("path").and_then(|p| p.as_str()) else {
+        return Ok(None);
+    };
+    Ok(file
+        .strip_prefix(store_path)
+        .map(|relative| dir.join(relative.trim_start_matches('/'))))
+}
+
+/// Finds the hash to replace in `file`: the specified hash if it appears
+/// exactly once, in any spelling, or else the first hash attribute at or below
+/// `line` (anywhere in the file without one) holding the specified hash or a
+/// placeholder.
+pub(crate) fn find_edit(
+    file: &Path,
+    line: Option<usize>,
+    mismatch: &HashMismatch,
+) -> Result<Option<Edit>, Error> {
+    let source = std::fs::read_to_string(file)
+        .map_err(|e| Error::other(format!("failed to read {}: {}", file.display(), e)))?;
+    Ok(edit_source(&file.display().to_string(), &source, line, mismatch))
+}
+
+/// Like [`find_edit`], for the contents `source` of `file`.
+fn edit_source(
+    file: &str,
+    source: &str,
+    line: Option<usize>,
+    mismatch: &HashMismatch,
+) -> Option<Edit> {
+    let lines: Vec<&str> = source.lines().collect();
+    let edit = |index: usize, old: &str| Edit {
+        file: file.to_string(),
+        line: index + 1,
+        old: lines[index].to_string(),
+        new: lines[index].replacen(old, &mismatch.got, 1),
+    };
+
+    // Nix prints SRI hashes, but sources may spell the hash without the
+    // algorithm prefix, or in nix32 or base16 as nixpkgs' `sha256` often is.
+    let specified = mismatch.specified.as_str();
+    let bare = specified
+        .split_once(['-', ':'])
+        .map_or(specified, |(_, hash)| hash);
+    let mut spellings = vec![specified.to_string(), bare.to_string()];
+    if let Ok(converted) = hash::convert(specified, None) {
+        spellings.extend([converted.nix32, converted.base16]);
+    }
+    for literal in &spellings {
+        let matches: Vec<usize> = (0..lines.len())
+            .filter(|&i| lines[i].contains(literal.as_str()))
+            .collect();
+        if let [index] = matches[..] {
+            return Some(edit(index, literal));
+        }
+    }
+
+    let (start, end) = match line {
+        Some(line) => {
+            let start = line.saturating_sub(1).min(lines.len());
+            (start, (start + MAX_SEARCH_LINES).min(lines.len()))
+        }
+        None => (0, lines.len()),
+    };
+    for (index, line) in lines.iter().enumerate().take(end).skip(start) {
+        let Some((attribute, value)) = line.split_once('=') else {
+            continue;
+        };
+        if !HASH_ATTRIBUTES.contains(&attribute.trim()) {
+            continue;
+        }
+        let value = value.trim().trim_end_matches(';').trim();
+        if PLACEHOLDERS.contains(&value) {
+            let new = format!("\"{}\"", mismatch.got);
+            return Some(Edit {
+                new: line.replacen(value, &new, 1),
+                ..edit(index, value)
+            });
+        }
+        if let Some(literal) = spellings.iter().find(|literal| value.contains(literal.as_str())) {
+            return Some(edit(index, literal));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The sha256 of the empty string in SRI, nix32 and base16.
+    const EMPTY_SRI: &str = "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=";
+    const EMPTY_NIX32: &str = "0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73";
+    const EMPTY_BASE16: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+    const GOT: &str = "sha256-3XYHZANT6AFBV0BqegkAZHbba6oeDkIUCDwbATLMhAY=";
+
+    fn mismatch(specified: &str) -> HashMismatch {
+        HashMismatch {
+            derivation: None,
+            specified: specified.to_string(),
+            got: GOT.to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_nix_output() {
+        let output = "\
+error: hash mismatch in fixed-output derivation '/nix/store/pd2c4lq5k2q6qvvhv0kd4d5dqg6xwy1a-source.drv':
+         specified: sha256-AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=
+            got:    sha256-3XYHZANT6AFBV0BqegkAZHbba6oeDkIUCDwbATLMhAY=
+error: 1 dependencies of derivation '/nix/store/4jv2wsxbzxmk5w6i7h0mw4jc3bmgvnf2-hello-2.12.1.drv' failed to build
+";
+        let mismatches = parse_mismatches(output);
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(
+            mismatches[0].derivation.as_deref(),
+            Some("/nix/store/pd2c4lq5k2q6qvvhv0kd4d5dqg6xwy1a-source.drv")
+        );
+        assert_eq!(
+            mismatches[0].specified,
+            "sha256-AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="
+        );
+        assert_eq!(mismatches[0].got, GOT);
+    }
+
+    #[test]
+    fn parses_prefixed_output_with_curly_quotes() {
+        let output = "\
+> error: hash mismatch in fixed-output derivation ‘/nix/store/pd2c4lq5k2q6qvvhv0kd4d5dqg6xwy1a-source.drv’:
+>          specified: sha256-AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=
+>             got:    sha256-3XYHZANT6AFBV0BqegkAZHbba6oeDkIUCDwbATLMhAY=
+> error: hash mismatch in fixed-output derivation ‘/nix/store/a1gqnnz8w6sdrr9jx5l1wzj0wg3bi2qr-vendor.drv’:
+>          specified: sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=
+>             got:    sha256-3XYHZANT6AFBV0BqegkAZHbba6oeDkIUCDwbATLMhAY=
+";
+        let mismatches = parse_mismatches(output);
+        let derivations: Vec<Option<&str>> = mismatches
+            .iter()
+            .map(|mismatch| mismatch.derivation.as_deref())
+            .collect();
+        assert_eq!(
+            derivations,
+            [
+                Some("/nix/store/pd2c4lq5k2q6qvvhv0kd4d5dqg6xwy1a-source.drv"),
+                Some("/nix/store/a1gqnnz8w6sdrr9jx5l1wzj0wg3bi2qr-vendor.drv"),
+            ]
+        );
+        assert_eq!(mismatches[1].specified, EMPTY_SRI);
+    }
+
+    #[test]
+    fn replaces_placeholders() {
+        let source = "{ lib, fetchFromGitHub }:\nfetchFromGitHub {\n  owner = \"foo\";\n  hash = lib.fakeHash;\n}\n";
+        let edit = edit_source("default.nix", source, Some(2), &mismatch(EMPTY_SRI)).unwrap();
+        assert_eq!(edit.line, 4);
+        assert_eq!(edit.old, "  hash = lib.fakeHash;");
+        assert_eq!(edit.new, format!("  hash = \"{}\";", GOT));
+
+        let source = "{\n  vendorHash = \"\";\n}\n";
+        let edit = edit_source("default.nix", source, None, &mismatch(EMPTY_SRI)).unwrap();
+        assert_eq!(edit.new, format!("  vendorHash = \"{}\";", GOT));
+    }
+
+    #[test]
+    fn finds_hashes_spelled_in_nix32_or_base16() {
+        for literal in [EMPTY_NIX32, EMPTY_BASE16, &EMPTY_SRI[7..]] {
+            let source = format!(
+                "{{\n  url = \"https://example.org/a.tar.gz\";\n  sha256 = \"{}\";\n}}\n",
+                literal
+            );
+            let edit =
+                edit_source("default.nix", &source, None, &mismatch(EMPTY_SRI)).expect(literal);
+            assert_eq!(edit.line, 3);
+            assert_eq!(edit.new, format!("  sha256 = \"{}\";", GOT));
+        }
+    }
+
+    #[test]
+    fn searches_only_near_a_known_position() {
+        let mut source = "  src = fetchurl {\n".to_string();
+        source.push_str(&"  # filler\n".repeat(MAX_SEARCH_LINES));
+        source.push_str("  hash = lib.fakeHash;\n");
+        assert!(edit_source("default.nix", &source, Some(1), &mismatch(EMPTY_SRI)).is_none());
+        let edit = edit_source("default.nix", &source, None, &mismatch(EMPTY_SRI)).unwrap();
+        assert_eq!(edit.line, MAX_SEARCH_LINES + 2);
+    }
+}
@@ -1,6 +1,10 @@
 use std::collections::HashSet;
 use std::io::Error;
 
+use crate::cache::cached;
+use crate::index::IndexWriter;
+use crate::limits;
+
 const HOME_MANAGER_OPTIONS_URL: &str =
     "https://nix-community.github.io/home-manager/options.xhtml";
 const HOME_MANAGER_RESULTS_LIMIT: usize = 20;
@@ -214,10 +218,10 @@ fn extract_section_html<'a>(
     let mut end = rest.len();
 
     for marker in stop_markers {
-        if let Some(idx) = rest.find(marker) {
-            if idx < end {
-                end = idx;
-            }
+        if let Some(idx) = rest.find(marker)
+            && idx < end
+        {
+            end = idx;
         }
     }
 
@@ -237,17 +241,17 @@ fn extract_section_value(dd_block: &str, label: &str, stop_markers: &[&str]) ->
 
     let mut end = rest.len();
     for marker in stop_markers {
-        if let Some(idx) = rest.find(marker) {
-            if idx < end {
-                end = idx;
-            }
+        if let Some(idx) = rest.find(marker)
+            && idx < end
+        {
+            end = idx;
         }
     }
 
     rest[..end].trim().to_string()
 }
 
-fn extract_dd_block<'a>(html: &'a str, cursor: usize) -> Option<(&'a str, usize)> {
+fn extract_dd_block(html: &str, cursor: usize) -> Option<(&str, usize)> {
     let dd_rel = html[cursor..].find("<dd")?;
     let dd_start = cursor + dd_rel;
     let dd_tag_end_rel = html[dd_start..].find('>')?;
@@ -325,14 +329,8 @@ fn parse_home_manager_options(
     results
 }
 
-pub(crate) fn search_home_manager_options(
-    query: &str,
-) -> Result<Vec<HomeManagerOption>, Error> {
-    let query = query.trim();
-    if query.is_empty() {
-        return Err(Error::other("query must not be empty"));
-    }
-
+fn fetch_home_manager_options() -> Result<String, Error> {
+    let _permit = limits::http().acquire()?;
     let resp = ureq::get(HOME_MANAGER_OPTIONS_URL)
         .set(
             "User-Agent",
@@ -342,9 +340,19 @@ pub(crate) fn search_home_manager_options(
         .call()
         .map_err(|err| Error::other(format!("home manager request failed: {err}")))?;
 
-    let body = resp
-        .into_string()
-        .map_err(|err| Error::other(format!("home manager response read failed: {err}")))?;
+    resp.into_string()
+        .map_err(|err| Error::other(format!("home manager response read failed: {err}")))
+}
+
+pub(crate) fn search_home_manager_options(
+    query: &str,
+) -> Result<Vec<HomeManagerOption>, Error> {
+    let query = query.trim();
+    if query.is_empty() {
+        return Err(Error::other("query must not be empty"));
+    }
+
+    let body = cached("home_manager", HOME_MANAGER_OPTIONS_URL, fetch_home_manager_options)?;
 
     Ok(parse_home_manager_options(
         &body,
@@ -352,3 +360,9 @@ pub(crate) fn search_home_manager_options(
         HOME_MANAGER_RESULTS_LIMIT,
     ))
 }
+
+/// Writes the Home Manager options page to `index`.
+pub(crate) fn index_home_manager_options(index: &IndexWriter) -> Result<(), Error> {
+    let body = fetch_home_manager_options()?;
+    index.write("home_manager", HOME_MANAGER_OPTIONS_URL, &body)
+}
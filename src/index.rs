@@ -0,0 +1,88 @@
+use std::io::Error;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::cache::write_entry;
+use crate::home_manager::index_home_manager_options;
+use crate::nix_manual::index_nix_manual;
+use crate::nixpkgs::index_nixpkgs_options;
+use crate::nvf::{index_nvf_manual, index_nvf_options};
+use crate::packages::index_packages;
+
+/// Writes entries into an index directory. An index uses the same layout as
+/// the cache, so it can be copied to other machines and read without rime
+/// having to know how it was built.
+pub(crate) struct IndexWriter {
+    root: PathBuf,
+}
+
+impl IndexWriter {
+    pub(crate) fn new(root: &Path) -> IndexWriter {
+        IndexWriter {
+            root: root.to_path_buf(),
+        }
+    }
+
+    pub(crate) fn write(&self, namespace: &str, key: &str, body: &str) -> Result<(), Error> {
+        write_entry(&self.root, namespace, key, body)
+    }
+}
+
+/// What `rime index build` should fetch and index.
+pub(crate) struct IndexSpec {
+    pub(crate) nixpkgs_refs: Vec<String>,
+    pub(crate) nvf_refs: Vec<String>,
+    pub(crate) installables: Vec<String>,
+}
+
+/// Fetches and indexes every source described by `spec` into `out`. Progress
+/// is reported on stderr; the first failure aborts the build.
+pub(crate) fn build_index(out: &Path, spec: &IndexSpec) -> Result<(), Error> {
+    let index = IndexWriter::new(out);
+
+    for ref_name in &spec.nixpkgs_refs {
+        eprintln!("indexing NixOS options for nixpkgs ref '{ref_name}'...");
+        let count = index_nixpkgs_options(&index, ref_name)?;
+        eprintln!("  {count} options");
+    }
+
+    eprintln!("indexing Home Manager options...");
+    index_home_manager_options(&index)?;
+
+    for ref_name in &spec.nvf_refs {
+        eprintln!("indexing nvf options for ref '{ref_name}'...");
+        let count = index_nvf_options(&index, ref_name)?;
+        eprintln!("  {count} options");
+
+        eprintln!("indexing nvf manual for ref '{ref_name}'...");
+        let count = index_nvf_manual(&index, ref_name)?;
+        eprintln!("  {count} files");
+    }
+
+    eprintln!("indexing Nix manual...");
+    let count = index_nix_manual(&index)?;
+    eprintln!("  {count} files");
+
+    for installable in &spec.installables {
+        eprintln!("indexing package metadata for '{installable}'...");
+        let count = index_packages(&index, installable)?;
+        eprintln!("  {count} packages");
+    }
+
+    let created = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let manifest = serde_json::json!({
+        "rime_version": env!("CARGO_PKG_VERSION"),
+        "created": created,
+        "nixpkgs_refs": spec.nixpkgs_refs,
+        "nvf_refs": spec.nvf_refs,
+        "installables": spec.installables,
+    });
+    let manifest = serde_json::to_string_pretty(&manifest).map_err(Error::other)?;
+    std::fs::write(out.join("manifest.json"), manifest)?;
+
+    eprintln!("index written to {}", out.display());
+    Ok(())
+}
@@ -0,0 +1,195 @@
+use std::io::Error;
+use std::sync::{Condvar, Mutex, OnceLock};
+
+use crate::config;
+use crate::progress;
+
+static NIX: OnceLock<Limiter> = OnceLock::new();
+static HTTP: OnceLock<Limiter> = OnceLock::new();
+
+/// Returns the limiter guarding nix subprocesses.
+pub(crate) fn nix() -> &'static Limiter {
+    NIX.get_or_init(|| {
+        let config = config::get();
+        Limiter::new(
+            "nix subprocess",
+            config.max_nix_processes(),
+            config.max_queue_length(),
+        )
+    })
+}
+
+/// Returns the limiter guarding outbound HTTP requests.
+pub(crate) fn http() -> &'static Limiter {
+    HTTP.get_or_init(|| {
+        let config = config::get();
+        Limiter::new(
+            "HTTP request",
+            config.max_http_requests(),
+            config.max_queue_length(),
+        )
+    })
+}
+
+/// A FIFO counting semaphore with a bounded wait queue.
+///
+/// Blocked callers report their queue position through [`progress::report`],
+/// and callers arriving at a full queue are rejected with an overload error
+/// rather than piling up.
+pub(crate) struct Limiter {
+    what: &'static str,
+    max_active: usize,
+    max_queued: usize,
+    state: Mutex<State>,
+    released: Condvar,
+}
+
+struct State {
+    active: usize,
+    next_ticket: u64,
+    serving: u64,
+}
+
+pub(crate) struct Permit<'a> {
+    limiter: &'a Limiter,
+}
+
+impl Limiter {
+    fn new(what: &'static str, max_active: usize, max_queued: usize) -> Limiter {
+        Limiter {
+            what,
+            max_active: max_active.max(1),
+            max_queued,
+            state: Mutex::new(State {
+                active: 0,
+                next_ticket: 0,
+                serving: 0,
+            }),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Blocks until a slot is free, in arrival order.
+    pub(crate) fn acquire(&self) -> Result<Permit<'_>, Error> {
+        let mut state = self.state.lock().map_err(|_| Error::other("limiter poisoned"))?;
+
+        let queued = (state.next_ticket - state.serving) as usize;
+        let must_wait = queued > 0 || state.active >= self.max_active;
+        if must_wait && queued >= self.max_queued {
+            return Err(Error::other(format!(
+                "rime is overloaded: {} callers are already queued for a {} slot (limit {}); retry later",
+                queued, self.what, self.max_queued
+            )));
+        }
+
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+
+        let mut reported = None;
+        while ticket != state.serving || state.active >= self.max_active {
+            let position = ticket - state.serving + 1;
+            if reported != Some(position) {
+                reported = Some(position);
+                progress::report(format!(
+                    "queued for a {} slot (position {} of {})",
+                    self.what,
+                    position,
+                    state.next_ticket - state.serving
+                ));
+            }
+            state = self
+                .released
+                .wait(state)
+                .map_err(|_| Error::other("limiter poisoned"))?;
+        }
+
+        state.serving += 1;
+        state.active += 1;
+        // Wake the next waiter so that it can report its new position.
+        self.released.notify_all();
+        Ok(Permit { limiter: self })
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.limiter.state.lock() {
+            state.active -= 1;
+        }
+        self.limiter.released.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    /// Waits until `count` tickets have been handed out by `limiter`.
+    fn wait_for_tickets(limiter: &Limiter, count: u64) {
+        while limiter.state.lock().unwrap().next_ticket < count {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn allows_max_active_permits() {
+        let limiter = Limiter::new("test", 2, 0);
+        let first = limiter.acquire().unwrap();
+        let _second = limiter.acquire().unwrap();
+        assert!(limiter.acquire().is_err());
+
+        drop(first);
+        assert!(limiter.acquire().is_ok());
+    }
+
+    #[test]
+    fn rejects_callers_when_queue_is_full() {
+        let limiter = Arc::new(Limiter::new("test", 1, 1));
+        let permit = limiter.acquire().unwrap();
+
+        let waiter = {
+            let limiter = Arc::clone(&limiter);
+            thread::spawn(move || limiter.acquire().map(|_| ()).is_ok())
+        };
+        wait_for_tickets(&limiter, 2);
+
+        let error = limiter.acquire().err().expect("queue is full");
+        assert!(error.to_string().contains("overloaded"), "{}", error);
+
+        drop(permit);
+        assert!(waiter.join().unwrap());
+    }
+
+    #[test]
+    fn serves_waiters_in_arrival_order() {
+        let limiter = Arc::new(Limiter::new("test", 1, 10));
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let permit = limiter.acquire().unwrap();
+
+        let waiters: Vec<_> = (0..5)
+            .map(|id| {
+                let waiter = {
+                    let limiter = Arc::clone(&limiter);
+                    let order = Arc::clone(&order);
+                    thread::spawn(move || {
+                        let _permit = limiter.acquire().unwrap();
+                        order.lock().unwrap().push(id);
+                    })
+                };
+                // Queue the waiters one after another.
+                wait_for_tickets(&limiter, id + 2);
+                waiter
+            })
+            .collect();
+
+        drop(permit);
+        for waiter in waiters {
+            waiter.join().unwrap();
+        }
+        assert_eq!(*order.lock().unwrap(), vec![0, 1, 2, 3, 4]);
+    }
+}
@@ -0,0 +1,146 @@
+use std::io::Error;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use regex::{Regex, RegexBuilder};
+
+use crate::nix;
+use crate::nix_error::nix_failure;
+
+const DEFAULT_LOG_DIR: &str = "/nix/var/log/nix";
+const DEFAULT_STORE_DIR: &str = "/nix/store";
+
+/// How many of the most recent build logs are considered when looking for
+/// the latest failed build.
+const MAX_FAILED_CANDIDATES: usize = 50;
+
+/// Returns the build log of `target`, which may be an installable, a store
+/// path or a `.drv` path.
+pub(crate) fn read_log(target: &str) -> Result<String, Error> {
+    // Run: nix log <target>
+    let output = nix::command().args(["log", target]).output()?;
+
+    if !output.status.success() {
+        return Err(nix_failure("nix log", &output));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Returns the derivation of the most recent build whose log is in the local
+/// log directory but whose outputs are not valid, i.e. the latest build that
+/// failed. Returns `None` if no such build is found.
+pub(crate) fn latest_failed_derivation() -> Result<Option<String>, Error> {
+    // Look where nix itself keeps logs and derivations, which depends on its
+    // environment and working directory rather than rime's.
+    let log_dir = nix::env_var("NIX_LOG_DIR")
+        .map(nix::resolve_path)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_LOG_DIR))
+        .join("drvs");
+    let store_dir = nix::env_var("NIX_STORE_DIR")
+        .map(nix::resolve_path)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_STORE_DIR));
+
+    let mut logs = build_logs(&log_dir).map_err(|e| {
+        Error::other(format!(
+            "failed to read build logs in {}: {}",
+            log_dir.display(),
+            e
+        ))
+    })?;
+    logs.sort_by_key(|(modified, _)| std::cmp::Reverse(*modified));
+
+    for (_, drv_name) in logs.into_iter().take(MAX_FAILED_CANDIDATES) {
+        let drv_path = store_dir.join(&drv_name);
+        // Derivations that were garbage collected cannot be inspected.
+        if !drv_path.exists() {
+            continue;
+        }
+        let drv_path = drv_path.to_string_lossy().to_string();
+        if !outputs_valid(&drv_path)? {
+            return Ok(Some(drv_path));
+        }
+    }
+    Ok(None)
+}
+
+/// Lists the build logs under `log_dir` with their modification time and the
+/// file name of the derivation they belong to. Logs are stored as
+/// `<log_dir>/<first two characters>/<rest>.drv[.bz2]`.
+fn build_logs(log_dir: &Path) -> Result<Vec<(SystemTime, String)>, Error> {
+    let mut logs = Vec::new();
+    for prefix in std::fs::read_dir(log_dir)? {
+        let prefix = prefix?;
+        if !prefix.file_type()?.is_dir() {
+            continue;
+        }
+        let prefix_name = prefix.file_name().to_string_lossy().to_string();
+        for entry in std::fs::read_dir(prefix.path())? {
+            let entry = entry?;
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            let rest = file_name.strip_suffix(".bz2").unwrap_or(&file_name);
+            if !rest.ends_with(".drv") {
+                continue;
+            }
+            let modified = entry.metadata()?.modified()?;
+            logs.push((modified, format!("{}{}", prefix_name, rest)));
+        }
+    }
+    Ok(logs)
+}
+
+fn outputs_valid(drv_path: &str) -> Result<bool, Error> {
+    // Run: nix path-info <drv>^*
+    let output = nix::command()
+        .args(["path-info", &format!("{}^*", drv_path)])
+        .output()?;
+    Ok(output.status.success())
+}
+
+/// Compiles `regex` for [`filter_log`], matching case-insensitively.
+pub(crate) fn grep_pattern(regex: &str) -> Result<Regex, Error> {
+    RegexBuilder::new(regex)
+        .case_insensitive(true)
+        .build()
+        .map_err(|e| Error::other(format!("invalid regex '{}': {}", regex, e)))
+}
+
+/// Narrows `log` down to the lines matching `grep` (with `context` lines
+/// around each match, groups separated by `--` as grep does), then to the last
+/// `tail` lines.
+pub(crate) fn filter_log(
+    log: &str,
+    grep: Option<&Regex>,
+    context: usize,
+    tail: Option<usize>,
+) -> String {
+    let lines: Vec<&str> = log.lines().collect();
+
+    let mut selected: Vec<&str> = match grep {
+        Some(pattern) => {
+            let mut selected = Vec::new();
+            let mut printed_until = 0;
+            for (i, line) in lines.iter().enumerate() {
+                if !pattern.is_match(line) {
+                    continue;
+                }
+                let start = i.saturating_sub(context).max(printed_until);
+                let end = (i + context + 1).min(lines.len());
+                if start > printed_until && !selected.is_empty() {
+                    selected.push("--");
+                }
+                selected.extend(&lines[start..end]);
+                printed_until = end;
+            }
+            selected
+        }
+        None => lines,
+    };
+
+    if let Some(tail) = tail {
+        let skip = selected.len().saturating_sub(tail);
+        selected.drain(..skip);
+    }
+
+    selected.join("\n")
+}
@@ -1,11 +1,45 @@
+mod binary_cache;
+mod build;
+mod cache;
+mod capabilities;
+mod closure;
+mod config;
+mod derivation;
+mod eval;
+mod flake_check;
+mod flake_lock;
+mod flake_show;
+mod flake_update;
 mod handler;
+mod hash;
+mod hash_mismatch;
 mod home_manager;
+mod index;
+mod limits;
+mod log;
+mod nix;
+mod nix_error;
+mod nix_manual;
 mod nixpkgs;
 mod nvf;
+mod packages;
+mod path_info;
+mod prefetch;
+mod progress;
+mod registry;
+mod repl;
+mod store_files;
+mod store_path;
 mod tools;
 
+use std::path::PathBuf;
+use std::sync::Arc;
+
 use clap::{Parser, Subcommand};
+use config::{Config, Profile};
 use handler::RimeServerHandler;
+use index::IndexSpec;
+use nix::SystemNix;
 use rust_mcp_sdk::error::SdkResult;
 use rust_mcp_sdk::mcp_server::{
     HyperServerOptions, McpServerOptions, hyper_server, server_runtime,
@@ -25,6 +59,22 @@ use rust_mcp_sdk::{McpServer, StdioTransport, ToMcpServerHandler, TransportOptio
     arg_required_else_help = true
 )]
 struct Cli {
+    /// Path to a JSON configuration file (default: $XDG_CONFIG_HOME/rime/config.json)
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Serve exclusively from local caches and pass --offline to nix
+    #[arg(long, global = true)]
+    offline: bool,
+
+    /// Directory holding a prebuilt index (default: $XDG_DATA_HOME/rime/index)
+    #[arg(long, global = true)]
+    index_dir: Option<PathBuf>,
+
+    /// What tools may change on this machine (default: read-only)
+    #[arg(long, global = true, value_enum)]
+    profile: Option<Profile>,
+
     #[command(subcommand)]
     command: Command,
 }
@@ -35,6 +85,34 @@ enum Command {
     Stdio,
     /// Run the MCP server over HTTP
     Http(HttpArgs),
+    /// Manage the local documentation and options index
+    #[command(subcommand)]
+    Index(IndexCommand),
+}
+
+#[derive(Subcommand, Debug)]
+enum IndexCommand {
+    /// Fetch and index docs, options and package metadata ahead of time
+    Build(IndexBuildArgs),
+}
+
+#[derive(Parser, Debug, Clone)]
+struct IndexBuildArgs {
+    /// Directory to write the index to (default: the configured index directory)
+    #[arg(long)]
+    out: Option<PathBuf>,
+
+    /// nixpkgs refs to index NixOS options for (repeatable)
+    #[arg(long = "nixpkgs-ref", default_values_t = [String::from("nixos-unstable")])]
+    nixpkgs_refs: Vec<String>,
+
+    /// nvf refs to index options and manual files for (repeatable)
+    #[arg(long = "nvf-ref", default_values_t = [String::from("main")])]
+    nvf_refs: Vec<String>,
+
+    /// Installables to index package metadata for (repeatable)
+    #[arg(long = "installable", default_values_t = [String::from("nixpkgs")])]
+    installables: Vec<String>,
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -71,8 +149,14 @@ fn server_details() -> InitializeResult {
             ..Default::default()
         },
         meta: None,
-        instructions: Some(
-            "Rime provides MCP tools for Nix/NixOS workflows.\n\
+        instructions: Some(instructions()),
+        protocol_version: "2025-11-25".to_string(),
+    }
+}
+
+fn instructions() -> String {
+    let mut instructions = String::from(
+        "Rime provides MCP tools for Nix/NixOS workflows.\n\
 Use nix_evaluate, nix_log, nix_packages_search, and nix_packages_why_depends for local nix.\n\
 Use nix_manual_* and nixos_wiki_* for documentation lookups.\n\
 Use nixpkgs_options_search to search for Nixpkgs options in nixpkgs for a specific ref.\n\
@@ -80,23 +164,70 @@ Use home_manager_options_search to query Home Manager options.\n\
 Use nvf_options_search to search for nvf (Neovim Flake) options.\n\
 Use nvf_manual_* for nvf documentation lookups.\n\
 Note: When creating inline Lua functions in nvf, use lib.generators.mkLuaInline.\n\
-Most tools shell out to nix; ensure it is on PATH."
-                .to_string(),
-        ),
-        protocol_version: "2025-11-25".to_string(),
+Most tools shell out to nix; ensure it is on PATH.\n\
+When rime runs offline, tools answer from local caches and fail fast if no cached answer exists.",
+    );
+    instructions.push_str(&format!(
+        "\nThe tool-safety profile is {}: {}.",
+        config::get().profile,
+        match config::get().profile {
+            Profile::ReadOnly => "tools only inspect, and nix_build is limited to dry runs",
+            Profile::Build => "tools may build and substitute store paths",
+            Profile::Write => "tools may build and change nix configuration",
+        }
+    ));
+    if let Some(capabilities) = capabilities::get() {
+        instructions.push('\n');
+        instructions.push_str(&capabilities.summary());
     }
+    instructions
 }
 
 #[tokio::main]
 async fn main() -> SdkResult<()> {
     let cli = Cli::parse();
+
+    let mut config = Config::load(cli.config.as_deref())?;
+    config.offline |= cli.offline;
+    if cli.index_dir.is_some() {
+        config.index_dir = cli.index_dir;
+    }
+    if let Some(profile) = cli.profile {
+        config.profile = profile;
+    }
+    config::init(config);
+
+    let config = config::get();
+    nix::install(Arc::new(SystemNix::new(config.nix.clone(), config.offline)));
+
     match cli.command {
         Command::Stdio => run_stdio().await,
         Command::Http(args) => run_http(args).await,
+        Command::Index(IndexCommand::Build(args)) => run_index_build(args),
     }
 }
 
+fn run_index_build(args: IndexBuildArgs) -> SdkResult<()> {
+    let config = config::get();
+    if config.offline {
+        return Err(std::io::Error::other("rime index build needs network access; drop --offline").into());
+    }
+
+    let Some(out) = args.out.or_else(|| config.index_dir()) else {
+        return Err(std::io::Error::other("no index directory configured; pass --out").into());
+    };
+
+    let spec = IndexSpec {
+        nixpkgs_refs: args.nixpkgs_refs,
+        nvf_refs: args.nvf_refs,
+        installables: args.installables,
+    };
+    index::build_index(&out, &spec)?;
+    Ok(())
+}
+
 async fn run_stdio() -> SdkResult<()> {
+    tokio::task::spawn_blocking(capabilities::detect).await?;
     let transport = StdioTransport::new(TransportOptions::default())?;
     let handler = RimeServerHandler {};
     let server = server_runtime::create_server(McpServerOptions {
@@ -110,6 +241,7 @@ async fn run_stdio() -> SdkResult<()> {
 }
 
 async fn run_http(args: HttpArgs) -> SdkResult<()> {
+    tokio::task::spawn_blocking(capabilities::detect).await?;
     let handler = RimeServerHandler {};
     let server = hyper_server::create_server(
         server_details(),
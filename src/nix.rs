@@ -0,0 +1,419 @@
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::io::{Error, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::{Arc, RwLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::config;
+use crate::limits;
+
+static RUNNER: RwLock<Option<Arc<dyn NixRunner>>> = RwLock::new(None);
+
+/// Subcommands that take a second word, e.g. `nix flake show`.
+const COMMAND_GROUPS: [&str; 6] = ["config", "derivation", "flake", "profile", "registry", "store"];
+
+/// Subcommands that evaluate Nix code and therefore accept `--eval-store`.
+const EVAL_COMMANDS: [&str; 12] = [
+    "build",
+    "derivation",
+    "develop",
+    "eval",
+    "flake",
+    "log",
+    "path-info",
+    "repl",
+    "run",
+    "search",
+    "shell",
+    "why-depends",
+];
+
+/// How rime invokes nix, configured under the `nix` key of the config file.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct NixSettings {
+    /// The nix binary to run (default: `nix` from PATH).
+    pub(crate) binary: Option<PathBuf>,
+
+    /// Arguments passed before the subcommand of every invocation, e.g.
+    /// `["--option", "substituters", "https://cache.example.org"]`.
+    pub(crate) args: Vec<String>,
+
+    /// Extra environment variables, e.g. `NIX_CONFIG`.
+    pub(crate) env: BTreeMap<String, String>,
+
+    /// Value of `NIX_PATH` for every invocation.
+    pub(crate) nix_path: Option<String>,
+
+    /// Store URL passed as `--store`.
+    pub(crate) store: Option<String>,
+
+    /// Store URL passed as `--eval-store` to subcommands that evaluate.
+    pub(crate) eval_store: Option<String>,
+
+    /// Working directory of every invocation (default: rime's own).
+    pub(crate) working_dir: Option<PathBuf>,
+}
+
+/// Runs nix subprocesses. Every tool goes through the installed runner, so
+/// tests can replace it with a fake via [`install`].
+pub(crate) trait NixRunner: Send + Sync {
+    /// Runs nix with `args` (starting at the subcommand) and collects its
+    /// output, killing it if it has not exited after `timeout`.
+    fn output(&self, args: &[OsString], timeout: Option<Duration>) -> Result<Output, Error>;
+
+    /// Starts a long-lived nix process with `args` and piped stdio.
+    fn spawn(&self, args: &[OsString]) -> Result<Child, Error>;
+}
+
+/// Runs the real nix binary as configured by [`NixSettings`].
+pub(crate) struct SystemNix {
+    settings: NixSettings,
+    offline: bool,
+}
+
+/// Replaces the runner used by every subsequent nix invocation.
+pub(crate) fn install(runner: Arc<dyn NixRunner>) {
+    if let Ok(mut current) = RUNNER.write() {
+        *current = Some(runner);
+    }
+}
+
+fn runner() -> Arc<dyn NixRunner> {
+    let installed = RUNNER.read().ok().and_then(|current| current.clone());
+    installed.unwrap_or_else(|| {
+        let config = config::get();
+        Arc::new(SystemNix::new(config.nix.clone(), config.offline))
+    })
+}
+
+impl SystemNix {
+    pub(crate) fn new(settings: NixSettings, offline: bool) -> SystemNix {
+        SystemNix { settings, offline }
+    }
+
+    /// Returns the `nix` command for `args`, with the flags every invocation
+    /// needs: the experimental features rime relies on, `--offline` when
+    /// running offline, and everything configured in [`NixSettings`].
+    fn command(&self, args: &[OsString]) -> Command {
+        let settings = &self.settings;
+        let mut command = base_command(settings);
+        command.args(["--extra-experimental-features", "nix-command flakes"]);
+        if self.offline {
+            command.arg("--offline");
+        }
+        if let Some(store) = &settings.store {
+            command.args(["--store", store]);
+        }
+        command.args(&settings.args);
+
+        let subcommand_len = match args.first().and_then(|arg| arg.to_str()) {
+            Some(group) if COMMAND_GROUPS.contains(&group) => 2,
+            _ => 1,
+        }
+        .min(args.len());
+        command.args(&args[..subcommand_len]);
+        if let Some(eval_store) = &settings.eval_store
+            && args
+                .first()
+                .and_then(|arg| arg.to_str())
+                .is_some_and(|subcommand| EVAL_COMMANDS.contains(&subcommand))
+        {
+            command.args(["--eval-store", eval_store]);
+        }
+        command.args(&args[subcommand_len..]);
+        command
+    }
+}
+
+/// Returns the configured nix binary with its environment and working
+/// directory, but none of the flags rime adds to its invocations.
+fn base_command(settings: &NixSettings) -> Command {
+    let mut command = Command::new(settings.binary.as_deref().unwrap_or("nix".as_ref()));
+    command.envs(&settings.env);
+    if let Some(nix_path) = &settings.nix_path {
+        command.env("NIX_PATH", nix_path);
+    }
+    if let Some(dir) = &settings.working_dir {
+        command.current_dir(dir);
+    }
+    command
+}
+
+/// Returns the environment variable `name` as nix subprocesses see it:
+/// configured in [`NixSettings::env`], or else inherited from rime.
+pub(crate) fn env_var(name: &str) -> Option<OsString> {
+    match config::get().nix.env.get(name) {
+        Some(value) => Some(OsString::from(value)),
+        None => std::env::var_os(name),
+    }
+}
+
+/// Resolves `path` the way nix subprocesses see it: relative paths are taken
+/// from the configured working directory.
+pub(crate) fn resolve_path(path: impl AsRef<Path>) -> PathBuf {
+    match &config::get().nix.working_dir {
+        Some(dir) if path.as_ref().is_relative() => dir.join(path),
+        _ => path.as_ref().to_path_buf(),
+    }
+}
+
+/// Returns the configured nix binary without the experimental features and
+/// other flags rime passes, to probe how nix behaves on its own.
+pub(crate) fn bare_command() -> Command {
+    base_command(&config::get().nix)
+}
+
+impl NixRunner for SystemNix {
+    fn output(&self, args: &[OsString], timeout: Option<Duration>) -> Result<Output, Error> {
+        let mut command = self.command(args);
+        match timeout {
+            Some(timeout) => run_with_timeout(&mut command, timeout),
+            None => command.output(),
+        }
+    }
+
+    fn spawn(&self, args: &[OsString]) -> Result<Child, Error> {
+        self.command(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+    }
+}
+
+/// Runs `command` and collects its output, killing it if it has not exited
+/// after `timeout`.
+pub(crate) fn run_with_timeout(command: &mut Command, timeout: Duration) -> Result<Output, Error> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Drain both pipes while waiting, so that a child writing more than a
+    // pipe buffer does not block until it is killed.
+    let stdout = read_in_background(child.stdout.take());
+    let stderr = read_in_background(child.stderr.take());
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("nix did not finish within {}s", timeout.as_secs()),
+            ));
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    };
+
+    Ok(Output {
+        status,
+        stdout: join_reader(stdout)?,
+        stderr: join_reader(stderr)?,
+    })
+}
+
+fn read_in_background<R: Read + Send + 'static>(pipe: Option<R>) -> JoinHandle<Result<Vec<u8>, Error>> {
+    std::thread::spawn(move || {
+        let mut bytes = Vec::new();
+        if let Some(mut pipe) = pipe {
+            pipe.read_to_end(&mut bytes)?;
+        }
+        Ok(bytes)
+    })
+}
+
+fn join_reader(reader: JoinHandle<Result<Vec<u8>, Error>>) -> Result<Vec<u8>, Error> {
+    reader
+        .join()
+        .unwrap_or_else(|_| Err(Error::other("reading nix output panicked")))
+}
+
+/// A nix invocation whose execution is subject to the nix subprocess limit.
+pub(crate) struct NixCommand {
+    args: Vec<OsString>,
+}
+
+/// Returns a new nix invocation. Arguments start at the subcommand; global
+/// flags are added by the installed [`NixRunner`].
+pub(crate) fn command() -> NixCommand {
+    NixCommand { args: Vec::new() }
+}
+
+impl NixCommand {
+    pub(crate) fn args<I, S>(&mut self, args: I) -> &mut NixCommand
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.args
+            .extend(args.into_iter().map(|arg| arg.as_ref().to_os_string()));
+        self
+    }
+
+    /// Runs the command once a nix subprocess slot is free and collects its
+    /// output.
+    pub(crate) fn output(&mut self) -> Result<Output, Error> {
+        let _permit = limits::nix().acquire()?;
+        runner().output(&self.args, None)
+    }
+
+    /// Like [`NixCommand::output`], but kills the subprocess if it has not
+    /// exited after `timeout`.
+    pub(crate) fn output_timeout(&mut self, timeout: Duration) -> Result<Output, Error> {
+        let _permit = limits::nix().acquire()?;
+        runner().output(&self.args, Some(timeout))
+    }
+
+    /// Starts the command as a long-lived process with piped stdio. Unlike
+    /// the output methods this does not take a nix subprocess slot; callers
+    /// hold one while the process is busy instead.
+    pub(crate) fn spawn(&mut self) -> Result<Child, Error> {
+        runner().spawn(&self.args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use super::*;
+
+    fn args(command: &Command) -> Vec<&str> {
+        command.get_args().filter_map(|arg| arg.to_str()).collect()
+    }
+
+    fn os_args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn adds_experimental_features_before_the_subcommand() {
+        let nix = SystemNix::new(NixSettings::default(), false);
+        let command = nix.command(&os_args(&["eval", "--json", "--expr", "1"]));
+        assert_eq!(command.get_program(), "nix");
+        assert_eq!(
+            args(&command),
+            [
+                "--extra-experimental-features",
+                "nix-command flakes",
+                "eval",
+                "--json",
+                "--expr",
+                "1"
+            ]
+        );
+    }
+
+    #[test]
+    fn places_store_flags_around_the_subcommand() {
+        let settings = NixSettings {
+            args: vec![
+                "--option".into(),
+                "substituters".into(),
+                "file:///cache".into(),
+            ],
+            store: Some("ssh-ng://builder".into()),
+            eval_store: Some("auto".into()),
+            ..NixSettings::default()
+        };
+        let nix = SystemNix::new(settings, true);
+
+        // Global flags come first; `--eval-store` follows the subcommand, here
+        // a command group with two words.
+        let command = nix.command(&os_args(&["flake", "show", "--json", "."]));
+        assert_eq!(
+            args(&command),
+            [
+                "--extra-experimental-features",
+                "nix-command flakes",
+                "--offline",
+                "--store",
+                "ssh-ng://builder",
+                "--option",
+                "substituters",
+                "file:///cache",
+                "flake",
+                "show",
+                "--eval-store",
+                "auto",
+                "--json",
+                ".",
+            ]
+        );
+
+        // Subcommands that do not evaluate do not accept `--eval-store`.
+        let command = nix.command(&os_args(&["store", "ping"]));
+        assert!(!args(&command).contains(&"--eval-store"));
+        assert!(args(&command).ends_with(&["store", "ping"]));
+    }
+
+    #[test]
+    fn applies_binary_environment_and_working_directory() {
+        let settings = NixSettings {
+            binary: Some("/opt/nix/bin/nix".into()),
+            env: BTreeMap::from([("NIX_CONFIG".to_string(), "sandbox = false".to_string())]),
+            nix_path: Some("nixpkgs=/src/nixpkgs".into()),
+            working_dir: Some("/src/project".into()),
+            ..NixSettings::default()
+        };
+        let command = base_command(&settings);
+        assert_eq!(command.get_program(), "/opt/nix/bin/nix");
+        assert_eq!(command.get_current_dir(), Some(Path::new("/src/project")));
+        let envs: BTreeMap<&OsStr, Option<&OsStr>> = command.get_envs().collect();
+        assert_eq!(
+            envs[OsStr::new("NIX_CONFIG")],
+            Some(OsStr::new("sandbox = false"))
+        );
+        assert_eq!(
+            envs[OsStr::new("NIX_PATH")],
+            Some(OsStr::new("nixpkgs=/src/nixpkgs"))
+        );
+        assert_eq!(args(&command), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn run_with_timeout_kills_hanging_processes() {
+        let started = Instant::now();
+        let mut command = Command::new("sleep");
+        command.arg("10");
+        let error = run_with_timeout(&mut command, Duration::from_millis(100)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn run_with_timeout_collects_output() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo out; echo err >&2; exit 3"]);
+        let output = run_with_timeout(&mut command, Duration::from_secs(10)).unwrap();
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(output.stdout, b"out\n");
+        assert_eq!(output.stderr, b"err\n");
+    }
+
+    #[test]
+    fn run_with_timeout_drains_large_output() {
+        // Far more than a pipe buffer holds.
+        let mut command = Command::new("sh");
+        command.args([
+            "-c",
+            "head -c 1048576 /dev/zero; head -c 262144 /dev/zero >&2",
+        ]);
+        let output = run_with_timeout(&mut command, Duration::from_secs(10)).unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout.len(), 1048576);
+        assert_eq!(output.stderr.len(), 262144);
+    }
+}
@@ -0,0 +1,420 @@
+use std::io::Error;
+use std::process::Output;
+
+use serde::Serialize;
+
+/// Lines of source shown before and after the error location.
+const SNIPPET_CONTEXT: usize = 2;
+
+/// At most this many trailing lines of stderr are kept verbatim.
+const MAX_STDERR_LINES: usize = 40;
+
+/// A failed nix invocation, parsed from the error nix (or Lix) printed.
+#[derive(Debug, Serialize)]
+pub(crate) struct NixError {
+    /// The nix command that failed, e.g. `nix eval`.
+    pub(crate) command: String,
+    pub(crate) status: Option<i32>,
+    pub(crate) kind: ErrorKind,
+    /// The innermost error message.
+    pub(crate) message: String,
+    /// Where the innermost error occurred.
+    pub(crate) position: Option<Position>,
+    /// The innermost attribute being evaluated when the error occurred.
+    pub(crate) attr_path: Option<String>,
+    /// The evaluation stack from outermost to innermost frame. Complete only
+    /// with `--show-trace`.
+    pub(crate) trace: Vec<Frame>,
+    /// Source lines around `position`.
+    pub(crate) snippet: Option<String>,
+    /// The last lines of stderr, verbatim.
+    pub(crate) stderr: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ErrorKind {
+    InfiniteRecursion,
+    AttributeMissing,
+    UndefinedVariable,
+    TypeError,
+    Assertion,
+    HashMismatch,
+    Unfree,
+    Insecure,
+    Broken,
+    UnsupportedSystem,
+    ImportFromDerivation,
+    SyntaxError,
+    BuildFailure,
+    Other,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Position {
+    pub(crate) file: String,
+    pub(crate) line: usize,
+    pub(crate) column: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Frame {
+    /// What nix was doing, e.g. `while evaluating the attribute 'foo'`.
+    pub(crate) description: String,
+    pub(crate) position: Option<Position>,
+}
+
+/// Returns the error for a nix invocation that exited unsuccessfully. Its
+/// message is the parsed [`NixError`] as JSON.
+pub(crate) fn nix_failure(command: &str, output: &Output) -> Error {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let error = parse(command, output.status.code(), &stderr);
+    match serde_json::to_string_pretty(&error) {
+        Ok(json) => Error::other(json),
+        Err(_) => Error::other(format!("{} (status: {}): {}", command, output.status, stderr)),
+    }
+}
+
+/// Parses the error output of a nix invocation.
+pub(crate) fn parse(command: &str, status: Option<i32>, stderr: &str) -> NixError {
+    let lines: Vec<&str> = stderr.lines().collect();
+
+    // Only the first top-level error is parsed; later ones (e.g. "1
+    // dependencies of derivation ... failed to build") are consequences.
+    let start = lines
+        .iter()
+        .position(|line| line.starts_with("error:"))
+        .unwrap_or(0);
+    let end = lines
+        .iter()
+        .skip(start + 1)
+        .position(|line| line.starts_with("error:"))
+        .map_or(lines.len(), |i| start + 1 + i);
+    let block = lines.get(start..end).unwrap_or_default();
+
+    let mut trace = Vec::new();
+    let mut message = None;
+    let mut position = None;
+    let mut snippet_lines = Vec::new();
+    for (i, line) in block.iter().enumerate() {
+        let trimmed = line.trim();
+        if let Some(description) = trimmed.strip_prefix('…') {
+            let position = block
+                .get(i + 1)
+                .and_then(|next| parse_position(next.trim()));
+            trace.push(Frame {
+                description: description.trim().to_string(),
+                position,
+            });
+        } else if let Some(text) = trimmed.strip_prefix("error:") {
+            let mut text = text.trim().to_string();
+            // The message may continue on the following lines.
+            for next in &block[i + 1..] {
+                let next = next.trim();
+                if next.is_empty() || next.starts_with("at ") || next.starts_with('…') {
+                    break;
+                }
+                text.push('\n');
+                text.push_str(next);
+            }
+            if !text.is_empty() {
+                message = Some(text);
+                position = None;
+                snippet_lines.clear();
+            }
+        } else if message.is_some() && position.is_none() {
+            position = parse_position(trimmed);
+        } else if position.is_some() && is_snippet_line(trimmed) {
+            snippet_lines.push(line.trim_start_matches(' ').to_string());
+        }
+    }
+
+    let message = message.unwrap_or_else(|| {
+        block
+            .iter()
+            .map(|line| line.trim())
+            .find(|line| !line.is_empty())
+            .unwrap_or("nix failed without an error message")
+            .to_string()
+    });
+    let kind = classify(&message);
+    let attr_path = trace
+        .iter()
+        .rev()
+        .find_map(|frame| quoted_after(&frame.description, "while evaluating the attribute"))
+        .or_else(|| quoted_after(&message, "does not provide attribute"));
+    let snippet = position
+        .as_ref()
+        .and_then(source_snippet)
+        .or_else(|| (!snippet_lines.is_empty()).then(|| snippet_lines.join("\n")));
+
+    let skip = lines.len().saturating_sub(MAX_STDERR_LINES);
+    NixError {
+        command: command.to_string(),
+        status,
+        kind,
+        message,
+        position,
+        attr_path,
+        trace,
+        snippet,
+        stderr: lines[skip..].join("\n"),
+    }
+}
+
+fn classify(message: &str) -> ErrorKind {
+    let message = message.to_lowercase();
+    let has = |needle: &str| message.contains(needle);
+
+    if has("infinite recursion") {
+        ErrorKind::InfiniteRecursion
+    } else if has("hash mismatch") {
+        ErrorKind::HashMismatch
+    } else if has("has an unfree license") {
+        ErrorKind::Unfree
+    } else if has("is marked as insecure") {
+        ErrorKind::Insecure
+    } else if has("is marked as broken") {
+        ErrorKind::Broken
+    } else if has("is not available on the requested hostplatform")
+        || has("is not supported on")
+        || (has("a '") && has("' with features") && has("is required to build"))
+    {
+        ErrorKind::UnsupportedSystem
+    } else if has("allow-import-from-derivation") || has("during evaluation because") {
+        ErrorKind::ImportFromDerivation
+    } else if has("assertion") && has("failed") {
+        ErrorKind::Assertion
+    } else if has("undefined variable") {
+        ErrorKind::UndefinedVariable
+    } else if (has("attribute '") && has("' missing"))
+        || has("does not provide attribute")
+        || has("has no attribute")
+    {
+        ErrorKind::AttributeMissing
+    } else if has("syntax error") {
+        ErrorKind::SyntaxError
+    } else if has("expected a")
+        || (has("while a") && has("was expected"))
+        || has("cannot coerce")
+        || has("is not a function")
+    {
+        ErrorKind::TypeError
+    } else if has("builder for") || has("cannot build") || has("failed to build") {
+        ErrorKind::BuildFailure
+    } else {
+        ErrorKind::Other
+    }
+}
+
+/// Parses `at <file>:<line>:<column>:` as printed by nix.
+fn parse_position(line: &str) -> Option<Position> {
+    let location = line.strip_prefix("at ")?.trim_end_matches(':');
+    let mut parts = location.rsplitn(3, ':');
+    let column = parts.next()?.parse().ok()?;
+    let line = parts.next()?.parse().ok()?;
+    let file = parts.next()?.to_string();
+    Some(Position { file, line, column })
+}
+
+/// Nix prints source lines as `  10|   foo = bar;` and carets as `    |   ^`.
+fn is_snippet_line(line: &str) -> bool {
+    let Some((number, _)) = line.split_once('|') else {
+        return false;
+    };
+    number.trim().chars().all(|c| c.is_ascii_digit())
+}
+
+/// Returns the first single-quoted string after `prefix` in `text`.
+fn quoted_after(text: &str, prefix: &str) -> Option<String> {
+    let (_, rest) = text.split_once(prefix)?;
+    let (_, rest) = rest.split_once('\'')?;
+    let (quoted, _) = rest.split_once('\'')?;
+    Some(quoted.to_string())
+}
+
+/// Reads the lines around `position` from its file, marking the error line.
+fn source_snippet(position: &Position) -> Option<String> {
+    let source = std::fs::read_to_string(&position.file).ok()?;
+    let lines: Vec<&str> = source.lines().collect();
+    let index = position.line.checked_sub(1)?;
+    if index >= lines.len() {
+        return None;
+    }
+
+    let first = index.saturating_sub(SNIPPET_CONTEXT);
+    let last = (index + SNIPPET_CONTEXT).min(lines.len() - 1);
+    let width = (last + 1).to_string().len();
+    let mut snippet = Vec::new();
+    for (i, line) in lines.iter().enumerate().take(last + 1).skip(first) {
+        snippet.push(format!("{:>width$}| {}", i + 1, line, width = width));
+        if i == index {
+            let caret = " ".repeat(position.column.saturating_sub(1));
+            snippet.push(format!("{:>width$}| {}^", "", caret, width = width));
+        }
+    }
+    Some(snippet.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UNDEFINED_VARIABLE: &str = "\
+error:
+       … while calling the 'derivationStrict' builtin
+         at <nix/derivation-internal.nix>:34:12:
+           33|
+           34|   strict = derivationStrict drvAttrs;
+             |            ^
+           35|
+
+       … while evaluating the attribute 'buildInputs' of derivation 'hello-2.12.1'
+         at /nonexistent/project/default.nix:7:3:
+            6|   src = ./.;
+            7|   buildInputs = [ foo ];
+             |   ^
+            8| }
+
+       error: undefined variable 'foo'
+       at /nonexistent/project/default.nix:7:19:
+            6|   src = ./.;
+            7|   buildInputs = [ foo ];
+             |                   ^
+            8| }
+";
+
+    const MISSING_ATTRIBUTE: &str = "\
+error: flake 'github:NixOS/nixpkgs/5e4fbfb6b3de1aa2872b76d49fafc942626e2add' does not provide attribute 'packages.x86_64-linux.helo', 'legacyPackages.x86_64-linux.helo' or 'helo'
+";
+
+    const BUILD_FAILURE: &str = "\
+this derivation will be built:
+  /nix/store/4jv2wsxbzxmk5w6i7h0mw4jc3bmgvnf2-hello-2.12.1.drv
+building '/nix/store/4jv2wsxbzxmk5w6i7h0mw4jc3bmgvnf2-hello-2.12.1.drv'...
+error: builder for '/nix/store/4jv2wsxbzxmk5w6i7h0mw4jc3bmgvnf2-hello-2.12.1.drv' failed with exit code 2;
+       last 2 log lines:
+       > hello.c:3:1: error: expected ';' before '}' token
+       > make: *** [Makefile:10: all] Error 2
+       For full logs, run 'nix log /nix/store/4jv2wsxbzxmk5w6i7h0mw4jc3bmgvnf2-hello-2.12.1.drv'.
+error: 1 dependencies of derivation '/nix/store/a1gqnnz8w6sdrr9jx5l1wzj0wg3bi2qr-env.drv' failed to build
+";
+
+    const HASH_MISMATCH: &str = "\
+error: hash mismatch in fixed-output derivation '/nix/store/pd2c4lq5k2q6qvvhv0kd4d5dqg6xwy1a-source.drv':
+         specified: sha256-AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=
+            got:    sha256-3XYHZANT6AFBV0BqegkAZHbba6oeDkIUCDwbATLMhAY=
+error: 1 dependencies of derivation '/nix/store/4jv2wsxbzxmk5w6i7h0mw4jc3bmgvnf2-hello-2.12.1.drv' failed to build
+";
+
+    #[test]
+    fn parses_eval_error_with_position_and_trace() {
+        let error = parse("nix eval", Some(1), UNDEFINED_VARIABLE);
+        assert_eq!(error.kind, ErrorKind::UndefinedVariable);
+        assert_eq!(error.message, "undefined variable 'foo'");
+
+        let position = error.position.expect("error position");
+        assert_eq!(position.file, "/nonexistent/project/default.nix");
+        assert_eq!((position.line, position.column), (7, 19));
+
+        let descriptions: Vec<&str> = error
+            .trace
+            .iter()
+            .map(|frame| frame.description.as_str())
+            .collect();
+        assert_eq!(
+            descriptions,
+            [
+                "while calling the 'derivationStrict' builtin",
+                "while evaluating the attribute 'buildInputs' of derivation 'hello-2.12.1'",
+            ]
+        );
+        let frame = error.trace[0].position.as_ref().expect("frame position");
+        assert_eq!(frame.file, "<nix/derivation-internal.nix>");
+        assert_eq!((frame.line, frame.column), (34, 12));
+        assert_eq!(error.attr_path.as_deref(), Some("buildInputs"));
+
+        // The file does not exist, so the snippet nix printed is kept.
+        assert_eq!(
+            error.snippet.as_deref(),
+            Some("6|   src = ./.;\n7|   buildInputs = [ foo ];\n|                   ^\n8| }")
+        );
+    }
+
+    #[test]
+    fn parses_missing_attribute() {
+        let error = parse("nix build", Some(1), MISSING_ATTRIBUTE);
+        assert_eq!(error.kind, ErrorKind::AttributeMissing);
+        assert_eq!(
+            error.attr_path.as_deref(),
+            Some("packages.x86_64-linux.helo")
+        );
+        assert!(error.position.is_none());
+        assert!(error.trace.is_empty());
+    }
+
+    #[test]
+    fn parses_build_failure() {
+        let error = parse("nix build", Some(1), BUILD_FAILURE);
+        assert_eq!(error.kind, ErrorKind::BuildFailure);
+        // Only the first error is parsed, with the log lines nix included.
+        assert!(error.message.starts_with(
+            "builder for '/nix/store/4jv2wsxbzxmk5w6i7h0mw4jc3bmgvnf2-hello-2.12.1.drv' failed"
+        ));
+        assert!(
+            error
+                .message
+                .contains("> make: *** [Makefile:10: all] Error 2")
+        );
+        assert!(!error.message.contains("dependencies"));
+        assert!(error.stderr.starts_with("this derivation will be built:"));
+    }
+
+    #[test]
+    fn parses_hash_mismatch() {
+        let error = parse("nix build", Some(1), HASH_MISMATCH);
+        assert_eq!(error.kind, ErrorKind::HashMismatch);
+        assert!(
+            error
+                .message
+                .contains("got:    sha256-3XYHZANT6AFBV0BqegkAZHbba6oeDkIUCDwbATLMhAY=")
+        );
+        assert!(!error.message.contains("dependencies"));
+    }
+
+    #[test]
+    fn classifies_messages() {
+        let cases = [
+            (
+                "infinite recursion encountered",
+                ErrorKind::InfiniteRecursion,
+            ),
+            ("attribute 'foo' missing", ErrorKind::AttributeMissing),
+            (
+                "value is a set while a string was expected",
+                ErrorKind::TypeError,
+            ),
+            ("assertion '(stdenv.isLinux)' failed", ErrorKind::Assertion),
+            (
+                "Package 'hello-2.12.1' in /nix/store/x/pkgs/hello.nix:5 is marked as broken, refusing to evaluate.",
+                ErrorKind::Broken,
+            ),
+            (
+                "Cannot build '/nix/store/x-hello.drv'.\nReason: builder failed with exit code 2.",
+                ErrorKind::BuildFailure,
+            ),
+            ("syntax error, unexpected '}'", ErrorKind::SyntaxError),
+            ("something else went wrong", ErrorKind::Other),
+        ];
+        for (message, kind) in cases {
+            assert_eq!(classify(message), kind, "{}", message);
+        }
+    }
+
+    #[test]
+    fn falls_back_to_first_line_without_error_prefix() {
+        let error = parse("nix eval", Some(1), "\nwarning: unknown setting 'foo'\n");
+        assert_eq!(error.kind, ErrorKind::Other);
+        assert_eq!(error.message, "warning: unknown setting 'foo'");
+    }
+}
@@ -0,0 +1,102 @@
+use std::io::Error;
+
+use crate::cache::cached;
+use crate::index::IndexWriter;
+use crate::limits;
+
+const NIX_MANUAL_TREE_URL: &str =
+    "https://api.github.com/repos/NixOS/nix/git/trees/master?recursive=1";
+const NIX_MANUAL_PREFIX: &str = "doc/manual/source/";
+
+fn fetch_nix_manual_tree() -> Result<String, Error> {
+    let _permit = limits::http().acquire()?;
+    let tree_resp = ureq::get(NIX_MANUAL_TREE_URL)
+        .set(
+            "User-Agent",
+            "rime/1.0 (+https://github.com/lukasl-dev/rime)",
+        )
+        .call()
+        .map_err(Error::other)?;
+
+    let status = tree_resp.status();
+    let status_text = tree_resp.status_text().to_string();
+    let tree_body = tree_resp.into_string()?;
+
+    // Validate before caching so that error pages never end up in the cache.
+    if let Err(e) = serde_json::from_str::<serde_json::Value>(&tree_body) {
+        let preview = if tree_body.len() > 500 {
+            &tree_body[..500]
+        } else {
+            &tree_body
+        };
+        return Err(Error::other(format!(
+            "GitHub trees API returned invalid json (status {} {}): {}. Body preview: {}",
+            status, status_text, e, preview
+        )));
+    }
+    Ok(tree_body)
+}
+
+fn parse_nix_manual_tree(tree_body: &str) -> Result<Vec<String>, Error> {
+    let tree_json: serde_json::Value = serde_json::from_str(tree_body).map_err(Error::other)?;
+
+    let Some(items) = tree_json.get("tree").and_then(|v| v.as_array()) else {
+        let pretty =
+            serde_json::to_string_pretty(&tree_json).unwrap_or_else(|_| tree_body.to_string());
+        return Err(Error::other(format!(
+            "GitHub trees API response missing 'tree' array. Response: {}",
+            pretty
+        )));
+    };
+
+    let mut md_files: Vec<String> = items
+        .iter()
+        .filter_map(|item| {
+            let path = item.get("path")?.as_str()?;
+            let kind = item.get("type")?.as_str()?;
+            if kind == "blob" && path.starts_with(NIX_MANUAL_PREFIX) && path.ends_with(".md") {
+                // Strip prefix and extension
+                let without_prefix = &path[NIX_MANUAL_PREFIX.len()..];
+                let without_ext = without_prefix.strip_suffix(".md").unwrap_or(without_prefix);
+                Some(without_ext.to_string())
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    md_files.sort();
+    md_files.dedup();
+    Ok(md_files)
+}
+
+fn fetch_nix_manual(path: &str) -> Result<String, Error> {
+    let url = format!(
+        "https://raw.githubusercontent.com/NixOS/nix/master/{}{}.md",
+        NIX_MANUAL_PREFIX, path
+    );
+    let _permit = limits::http().acquire()?;
+    let resp = ureq::get(&url).call().map_err(Error::other)?;
+    resp.into_string()
+}
+
+pub(crate) fn list_nix_manual() -> Result<Vec<String>, Error> {
+    let tree_body = cached("nix_manual_tree", "master", fetch_nix_manual_tree)?;
+    parse_nix_manual_tree(&tree_body)
+}
+
+pub(crate) fn read_nix_manual(path: &str) -> Result<String, Error> {
+    cached("nix_manual", path, || fetch_nix_manual(path))
+}
+
+/// Writes the Nix manual file tree and every Markdown file in it to `index`.
+pub(crate) fn index_nix_manual(index: &IndexWriter) -> Result<usize, Error> {
+    let tree_body = fetch_nix_manual_tree()?;
+    let md_files = parse_nix_manual_tree(&tree_body)?;
+    index.write("nix_manual_tree", "master", &tree_body)?;
+
+    for path in &md_files {
+        index.write("nix_manual", path, &fetch_nix_manual(path)?)?;
+    }
+    Ok(md_files.len())
+}
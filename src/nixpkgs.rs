@@ -1,6 +1,12 @@
 use serde::Deserialize;
 use std::io::Error;
-use std::process::Command;
+
+use crate::cache::{cached, read_index};
+use crate::index::IndexWriter;
+use crate::nix;
+use crate::nix_error::nix_failure;
+
+const NIXPKGS_OPTIONS_LIMIT: usize = 20;
 
 #[derive(Debug, Deserialize)]
 pub(crate) struct NixpkgsOption {
@@ -11,8 +17,18 @@ pub(crate) struct NixpkgsOption {
     pub(crate) default: String,
 }
 
-pub(crate) fn search_nixpkgs_options(query: &str, ref_name: &str) -> Result<Vec<NixpkgsOption>, Error> {
-    let expression = format!(
+/// Builds the expression listing the NixOS options of `ref_name`. Without a
+/// query, every option is listed.
+fn options_expression(ref_name: &str, query: Option<&str>) -> String {
+    let results = match query {
+        Some(query) => format!(
+            r#"pkgs.lib.take {} (builtins.filter (opt: pkgs.lib.hasInfix "{}" opt.name) optionsList)"#,
+            NIXPKGS_OPTIONS_LIMIT, query
+        ),
+        None => "optionsList".to_string(),
+    };
+
+    format!(
         r#"
 let
   nixpkgs = builtins.getFlake "github:NixOS/nixpkgs/{}";
@@ -22,44 +38,70 @@ let
     modules = [];
   }};
   optionsList = pkgs.lib.optionAttrSetToDocList eval.options;
-  query = "{}";
-  results = builtins.filter (opt: pkgs.lib.hasInfix query opt.name) optionsList;
+  results = {};
 in
   builtins.map (opt: {{
     name = opt.name;
     description = if opt ? description then (if builtins.isAttrs opt.description && opt.description ? text then opt.description.text else if builtins.isString opt.description then opt.description else "") else "";
     type = if opt ? type then (if builtins.isString opt.type then opt.type else if builtins.isAttrs opt.type && opt.type ? description then opt.type.description else "") else "";
     default = if opt ? default then (if builtins.isAttrs opt.default && opt.default ? text then opt.default.text else builtins.toJSON opt.default) else "";
-  }}) (pkgs.lib.take 20 results)
+  }}) results
 "#,
-        ref_name, query
-    );
+        ref_name, results
+    )
+}
 
-    let output = Command::new("nix")
+fn eval_options(expression: &str) -> Result<String, Error> {
+    let output = nix::command()
         .args([
-            "--extra-experimental-features",
-            "nix-command flakes",
             "eval",
             "--json",
             "--impure",
             "--expr",
-            &expression,
+            expression,
         ])
         .output()?;
 
     if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-        return Err(Error::other(format!(
-            "nix eval for nixpkgs options failed: {}",
-            stderr
-        )));
+        return Err(nix_failure("nix eval", &output));
     }
 
-    let stdout = String::from_utf8(output.stdout)
-        .map_err(|e| Error::other(format!("failed to read nix output: {}", e)))?;
+    String::from_utf8(output.stdout)
+        .map_err(|e| Error::other(format!("failed to read nix output: {}", e)))
+}
+
+fn parse_options(stdout: &str) -> Result<Vec<NixpkgsOption>, Error> {
+    serde_json::from_str(stdout)
+        .map_err(|e| Error::other(format!("failed to parse nix output: {}", e)))
+}
 
-    let options: Vec<NixpkgsOption> = serde_json::from_str(&stdout)
-        .map_err(|e| Error::other(format!("failed to parse nix output: {}", e)))?;
+pub(crate) fn search_nixpkgs_options(query: &str, ref_name: &str) -> Result<Vec<NixpkgsOption>, Error> {
+    let live = cached("nixpkgs_options", &format!("{}/{}", ref_name, query), || {
+        eval_options(&options_expression(ref_name, Some(query)))
+    });
+    let stdout = match live {
+        Ok(stdout) => stdout,
+        // A prebuilt index holds every option of the ref, so it can answer
+        // any query when there is neither a live nor a cached answer.
+        Err(e) => {
+            let Some(body) = read_index("nixpkgs_options_index", ref_name) else {
+                return Err(e);
+            };
+            let options = parse_options(&body)?;
+            return Ok(options
+                .into_iter()
+                .filter(|opt| opt.name.contains(query))
+                .take(NIXPKGS_OPTIONS_LIMIT)
+                .collect());
+        }
+    };
+    parse_options(&stdout)
+}
 
-    Ok(options)
+/// Writes every NixOS option of `ref_name` to `index`.
+pub(crate) fn index_nixpkgs_options(index: &IndexWriter, ref_name: &str) -> Result<usize, Error> {
+    let stdout = eval_options(&options_expression(ref_name, None))?;
+    let count = parse_options(&stdout)?.len();
+    index.write("nixpkgs_options_index", ref_name, &stdout)?;
+    Ok(count)
 }
@@ -1,6 +1,13 @@
 use serde::Deserialize;
 use std::io::Error;
-use std::process::Command;
+
+use crate::cache::{cached, read_index};
+use crate::index::IndexWriter;
+use crate::limits;
+use crate::nix;
+use crate::nix_error::nix_failure;
+
+const NVF_OPTIONS_LIMIT: usize = 20;
 
 #[derive(Debug, Deserialize)]
 pub(crate) struct NvfOption {
@@ -11,58 +18,95 @@ pub(crate) struct NvfOption {
     pub(crate) default: String,
 }
 
-pub(crate) fn search_nvf_options(query: &str, ref_name: &str) -> Result<Vec<NvfOption>, Error> {
-    let expression = format!(
+/// Builds the expression listing the nvf options of `ref_name`. Without a
+/// query, every option is listed.
+fn options_expression(ref_name: &str, query: Option<&str>) -> String {
+    let results = match query {
+        Some(query) => format!(
+            r#"pkgs.lib.take {} (builtins.filter (opt: pkgs.lib.hasInfix "{}" opt.name) optionsList)"#,
+            NVF_OPTIONS_LIMIT, query
+        ),
+        None => "optionsList".to_string(),
+    };
+
+    format!(
         r#"
 let
   flake = builtins.getFlake "github:NotAShelf/nvf/{}";
   pkgs = import <nixpkgs> {{}};
   eval = flake.lib.neovimConfiguration {{ inherit pkgs; modules = []; }};
   optionsList = pkgs.lib.optionAttrSetToDocList eval.options;
-  query = "{}";
-  results = builtins.filter (opt: pkgs.lib.hasInfix query opt.name) optionsList;
+  results = {};
 in
   builtins.map (opt: {{
     name = opt.name;
     description = if opt ? description then opt.description else "";
     type = if opt ? type then opt.type else "";
     default = if opt ? default then (if builtins.isAttrs opt.default && opt.default ? text then opt.default.text else builtins.toJSON opt.default) else "";
-  }}) (pkgs.lib.take 20 results)
+  }}) results
 "#,
-        ref_name, query
-    );
+        ref_name, results
+    )
+}
 
-    let output = Command::new("nix")
+fn eval_options(expression: &str) -> Result<String, Error> {
+    let output = nix::command()
         .args([
-            "--extra-experimental-features",
-            "nix-command flakes",
             "eval",
             "--json",
             "--impure",
             "--expr",
-            &expression,
+            expression,
         ])
         .output()?;
 
     if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-        return Err(Error::other(format!(
-            "nix eval for nvf options failed: {}",
-            stderr
-        )));
+        return Err(nix_failure("nix eval", &output));
     }
 
-    let stdout = String::from_utf8(output.stdout)
-        .map_err(|e| Error::other(format!("failed to read nix output: {}", e)))?;
+    String::from_utf8(output.stdout)
+        .map_err(|e| Error::other(format!("failed to read nix output: {}", e)))
+}
 
-    let options: Vec<NvfOption> = serde_json::from_str(&stdout)
-        .map_err(|e| Error::other(format!("failed to parse nix output: {}", e)))?;
+fn parse_options(stdout: &str) -> Result<Vec<NvfOption>, Error> {
+    serde_json::from_str(stdout)
+        .map_err(|e| Error::other(format!("failed to parse nix output: {}", e)))
+}
 
-    Ok(options)
+pub(crate) fn search_nvf_options(query: &str, ref_name: &str) -> Result<Vec<NvfOption>, Error> {
+    let live = cached("nvf_options", &format!("{}/{}", ref_name, query), || {
+        eval_options(&options_expression(ref_name, Some(query)))
+    });
+    let stdout = match live {
+        Ok(stdout) => stdout,
+        // A prebuilt index holds every option of the ref, so it can answer
+        // any query when there is neither a live nor a cached answer.
+        Err(e) => {
+            let Some(body) = read_index("nvf_options_index", ref_name) else {
+                return Err(e);
+            };
+            let options = parse_options(&body)?;
+            return Ok(options
+                .into_iter()
+                .filter(|opt| opt.name.contains(query))
+                .take(NVF_OPTIONS_LIMIT)
+                .collect());
+        }
+    };
+    parse_options(&stdout)
 }
 
-pub(crate) fn list_nvf_manual(ref_name: &str) -> Result<Vec<String>, Error> {
+/// Writes every nvf option of `ref_name` to `index`.
+pub(crate) fn index_nvf_options(index: &IndexWriter, ref_name: &str) -> Result<usize, Error> {
+    let stdout = eval_options(&options_expression(ref_name, None))?;
+    let count = parse_options(&stdout)?.len();
+    index.write("nvf_options_index", ref_name, &stdout)?;
+    Ok(count)
+}
+
+fn fetch_nvf_manual_tree(ref_name: &str) -> Result<String, Error> {
     let tree_url = format!("https://api.github.com/repos/NotAShelf/nvf/git/trees/{}?recursive=1", ref_name);
+    let _permit = limits::http().acquire()?;
     let tree_resp = ureq::get(&tree_url)
         .set(
             "User-Agent",
@@ -71,10 +115,13 @@ pub(crate) fn list_nvf_manual(ref_name: &str) -> Result<Vec<String>, Error> {
         .call()
         .map_err(|e| Error::other(format!("GitHub API request failed: {}", e)))?;
 
-    let tree_body = tree_resp
+    tree_resp
         .into_string()
-        .map_err(|e| Error::other(format!("failed to read GitHub response: {}", e)))?;
-    let tree_json: serde_json::Value = serde_json::from_str(&tree_body)
+        .map_err(|e| Error::other(format!("failed to read GitHub response: {}", e)))
+}
+
+fn parse_nvf_manual_tree(tree_body: &str) -> Result<Vec<String>, Error> {
+    let tree_json: serde_json::Value = serde_json::from_str(tree_body)
         .map_err(|e| Error::other(format!("failed to parse GitHub response: {}", e)))?;
 
     let Some(items) = tree_json.get("tree").and_then(|v| v.as_array()) else {
@@ -101,19 +148,42 @@ pub(crate) fn list_nvf_manual(ref_name: &str) -> Result<Vec<String>, Error> {
     Ok(md_files)
 }
 
-pub(crate) fn read_nvf_manual(path: &str, ref_name: &str) -> Result<String, Error> {
+fn fetch_nvf_manual(path: &str, ref_name: &str) -> Result<String, Error> {
     let url = format!(
         "https://raw.githubusercontent.com/NotAShelf/nvf/{}/docs/manual/{}.md",
         ref_name,
         path
     );
 
+    let _permit = limits::http().acquire()?;
     let resp = ureq::get(&url)
         .call()
         .map_err(|e| Error::other(format!("failed to fetch nvf manual: {}", e)))?;
-    let body = resp
-        .into_string()
-        .map_err(|e| Error::other(format!("failed to read nvf manual: {}", e)))?;
+    resp.into_string()
+        .map_err(|e| Error::other(format!("failed to read nvf manual: {}", e)))
+}
 
-    Ok(body)
+pub(crate) fn list_nvf_manual(ref_name: &str) -> Result<Vec<String>, Error> {
+    let tree_body = cached("nvf_manual_tree", ref_name, || fetch_nvf_manual_tree(ref_name))?;
+    parse_nvf_manual_tree(&tree_body)
+}
+
+pub(crate) fn read_nvf_manual(path: &str, ref_name: &str) -> Result<String, Error> {
+    cached("nvf_manual", &format!("{}/{}", ref_name, path), || {
+        fetch_nvf_manual(path, ref_name)
+    })
+}
+
+/// Writes the nvf manual file tree of `ref_name` and every Markdown file in
+/// it to `index`.
+pub(crate) fn index_nvf_manual(index: &IndexWriter, ref_name: &str) -> Result<usize, Error> {
+    let tree_body = fetch_nvf_manual_tree(ref_name)?;
+    let md_files = parse_nvf_manual_tree(&tree_body)?;
+    index.write("nvf_manual_tree", ref_name, &tree_body)?;
+
+    for path in &md_files {
+        let body = fetch_nvf_manual(path, ref_name)?;
+        index.write("nvf_manual", &format!("{}/{}", ref_name, path), &body)?;
+    }
+    Ok(md_files.len())
 }
@@ -0,0 +1,59 @@
+use std::io::Error;
+
+use regex::RegexBuilder;
+
+use crate::cache::read_index;
+use crate::index::IndexWriter;
+use crate::nix;
+use crate::nix_error::nix_failure;
+
+/// Writes the metadata (attribute path, pname, version and description) of
+/// every package in `installable` to `index`.
+pub(crate) fn index_packages(index: &IndexWriter, installable: &str) -> Result<usize, Error> {
+    // Run: nix search --json <installable> ^
+    let output = nix::command()
+        .args(["search", "--json", installable, "^"])
+        .output()?;
+
+    if !output.status.success() {
+        return Err(nix_failure("nix search", &output));
+    }
+
+    let stdout = String::from_utf8(output.stdout)
+        .map_err(|e| Error::other(format!("failed to read nix output: {}", e)))?;
+    let packages: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&stdout)
+        .map_err(|e| Error::other(format!("failed to parse nix output: {}", e)))?;
+
+    index.write("packages", installable, &stdout)?;
+    Ok(packages.len())
+}
+
+/// Searches the indexed packages of `installable` case-insensitively, like
+/// `nix search`, mirroring its JSON output. Returns `None` if the installable
+/// is not indexed.
+pub(crate) fn search_indexed_packages(
+    installable: &str,
+    regex: &str,
+) -> Option<Result<serde_json::Value, Error>> {
+    let body = read_index("packages", installable)?;
+    let packages: serde_json::Map<String, serde_json::Value> = match serde_json::from_str(&body) {
+        Ok(packages) => packages,
+        Err(e) => return Some(Err(Error::other(format!("corrupt package index: {}", e)))),
+    };
+
+    let pattern = match RegexBuilder::new(regex).case_insensitive(true).build() {
+        Ok(pattern) => pattern,
+        Err(e) => return Some(Err(Error::other(format!("invalid regex '{}': {}", regex, e)))),
+    };
+    let matches: serde_json::Map<String, serde_json::Value> = packages
+        .into_iter()
+        .filter(|(attr_path, package)| {
+            let field = |name: &str| package.get(name).and_then(|v| v.as_str()).unwrap_or("");
+            pattern.is_match(attr_path)
+                || pattern.is_match(field("pname"))
+                || pattern.is_match(field("description"))
+        })
+        .collect();
+
+    Some(Ok(serde_json::Value::Object(matches)))
+}
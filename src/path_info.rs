@@ -0,0 +1,106 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Error;
+
+use serde_json::{Value, json};
+
+use crate::nix;
+use crate::nix_error::nix_failure;
+
+/// Returns the path info of the store paths of `installable`, or with
+/// `recursive` of their whole closure, keyed by store path. Closure sizes are
+/// included.
+pub(crate) fn path_info(installable: &str, recursive: bool) -> Result<BTreeMap<String, Value>, Error> {
+    // Run: nix path-info --json --closure-size [--recursive] <installable>
+    let mut command = nix::command();
+    command.args(["path-info", "--json", "--closure-size"]);
+    if recursive {
+        command.args(["--recursive"]);
+    }
+    let output = command.args([installable]).output()?;
+    if !output.status.success() {
+        return Err(nix_failure("nix path-info", &output));
+    }
+
+    let info: Value = serde_json::from_slice(&output.stdout)
+        .map_err(|e| Error::other(format!("failed to parse nix output: {}", e)))?;
+    // Older nix versions print a list of infos with a `path` field, newer ones
+    // an object keyed by path with `null` for invalid paths.
+    let infos = match info {
+        Value::Array(infos) => infos
+            .into_iter()
+            .filter_map(|info| {
+                let path = info.get("path")?.as_str()?.to_string();
+                Some((path, info))
+            })
+            .collect(),
+        Value::Object(infos) => infos.into_iter().collect(),
+        _ => BTreeMap::new(),
+    };
+    Ok(infos)
+}
+
+/// Summarizes the path info of `path` for display.
+pub(crate) fn summarize(path: &str, info: &Value) -> Value {
+    if info.is_null() || info.get("valid") == Some(&Value::Bool(false)) {
+        return json!({ "path": path, "valid": false });
+    }
+    json!({
+        "path": path,
+        "nar_hash": info.get("narHash"),
+        "nar_size": info.get("narSize"),
+        "closure_size": info.get("closureSize"),
+        "references": info.get("references"),
+        "deriver": info.get("deriver"),
+        "signatures": info.get("signatures"),
+        "ca": info.get("ca"),
+        "registration_time": info.get("registrationTime"),
+        "ultimate": info.get("ultimate"),
+    })
+}
+
+/// Returns the `top` largest paths of `closure` by NAR size, leaving out
+/// `roots`.
+pub(crate) fn largest(
+    closure: &BTreeMap<String, Value>,
+    roots: &BTreeSet<&str>,
+    top: usize,
+) -> Vec<Value> {
+    let size = |info: &Value, field: &str| info.get(field).and_then(Value::as_u64).unwrap_or(0);
+    let mut paths: Vec<(&String, &Value)> = closure
+        .iter()
+        .filter(|(path, _)| !roots.contains(path.as_str()))
+        .collect();
+    paths.sort_by_key(|(_, info)| Reverse(size(info, "narSize")));
+    paths
+        .into_iter()
+        .take(top)
+        .map(|(path, info)| {
+            json!({
+                "path": path,
+                "nar_size": size(info, "narSize"),
+                "closure_size": size(info, "closureSize"),
+            })
+        })
+        .collect()
+}
+
+/// Returns the paths in `closure` that refer to `path`, other than itself.
+pub(crate) fn referrers<'a>(closure: &'a BTreeMap<String, Value>, path: &str) -> Vec<&'a str> {
+    // References may be printed as full paths or, by newer nix versions,
+    // relative to the store directory.
+    let base = path.rsplit('/').next().unwrap_or(path);
+    closure
+        .iter()
+        .filter(|(referrer, _)| referrer.as_str() != path)
+        .filter(|(_, info)| {
+            info.get("references")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_str)
+                .any(|reference| reference == path || reference.rsplit('/').next() == Some(base))
+        })
+        .map(|(referrer, _)| referrer.as_str())
+        .collect()
+}
@@ -0,0 +1,197 @@
+use std::io::Error;
+
+use serde_json::{Value, json};
+
+use crate::nix;
+use crate::nix_error::nix_failure;
+
+/// Something to prefetch into the store.
+pub(crate) enum Source {
+    /// A file, or with `unpack` an archive, at `url` (including `file://`).
+    Url { url: String, unpack: bool },
+    /// A GitHub repository at a tag, branch or commit (default: HEAD).
+    GitHub {
+        owner: String,
+        repo: String,
+        rev: Option<String>,
+    },
+    /// A git repository at a tag, branch or commit (default: HEAD).
+    Git { url: String, rev: Option<String> },
+    /// A crate from crates.io.
+    Crate { name: String, version: String },
+    /// Any flake reference, e.g. `path:/some/dir` or `tarball+https://...`.
+    Flake(String),
+}
+
+/// Fetches `source` into the store and returns its SRI hash, store path and
+/// a fetcher expression using that hash.
+pub(crate) fn prefetch(source: &Source) -> Result<Value, Error> {
+    match source {
+        Source::Url { url, unpack } => {
+            let (hash, store_path) = prefetch_file(url, *unpack)?;
+            let fetcher = if *unpack { "fetchzip" } else { "fetchurl" };
+            Ok(json!({
+                "hash": hash,
+                "store_path": store_path,
+                "expression": format!(
+                    "{} {{\n  url = {};\n  hash = \"{}\";\n}}",
+                    fetcher,
+                    quote(url),
+                    hash
+                ),
+            }))
+        }
+        Source::Crate { name, version } => {
+            let url = format!("https://crates.io/api/v1/crates/{}/{}/download", name, version);
+            let (hash, store_path) = prefetch_file(&url, true)?;
+            Ok(json!({
+                "hash": hash,
+                "store_path": store_path,
+                "expression": format!(
+                    "fetchCrate {{\n  pname = {};\n  version = {};\n  hash = \"{}\";\n}}",
+                    quote(name),
+                    quote(version),
+                    hash
+                ),
+            }))
+        }
+        Source::GitHub { owner, repo, rev } => {
+            let flake_ref = match rev {
+                // Branches may contain slashes, which the path form cannot hold.
+                Some(rev) if is_commit(rev) => format!("github:{}/{}?rev={}", owner, repo, rev),
+                Some(rev) => format!("github:{}/{}?ref={}", owner, repo, rev),
+                None => format!("github:{}/{}", owner, repo),
+            };
+            let prefetched = prefetch_flake(&flake_ref)?;
+            let commit = locked_rev(&prefetched)?;
+            let hash = field(&prefetched, "hash")?;
+            // Keep the tag or branch visible next to the commit it resolved to.
+            let comment = match rev {
+                Some(rev) if rev != &commit => format!(" # {}", rev),
+                _ => String::new(),
+            };
+            Ok(json!({
+                "hash": hash,
+                "store_path": prefetched.get("storePath"),
+                "rev": commit,
+                "expression": format!(
+                    "fetchFromGitHub {{\n  owner = {};\n  repo = {};\n  rev = \"{}\";{}\n  hash = \"{}\";\n}}",
+                    quote(owner),
+                    quote(repo),
+                    commit,
+                    comment,
+                    hash
+                ),
+            }))
+        }
+        Source::Git { url, rev } => {
+            let url = url.strip_prefix("git+").unwrap_or(url);
+            let flake_ref = match rev {
+                // Commits are given as `rev`, everything else as `ref`.
+                Some(rev) if is_commit(rev) => format!("git+{}?rev={}", url, rev),
+                Some(rev) => format!("git+{}?ref={}", url, rev),
+                None => format!("git+{}", url),
+            };
+            let prefetched = prefetch_flake(&flake_ref)?;
+            let commit = locked_rev(&prefetched)?;
+            let hash = field(&prefetched, "hash")?;
+            // The flake fetcher leaves out submodules, which fetchgit includes
+            // by default; the hash only matches without them.
+            Ok(json!({
+                "hash": hash,
+                "store_path": prefetched.get("storePath"),
+                "rev": commit,
+                "expression": format!(
+                    "fetchgit {{\n  url = {};\n  rev = \"{}\";\n  fetchSubmodules = false;\n  hash = \"{}\";\n}}",
+                    quote(url),
+                    commit,
+                    hash
+                ),
+            }))
+        }
+        Source::Flake(flake_ref) => {
+            let prefetched = prefetch_flake(flake_ref)?;
+            let hash = field(&prefetched, "hash")?;
+            let mut attrs = String::new();
+            if let Some(locked) = prefetched.get("locked").and_then(Value::as_object) {
+                for (name, value) in locked {
+                    if name == "narHash" || name == "lastModified" {
+                        continue;
+                    }
+                    let value = match value {
+                        Value::String(s) => quote(s),
+                        other => other.to_string(),
+                    };
+                    attrs.push_str(&format!("\n  {} = {};", name, value));
+                }
+            }
+            Ok(json!({
+                "hash": hash,
+                "store_path": prefetched.get("storePath"),
+                "locked": prefetched.get("locked"),
+                "expression": format!(
+                    "builtins.fetchTree {{{}\n  narHash = \"{}\";\n}}",
+                    attrs, hash
+                ),
+            }))
+        }
+    }
+}
+
+fn prefetch_file(url: &str, unpack: bool) -> Result<(String, String), Error> {
+    // Run: nix store prefetch-file --json [--unpack] <url>
+    let mut command = nix::command();
+    command.args(["store", "prefetch-file", "--json"]);
+    if unpack {
+        command.args(["--unpack"]);
+    }
+    let output = command.args([url]).output()?;
+    if !output.status.success() {
+        return Err(nix_failure("nix store prefetch-file", &output));
+    }
+
+    let prefetched = parse_json(&output.stdout)?;
+    Ok((
+        field(&prefetched, "hash")?,
+        field(&prefetched, "storePath")?,
+    ))
+}
+
+fn prefetch_flake(flake_ref: &str) -> Result<Value, Error> {
+    // Run: nix flake prefetch --json <flake_ref>
+    let output = nix::command()
+        .args(["flake", "prefetch", "--json", flake_ref])
+        .output()?;
+    if !output.status.success() {
+        return Err(nix_failure("nix flake prefetch", &output));
+    }
+    parse_json(&output.stdout)
+}
+
+fn parse_json(stdout: &[u8]) -> Result<Value, Error> {
+    serde_json::from_slice(stdout)
+        .map_err(|e| Error::other(format!("failed to parse nix output: {}", e)))
+}
+
+fn field(value: &Value, name: &str) -> Result<String, Error> {
+    value
+        .get(name)
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .ok_or_else(|| Error::other(format!("nix output has no {}", name)))
+}
+
+fn locked_rev(prefetched: &Value) -> Result<String, Error> {
+    let locked = prefetched
+        .get("locked")
+        .ok_or_else(|| Error::other("nix output has no locked"))?;
+    field(locked, "rev")
+}
+
+fn is_commit(rev: &str) -> bool {
+    rev.len() == 40 && rev.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn quote(s: &str) -> String {
+    serde_json::to_string(s).unwrap_or_default().replace("${", "\\${")
+}
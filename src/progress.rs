@@ -0,0 +1,59 @@
+use std::cell::RefCell;
+use std::sync::Arc;
+
+use rust_mcp_sdk::McpServer;
+use rust_mcp_sdk::schema::{ProgressNotificationParams, ProgressToken};
+use tokio::sync::mpsc;
+
+thread_local! {
+    static REPORTER: RefCell<Option<Reporter>> = const { RefCell::new(None) };
+}
+
+/// Sends progress messages of a tool call running on a blocking thread to the
+/// client that requested them.
+pub(crate) struct Reporter {
+    tx: mpsc::UnboundedSender<String>,
+}
+
+impl Reporter {
+    /// Returns a reporter forwarding messages as progress notifications for
+    /// `token`. Must be called from within the tokio runtime.
+    pub(crate) fn forward(runtime: Arc<dyn McpServer>, token: ProgressToken) -> Reporter {
+        let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+        tokio::spawn(async move {
+            let mut progress = 0.0;
+            while let Some(message) = rx.recv().await {
+                progress += 1.0;
+                let _ = runtime
+                    .notify_progress(ProgressNotificationParams {
+                        message: Some(message),
+                        meta: None,
+                        progress,
+                        progress_token: token.clone(),
+                        total: None,
+                    })
+                    .await;
+            }
+        });
+        Reporter { tx }
+    }
+}
+
+/// Runs `f` with `reporter` receiving everything passed to [`report`] on the
+/// current thread.
+pub(crate) fn with_reporter<R>(reporter: Option<Reporter>, f: impl FnOnce() -> R) -> R {
+    REPORTER.with(|cell| *cell.borrow_mut() = reporter);
+    let result = f();
+    REPORTER.with(|cell| cell.borrow_mut().take());
+    result
+}
+
+/// Reports progress of the tool call running on the current thread. Does
+/// nothing if the client did not ask for progress notifications.
+pub(crate) fn report(message: String) {
+    REPORTER.with(|cell| {
+        if let Some(reporter) = cell.borrow().as_ref() {
+            let _ = reporter.tx.send(message);
+        }
+    });
+}
@@ -0,0 +1,186 @@
+use std::io::Error;
+
+use serde::Serialize;
+use serde_json::{Value, json};
+
+use crate::config::{self, Profile};
+use crate::flake_lock;
+use crate::nix;
+use crate::nix_error::nix_failure;
+
+/// The registries in the order nix looks up flake references in them.
+const REGISTRY_PRIORITY: [&str; 3] = ["user", "system", "global"];
+
+/// An entry of a flake registry, e.g. `flake:nixpkgs` to
+/// `github:NixOS/nixpkgs/nixpkgs-unstable` in the global registry.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Entry {
+    pub(crate) registry: String,
+    pub(crate) from: String,
+    pub(crate) to: String,
+    /// Whether an entry for the same reference in a registry nix consults
+    /// first hides this one.
+    pub(crate) shadowed: bool,
+}
+
+/// Returns the entries of the user, system and global registries.
+pub(crate) fn list() -> Result<Vec<Entry>, Error> {
+    // Run: nix registry list
+    let output = nix::command().args(["registry", "list"]).output()?;
+    if !output.status.success() {
+        return Err(nix_failure("nix registry list", &output));
+    }
+
+    Ok(parse_entries(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Parses the output of `nix registry list`, marking shadowed entries.
+fn parse_entries(stdout: &str) -> Vec<Entry> {
+    let mut entries: Vec<Entry> = stdout
+        .lines()
+        .filter_map(|line| {
+            let mut columns = line.split_whitespace();
+            Some(Entry {
+                registry: columns.next()?.to_string(),
+                from: columns.next()?.to_string(),
+                to: columns.next()?.to_string(),
+                shadowed: false,
+            })
+        })
+        .collect();
+    for i in 0..entries.len() {
+        let priority = registry_priority(&entries[i].registry);
+        entries[i].shadowed = entries
+            .iter()
+            .any(|other| other.from == entries[i].from && registry_priority(&other.registry) < priority);
+    }
+    entries
+}
+
+fn registry_priority(registry: &str) -> usize {
+    REGISTRY_PRIORITY
+        .iter()
+        .position(|name| *name == registry)
+        .unwrap_or(REGISTRY_PRIORITY.len())
+}
+
+/// Returns the registry entry nix uses for `reference`, e.g. `nixpkgs` or
+/// `flake:nixpkgs/nixos-24.05`: one for exactly that reference, or else for
+/// its flake id.
+fn lookup<'a>(entries: &'a [Entry], reference: &str) -> Option<&'a Entry> {
+    let reference = reference.strip_prefix("flake:").unwrap_or(reference);
+    let id = reference.split(['/', '?']).next().unwrap_or(reference);
+    [reference, id].iter().find_map(|from| {
+        entries
+            .iter()
+            .find(|entry| !entry.shadowed && entry.from == format!("flake:{}", from))
+    })
+}
+
+/// Resolves `reference` through the registries and locks it, returning the
+/// registry entry used, the URL it resolves to and the revision it locks to.
+pub(crate) fn resolve(reference: &str) -> Result<Value, Error> {
+    let entries = list()?;
+
+    // Run: nix flake metadata --json <reference>
+    let output = nix::command()
+        .args(["flake", "metadata", "--json", reference])
+        .output()?;
+    if !output.status.success() {
+        return Err(nix_failure("nix flake metadata", &output));
+    }
+    let metadata: Value = serde_json::from_slice(&output.stdout)
+        .map_err(|e| Error::other(format!("failed to parse nix output: {}", e)))?;
+
+    Ok(json!({
+        "reference": reference,
+        "registry_entry": lookup(&entries, reference),
+        "resolved_url": metadata.get("resolvedUrl"),
+        "locked_url": metadata.get("url"),
+        "rev": metadata.get("revision"),
+        "last_modified": metadata.get("lastModified").and_then(Value::as_u64).map(flake_lock::format_date),
+        "store_path": metadata.get("path"),
+    }))
+}
+
+/// Pins `reference` in the user registry to its current revision, or to the
+/// flake reference `to`, and returns the new entry. Needs the `write` profile.
+pub(crate) fn pin(reference: &str, to: Option<&str>) -> Result<Value, Error> {
+    config::require_profile(Profile::Write, "changing the flake registry")?;
+
+    // Run: nix registry pin <reference> [<to>]
+    let mut command = nix::command();
+    command.args(["registry", "pin", reference]);
+    if let Some(to) = to {
+        command.args([to]);
+    }
+    let output = command.output()?;
+    if !output.status.success() {
+        return Err(nix_failure("nix registry pin", &output));
+    }
+
+    let entries = list()?;
+    let entry = lookup(&entries, reference).filter(|entry| entry.registry == "user");
+    Ok(json!({
+        "reference": reference,
+        "pinned": entry.is_some(),
+        "entry": entry,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `nix registry list` with nixpkgs pinned in the user and system
+    /// registries.
+    const LIST: &str = "\
+user   flake:nixpkgs path:/nix/store/0000000000000000000000000000000a-source
+system flake:nixpkgs github:NixOS/nixpkgs/nixos-24.05
+system flake:nixpkgs/nixos-23.11 github:NixOS/nixpkgs/nixos-23.11
+global flake:nixpkgs github:NixOS/nixpkgs/nixpkgs-unstable
+global flake:home-manager github:nix-community/home-manager
+";
+
+    #[test]
+    fn shadows_entries_of_later_registries() {
+        let entries = parse_entries(LIST);
+        let shadowed: Vec<(&str, &str, bool)> = entries
+            .iter()
+            .map(|entry| (entry.registry.as_str(), entry.from.as_str(), entry.shadowed))
+            .collect();
+        assert_eq!(
+            shadowed,
+            [
+                ("user", "flake:nixpkgs", false),
+                ("system", "flake:nixpkgs", true),
+                ("system", "flake:nixpkgs/nixos-23.11", false),
+                ("global", "flake:nixpkgs", true),
+                ("global", "flake:home-manager", false),
+            ]
+        );
+    }
+
+    #[test]
+    fn looks_up_references_and_their_flake_ids() {
+        let entries = parse_entries(LIST);
+        let to = |reference| lookup(&entries, reference).map(|entry| entry.to.as_str());
+        assert_eq!(
+            to("nixpkgs"),
+            Some("path:/nix/store/0000000000000000000000000000000a-source")
+        );
+        assert_eq!(
+            to("flake:nixpkgs/nixos-23.11"),
+            Some("github:NixOS/nixpkgs/nixos-23.11")
+        );
+        assert_eq!(
+            to("nixpkgs/nixos-24.05"),
+            Some("path:/nix/store/0000000000000000000000000000000a-source")
+        );
+        assert_eq!(
+            to("home-manager?dir=modules"),
+            Some("github:nix-community/home-manager")
+        );
+        assert_eq!(to("nix-darwin"), None);
+    }
+}
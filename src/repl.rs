@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::process::{Child, ChildStdin};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::limits;
+use crate::nix;
+
+/// Sessions not used for this long are closed on the next tool call.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// How long a new session may take to print its banner.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+const PROMPT: &str = "nix-repl> ";
+
+type Session = Arc<Mutex<Repl>>;
+
+static SESSIONS: Mutex<Option<HashMap<String, Session>>> = Mutex::new(None);
+
+/// A long-lived `nix repl` process. Every input is followed by a sentinel
+/// expression that prints a unique marker on both stdout and stderr, so the
+/// output of each input can be told apart from that of the next.
+struct Repl {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: Receiver<String>,
+    stderr: Receiver<String>,
+    inputs: u64,
+    last_used: Instant,
+}
+
+impl Repl {
+    fn start() -> Result<Repl, Error> {
+        let mut child = nix::command().args(["repl"]).spawn()?;
+        let (Some(stdin), Some(stdout), Some(stderr)) =
+            (child.stdin.take(), child.stdout.take(), child.stderr.take())
+        else {
+            let _ = child.kill();
+            return Err(Error::other("failed to open the pipes of nix repl"));
+        };
+
+        let mut repl = Repl {
+            child,
+            stdin,
+            stdout: read_lines(stdout),
+            stderr: read_lines(stderr),
+            inputs: 0,
+            last_used: Instant::now(),
+        };
+        // Swallow the welcome banner.
+        let _permit = limits::nix().acquire()?;
+        repl.eval("", STARTUP_TIMEOUT)?;
+        Ok(repl)
+    }
+
+    /// Sends `input` to the repl and returns what it printed in response.
+    /// Callers hold a nix subprocess slot while the repl is busy.
+    fn eval(&mut self, input: &str, timeout: Duration) -> Result<String, Error> {
+        self.inputs += 1;
+        self.last_used = Instant::now();
+        let sentinel = format!("__rime_done_{}", self.inputs);
+
+        let mut message = String::new();
+        for line in input.lines() {
+            message.push_str(line);
+            message.push('\n');
+        }
+        message.push_str(&format!("builtins.trace \"{0}\" \"{0}\"\n", sentinel));
+        self.stdin.write_all(message.as_bytes())?;
+        self.stdin.flush()?;
+
+        let deadline = Instant::now() + timeout;
+        let stdout = collect_until(&self.stdout, &sentinel, deadline)?;
+        let stderr = collect_until(&self.stderr, &sentinel, deadline)?;
+
+        let mut output: Vec<String> = stdout;
+        output.extend(stderr);
+        Ok(output.join("\n").trim().to_string())
+    }
+}
+
+impl Drop for Repl {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Forwards the lines of `pipe` to a channel, cleaned of prompts and colors.
+fn read_lines(pipe: impl Read + Send + 'static) -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in BufReader::new(pipe).lines() {
+            let Ok(line) = line else { break };
+            let line = strip_ansi(&line);
+            let line = line.trim_start_matches(PROMPT).to_string();
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+/// Collects lines from `lines` up to (excluding) the one containing
+/// `sentinel`.
+fn collect_until(
+    lines: &Receiver<String>,
+    sentinel: &str,
+    deadline: Instant,
+) -> Result<Vec<String>, Error> {
+    let mut collected = Vec::new();
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match lines.recv_timeout(remaining) {
+            Ok(line) if line.contains(sentinel) => return Ok(collected),
+            Ok(line) => collected.push(line),
+            Err(RecvTimeoutError::Timeout) => {
+                return Err(Error::new(ErrorKind::TimedOut, "nix repl did not answer in time"));
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(Error::other(format!(
+                    "nix repl exited: {}",
+                    collected.join("\n")
+                )));
+            }
+        }
+    }
+}
+
+fn strip_ansi(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c != '\u{1b}' {
+            out.push(c);
+            continue;
+        }
+        if chars.next() == Some('[') {
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        }
+    }
+    out
+}
+
+fn with_sessions<T>(f: impl FnOnce(&mut HashMap<String, Session>) -> T) -> T {
+    let mut sessions = SESSIONS.lock().unwrap_or_else(|e| e.into_inner());
+    let sessions = sessions.get_or_insert_with(HashMap::new);
+    // Busy sessions are locked and thus never considered idle.
+    sessions.retain(|_, session| {
+        session
+            .try_lock()
+            .map(|repl| repl.last_used.elapsed() < IDLE_TIMEOUT)
+            .unwrap_or(true)
+    });
+    f(sessions)
+}
+
+/// Starts a new repl for `session`, replacing any existing one.
+pub(crate) fn create_session(session: &str) -> Result<(), Error> {
+    close_session(session);
+    let repl = Repl::start()?;
+    with_sessions(|sessions| sessions.insert(session.to_string(), Arc::new(Mutex::new(repl))));
+    Ok(())
+}
+
+/// Closes the repl of `session`. Returns whether there was one.
+pub(crate) fn close_session(session: &str) -> bool {
+    with_sessions(|sessions| sessions.remove(session)).is_some()
+}
+
+/// Evaluates `input` in the repl of `session`, starting one if needed. If
+/// the repl does not answer within `timeout`, it is closed.
+pub(crate) fn eval_in_session(
+    session: &str,
+    input: &str,
+    timeout: Duration,
+) -> Result<String, Error> {
+    let existing = with_sessions(|sessions| sessions.get(session).cloned());
+    let repl = match existing {
+        Some(repl) => repl,
+        None => {
+            create_session(session)?;
+            with_sessions(|sessions| sessions.get(session).cloned())
+                .ok_or_else(|| Error::other("the session was closed concurrently"))?
+        }
+    };
+
+    let mut repl = repl.lock().unwrap_or_else(|e| e.into_inner());
+    let _permit = limits::nix().acquire()?;
+    repl.eval(input, timeout).map_err(|e| {
+        // The repl is in an unknown state, e.g. still evaluating or waiting
+        // for the rest of an incomplete expression.
+        drop(repl);
+        close_session(session);
+        Error::new(e.kind(), format!("{}; the session was closed", e))
+    })
+}
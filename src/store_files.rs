@@ -0,0 +1,212 @@
+use std::io::Error;
+
+use serde_json::{Map, Value, json};
+
+use crate::config::{self, Profile};
+use crate::nix;
+use crate::nix_error::nix_failure;
+use crate::progress;
+use crate::store_path;
+
+/// Returns the store path of `installable`, an installable or store path.
+/// Unless `realise` is set, the path must already be valid in the store; with
+/// it, the path is built or substituted first, which needs the `build`
+/// profile.
+pub(crate) fn resolve(installable: &str, realise: bool) -> Result<String, Error> {
+    let is_store_path = installable.starts_with('/') && store_path::parse(installable).is_ok();
+    if is_store_path && !realise {
+        return Ok(installable.to_string());
+    }
+
+    let output = if realise {
+        config::require_profile(Profile::Build, "building or substituting")?;
+        progress::report(format!("realising {}", installable));
+        // Run: nix build --no-link --print-out-paths <installable>
+        let output = nix::command()
+            .args(["build", "--no-link", "--print-out-paths", installable])
+            .output()?;
+        if !output.status.success() {
+            return Err(nix_failure("nix build", &output));
+        }
+        output
+    } else {
+        // Run: nix path-info <installable>
+        let output = nix::command().args(["path-info", installable]).output()?;
+        if !output.status.success() {
+            return Err(nix_failure("nix path-info", &output));
+        }
+        output
+    };
+    if is_store_path {
+        return Ok(installable.to_string());
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    stdout
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .map(str::to_string)
+        .ok_or_else(|| Error::other(format!("{} has no store path", installable)))
+}
+
+/// Returns the path to inspect: `sub_path` inside the store path of
+/// `installable`, or its `bin` directory, or with `main_program` its main
+/// program in `bin`.
+pub(crate) fn locate(
+    installable: &str,
+    sub_path: Option<&str>,
+    bin: bool,
+    main_program: bool,
+    realise: bool,
+) -> Result<String, Error> {
+    let sub_path = match (sub_path, bin, main_program) {
+        (_, _, true) => Some(format!("bin/{}", self::main_program(installable)?)),
+        (_, true, false) => Some("bin".to_string()),
+        (sub_path, false, false) => sub_path.map(|p| p.trim_matches('/').to_string()),
+    };
+    let store_path = resolve(installable, realise)?;
+    Ok(match sub_path.filter(|p| !p.is_empty()) {
+        Some(sub_path) => format!("{}/{}", store_path.trim_end_matches('/'), sub_path),
+        None => store_path,
+    })
+}
+
+/// Returns the name of the main program of `installable` like `lib.getExe`:
+/// its `meta.mainProgram`, or else its package name. For store paths, the
+/// package name is taken from the path.
+pub(crate) fn main_program(installable: &str) -> Result<String, Error> {
+    if installable.starts_with('/')
+        && let Ok(parsed) = store_path::parse(installable)
+    {
+        return Ok(parsed.pname);
+    }
+
+    // Run: nix eval --raw <installable> --apply <main program>
+    let output = nix::command()
+        .args([
+            "eval",
+            "--raw",
+            installable,
+            "--apply",
+            "pkg: pkg.meta.mainProgram or (builtins.parseDrvName pkg.name).name",
+        ])
+        .output()?;
+    if !output.status.success() {
+        return Err(nix_failure("nix eval", &output));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Lists the files at `path`, a store path or a path inside one, with their
+/// type, size, executable bit and symlink target. With `recursive`, the whole
+/// tree is listed, up to `max_entries` entries.
+pub(crate) fn list(path: &str, recursive: bool, max_entries: usize) -> Result<Value, Error> {
+    // Run: nix store ls --json [--recursive] <path>
+    let mut command = nix::command();
+    command.args(["store", "ls", "--json"]);
+    if recursive {
+        command.args(["--recursive"]);
+    }
+    let output = command.args([path]).output()?;
+    if !output.status.success() {
+        return Err(nix_failure("nix store ls", &output));
+    }
+    let listing: Value = serde_json::from_slice(&output.stdout)
+        .map_err(|e| Error::other(format!("failed to parse nix output: {}", e)))?;
+
+    let mut entries = Vec::new();
+    let mut total_entries = 0;
+    let mut total_size = 0;
+    match listing.get("entries").and_then(Value::as_object) {
+        Some(children) => flatten(
+            children,
+            "",
+            &mut entries,
+            &mut total_entries,
+            &mut total_size,
+            max_entries,
+        ),
+        None => {
+            total_entries = 1;
+            total_size = size(&listing);
+            entries.push(entry(".", &listing));
+        }
+    }
+
+    Ok(json!({
+        "path": path,
+        "type": listing.get("type"),
+        "entries": entries,
+        "total_entries": total_entries,
+        "total_size": total_size,
+        "truncated": total_entries > entries.len(),
+    }))
+}
+
+fn flatten(
+    children: &Map<String, Value>,
+    prefix: &str,
+    entries: &mut Vec<Value>,
+    total_entries: &mut usize,
+    total_size: &mut u64,
+    max_entries: usize,
+) {
+    for (name, child) in children {
+        let path = format!("{}{}", prefix, name);
+        *total_entries += 1;
+        *total_size += size(child);
+        if entries.len() < max_entries {
+            entries.push(entry(&path, child));
+        }
+        if let Some(grandchildren) = child.get("entries").and_then(Value::as_object) {
+            flatten(
+                grandchildren,
+                &format!("{}/", path),
+                entries,
+                total_entries,
+                total_size,
+                max_entries,
+            );
+        }
+    }
+}
+
+fn size(listing: &Value) -> u64 {
+    listing.get("size").and_then(Value::as_u64).unwrap_or(0)
+}
+
+fn entry(path: &str, listing: &Value) -> Value {
+    let mut entry = json!({ "path": path, "type": listing.get("type") });
+    for field in ["size", "executable", "target"] {
+        if let Some(value) = listing.get(field) {
+            entry[field] = value.clone();
+        }
+    }
+    entry
+}
+
+/// Returns the contents of the file at `path` inside a store path, cut off
+/// after `max_bytes` bytes. Binary files are described instead.
+pub(crate) fn read(path: &str, max_bytes: usize) -> Result<String, Error> {
+    // Run: nix store cat <path>
+    let output = nix::command().args(["store", "cat", path]).output()?;
+    if !output.status.success() {
+        return Err(nix_failure("nix store cat", &output));
+    }
+
+    let contents = output.stdout;
+    let head = &contents[..contents.len().min(max_bytes)];
+    if head.contains(&0) {
+        return Ok(format!("{} is a binary file ({} bytes)", path, contents.len()));
+    }
+    let text = String::from_utf8_lossy(head);
+    if contents.len() > max_bytes {
+        return Ok(format!(
+            "{}\n… ({} more bytes)",
+            text,
+            contents.len() - max_bytes
+        ));
+    }
+    Ok(text.to_string())
+}
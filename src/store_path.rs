@@ -0,0 +1,116 @@
+use std::io::Error;
+
+use serde::Serialize;
+
+const HASH_LEN: usize = 32;
+const NIX32_CHARS: &str = "0123456789abcdfghijklmnpqrsvwxyz";
+
+/// Outputs commonly appended to store path names, e.g. `openssl-3.0.13-dev`.
+const OUTPUT_NAMES: [&str; 9] = [
+    "bin", "dev", "doc", "info", "lib", "man", "out", "static", "debug",
+];
+
+/// A store path split into its parts.
+#[derive(Debug, Serialize)]
+pub(crate) struct StorePath {
+    pub(crate) store_dir: Option<String>,
+    pub(crate) hash: String,
+    /// Everything after the hash, e.g. `hello-2.12.1`.
+    pub(crate) name: String,
+    /// The package name as Nix splits it, e.g. `hello`.
+    pub(crate) pname: String,
+    /// The version as Nix splits it, e.g. `2.12.1`. Empty if there is none.
+    pub(crate) version: String,
+    /// The output, if the name ends in a well-known output name that is not
+    /// part of the version, e.g. `dev` for `openssl-3.0.13-dev`.
+    pub(crate) output: Option<String>,
+    pub(crate) is_derivation: bool,
+    /// The path below the store path, e.g. `bin/hello`.
+    pub(crate) sub_path: Option<String>,
+}
+
+/// Parses `/nix/store/<hash>-<name>[/<sub path>]`. The store directory may be
+/// anything, or missing.
+pub(crate) fn parse(path: &str) -> Result<StorePath, Error> {
+    let path = path.trim();
+    let components: Vec<&str> = path.split('/').collect();
+    let index = components
+        .iter()
+        .position(|component| split_hash(component).is_some())
+        .ok_or_else(|| {
+            Error::other(format!(
+                "{} is not a store path: expected <store dir>/<32-character hash>-<name>",
+                path
+            ))
+        })?;
+
+    let (hash, name) = split_hash(components[index]).unwrap_or_default();
+    let store_dir = components[..index].join("/");
+    let sub_path = components[index + 1..].join("/");
+
+    if let Some(c) = name
+        .chars()
+        .find(|c| !c.is_ascii_alphanumeric() && !"+-._?=".contains(*c))
+    {
+        return Err(Error::other(format!(
+            "store path name {} contains the invalid character {:?}",
+            name, c
+        )));
+    }
+
+    let is_derivation = name.ends_with(".drv");
+    let base = name.strip_suffix(".drv").unwrap_or(name);
+    let (pname, version) = split_name(base);
+    let output = version
+        .rsplit_once('-')
+        .map_or(version, |(_, last)| last)
+        .to_string();
+    let output = OUTPUT_NAMES
+        .contains(&output.as_str())
+        .then_some(output)
+        .filter(|_| !is_derivation);
+
+    Ok(StorePath {
+        store_dir: (!store_dir.is_empty()).then_some(store_dir),
+        hash: hash.to_string(),
+        name: name.to_string(),
+        pname: pname.to_string(),
+        version: version.to_string(),
+        output,
+        is_derivation,
+        sub_path: (!sub_path.is_empty()).then_some(sub_path),
+    })
+}
+
+fn split_hash(component: &str) -> Option<(&str, &str)> {
+    let (hash, name) = component.split_at_checked(HASH_LEN)?;
+    let name = name.strip_prefix('-')?;
+    let valid = hash.chars().all(|c| NIX32_CHARS.contains(c)) && !name.is_empty();
+    valid.then_some((hash, name))
+}
+
+/// Splits a name into package name and version like Nix's `DrvName`: the
+/// version starts after the first dash that is not followed by a letter.
+pub(crate) fn split_name(name: &str) -> (&str, &str) {
+    let bytes = name.as_bytes();
+    for (i, &b) in bytes.iter().enumerate() {
+        if b == b'-' && bytes.get(i + 1).is_some_and(|next| !next.is_ascii_alphabetic()) {
+            return (&name[..i], &name[i + 1..]);
+        }
+    }
+    (name, "")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_names_like_drv_name() {
+        assert_eq!(split_name("hello-2.12.1"), ("hello", "2.12.1"));
+        assert_eq!(split_name("foo-bar-1.0-dev"), ("foo-bar", "1.0-dev"));
+        // A dash followed by a digit starts the version even inside a name.
+        assert_eq!(split_name("name-with-2digits"), ("name-with", "2digits"));
+        assert_eq!(split_name("source"), ("source", ""));
+    }
+}
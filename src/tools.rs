@@ -1,6 +1,6 @@
 use std::collections::BTreeMap;
 use std::io::Error;
-use std::process::Command;
+use std::time::Duration;
 
 use rust_mcp_sdk::schema::{CallToolResult, TextContent, schema_utils::CallToolError};
 use rust_mcp_sdk::{
@@ -8,10 +8,47 @@ use rust_mcp_sdk::{
     tool_box,
 };
 
+use crate::binary_cache;
+use crate::build::{parse_dry_run, triage};
+use crate::cache::cached;
+use crate::closure;
+use crate::config::{self, Profile};
+use crate::derivation;
+use crate::eval::{nix_syntax_apply, render_nix};
+use crate::flake_check::{self, CheckOptions};
+use crate::flake_lock;
+use crate::flake_show::{self, ShowOptions};
+use crate::flake_update;
+use crate::hash;
+use crate::hash_mismatch::{find_edit, parse_mismatches, source_position};
 use crate::home_manager::search_home_manager_options;
+use crate::limits;
+use crate::log::{filter_log, grep_pattern, latest_failed_derivation, read_log};
+use crate::nix;
+use crate::nix_error::nix_failure;
+use crate::nix_manual::{list_nix_manual, read_nix_manual};
 use crate::nixpkgs::search_nixpkgs_options;
+use crate::packages::search_indexed_packages;
+use crate::path_info;
+use crate::prefetch::{Source, prefetch};
+use crate::progress;
+use crate::registry;
+use crate::repl::{close_session, create_session, eval_in_session};
+use crate::store_files;
+use crate::store_path;
 use crate::nvf::{list_nvf_manual, read_nvf_manual, search_nvf_options};
 
+const DEFAULT_EVAL_DEPTH: u32 = 2;
+const DEFAULT_BUILD_LOG_LINES: usize = 50;
+const DEFAULT_REPL_TIMEOUT_SECS: u64 = 300;
+const DEFAULT_DRV_VALUE_LENGTH: u32 = 200;
+const DEFAULT_LARGEST_PATHS: u32 = 10;
+const DEFAULT_TREE_DEPTH: u32 = 3;
+const DEFAULT_SYSTEM_PROFILE: &str = "/nix/var/nix/profiles/system";
+const DEFAULT_LS_ENTRIES: u32 = 500;
+const DEFAULT_CAT_BYTES: u32 = 64 * 1024;
+const DEFAULT_CACHE_QUERIES: u32 = 2000;
+const DEFAULT_STALE_DAYS: u32 = 30;
 const NIXOS_API_BASE: &str = "https://search.nixos.org/backend";
 const AUTH_BASIC_B64: &str = "Basic YVdWU0FMWHBadjpYOGdQSG56TDUyd0ZFZWt1eHNmUTljU2g=";
 const NIXOS_GENERATIONS: [i32; 4] = [43, 44, 45, 46];
@@ -19,90 +56,952 @@ const NIXOS_VERSIONS: [&str; 7] = [
     "unstable", "20.09", "24.11", "25.05", "25.11", "26.05", "30.05",
 ];
 
-#[mcp_tool(name = "nix_evaluate", description = "Evaluate a Nix expression.")]
+#[mcp_tool(
+    name = "nix_evaluate",
+    description = "Evaluate a flake installable, a Nix expression or a Nix file, as JSON or in Nix syntax."
+)]
+#[derive(Debug, ::serde::Deserialize, ::serde::Serialize, JsonSchema)]
+pub struct NixEvaluateTool {
+    /// The installable to evaluate. With `expr` or `file`, the attribute path
+    /// to select from their value instead.
+    ///
+    /// Examples: "nixpkgs#lib.version", ".#nixosConfigurations.host.config.networking", etc.
+    #[serde(alias = "expression")]
+    installable: Option<String>,
+
+    /// A Nix expression to evaluate (`--expr`).
+    ///
+    /// Examples: "builtins.nixVersion", "(import <nixpkgs> {}).lib.version", etc.
+    expr: Option<String>,
+
+    /// A Nix file to evaluate (`--file`).
+    file: Option<String>,
+
+    /// A function to apply to the value before output (`--apply`).
+    ///
+    /// Examples: "builtins.attrNames", "x: x.meta.description", etc.
+    apply: Option<String>,
+
+    /// Allow access to mutable paths, the environment and `<nixpkgs>` (`--impure`).
+    impure: Option<bool>,
+
+    /// Flake inputs to override, as `input=flakeref` (`--override-input`).
+    ///
+    /// Examples: ["nixpkgs=github:NixOS/nixpkgs/nixos-24.11"], etc.
+    override_input: Option<Vec<String>>,
+
+    /// The system to evaluate for (`--system`).
+    ///
+    /// Examples: "x86_64-linux", "aarch64-darwin", etc.
+    system: Option<String>,
+
+    /// Include the evaluation stack trace in errors (`--show-trace`).
+    show_trace: Option<bool>,
+
+    /// "json" (default) or "nix". Nix syntax also renders functions and
+    /// derivations, and limits how deep attribute sets and lists are shown.
+    format: Option<String>,
+
+    /// How many levels of attribute sets and lists to show in "nix" format
+    /// (default: 2).
+    depth: Option<u32>,
+}
+
+impl NixEvaluateTool {
+    pub fn call_tool(&self) -> Result<CallToolResult, CallToolError> {
+        let nix_syntax = match self.format.as_deref() {
+            None | Some("json") => false,
+            Some("nix") => true,
+            Some(other) => {
+                return Err(CallToolError::new(Error::other(format!(
+                    r#"unknown format "{}": expected "json" or "nix""#,
+                    other
+                ))));
+            }
+        };
+        if self.expr.is_some() && self.file.is_some() {
+            return Err(CallToolError::new(Error::other(
+                "expr and file cannot be combined",
+            )));
+        }
+        if self.installable.is_none() && self.expr.is_none() && self.file.is_none() {
+            return Err(CallToolError::new(Error::other(
+                "one of installable, expr or file is required",
+            )));
+        }
+
+        // Run: nix eval --json [--expr <expr> | --file <file>] [<installable>] [flags]
+        let mut command = nix::command();
+        command.args(["eval", "--json"]);
+        if let Some(expr) = &self.expr {
+            command.args(["--expr", expr]);
+        }
+        if let Some(file) = &self.file {
+            command.args(["--file", file]);
+        }
+        if let Some(installable) = &self.installable {
+            command.args([installable]);
+        }
+        if nix_syntax {
+            let depth = self.depth.unwrap_or(DEFAULT_EVAL_DEPTH);
+            command.args(["--apply", &nix_syntax_apply(depth, self.apply.as_deref())]);
+        } else if let Some(apply) = &self.apply {
+            command.args(["--apply", apply]);
+        }
+        if self.impure == Some(true) {
+            command.args(["--impure"]);
+        }
+        for override_input in self.override_input.iter().flatten() {
+            let Some((input, flake_ref)) = override_input.split_once('=') else {
+                return Err(CallToolError::new(Error::other(format!(
+                    r#"invalid override_input "{}": expected input=flakeref"#,
+                    override_input
+                ))));
+            };
+            command.args(["--override-input", input, flake_ref]);
+        }
+        if let Some(system) = &self.system {
+            command.args(["--system", system]);
+        }
+        if self.show_trace == Some(true) {
+            command.args(["--show-trace"]);
+        }
+        let output = command.output().map_err(CallToolError::new)?;
+
+        if !output.status.success() {
+            return Err(CallToolError::new(nix_failure("nix eval", &output)));
+        }
+
+        let stdout = String::from_utf8(output.stdout).map_err(CallToolError::new)?;
+        let json_val: serde_json::Value =
+            serde_json::from_str(&stdout).map_err(CallToolError::new)?;
+
+        let text = if nix_syntax {
+            render_nix(&json_val)
+        } else {
+            serde_json::to_string_pretty(&json_val).map_err(CallToolError::new)?
+        };
+
+        Ok(CallToolResult::text_content(vec![TextContent::from(text)]))
+    }
+}
+
+#[mcp_tool(
+    name = "nix_log",
+    description = "Get the build log of an installable, store path or `.drv` path, or of the most recent failed build."
+)]
+#[derive(Debug, ::serde::Deserialize, ::serde::Serialize, JsonSchema)]
+pub struct NixLogTool {
+    /// The installable, store path or `.drv` path to get the build log for.
+    /// Omit it to get the log of the most recent failed local build.
+    ///
+    /// Examples: "nixpkgs#hello", "/nix/store/...-hello-2.12.1.drv", etc.
+    installable: Option<String>,
+
+    /// Only return the last this many lines (after filtering).
+    tail_lines: Option<u32>,
+
+    /// Only return lines matching this case-insensitive regex.
+    ///
+    /// Examples: "error", "^FAIL|failed", etc.
+    grep: Option<String>,
+
+    /// Lines of context to return around each `grep` match (default: 0).
+    context_lines: Option<u32>,
+}
+
+impl NixLogTool {
+    pub fn call_tool(&self) -> Result<CallToolResult, CallToolError> {
+        let (target, header) = match &self.installable {
+            Some(installable) => (installable.clone(), None),
+            None => {
+                let drv = latest_failed_derivation()
+                    .map_err(CallToolError::new)?
+                    .ok_or_else(|| {
+                        CallToolError::new(Error::other(
+                            "no failed build found in the local build logs",
+                        ))
+                    })?;
+                let header = format!("Most recent failed build: {}", drv);
+                (drv, Some(header))
+            }
+        };
+
+        let grep = self
+            .grep
+            .as_deref()
+            .map(grep_pattern)
+            .transpose()
+            .map_err(CallToolError::new)?;
+        let log = read_log(&target).map_err(CallToolError::new)?;
+        let mut text = filter_log(
+            &log,
+            grep.as_ref(),
+            self.context_lines.unwrap_or(0) as usize,
+            self.tail_lines.map(|tail| tail as usize),
+        );
+        if text.is_empty() && self.grep.is_some() {
+            text = "No lines matched.".to_string();
+        }
+        if let Some(header) = header {
+            text = format!("{}\n\n{}", header, text);
+        }
+
+        Ok(CallToolResult::text_content(vec![TextContent::from(text)]))
+    }
+}
+
+#[mcp_tool(
+    name = "nix_repl",
+    description = "Evaluate input in a persistent `nix repl` session that keeps loaded flakes, files and bindings across calls."
+)]
+#[derive(Debug, ::serde::Deserialize, ::serde::Serialize, JsonSchema)]
+pub struct NixReplTool {
+    /// "eval" (default) sends `input` to the session, starting one if needed.
+    /// "create" and "reset" start a fresh session, then send `input` if given.
+    /// "close" ends the session.
+    action: Option<String>,
+
+    /// Repl input: expressions, bindings that persist across calls, and repl
+    /// commands such as `:lf` (load flake), `:l` (load file) and `:t` (type).
+    ///
+    /// Examples: ":lf nixpkgs", "pkgs = import <nixpkgs> {}", ":t pkgs.hello", "pkgs.hello.version", etc.
+    input: Option<String>,
+
+    /// Seconds to wait for `input` before giving up and closing the session
+    /// (default: 300).
+    timeout_seconds: Option<u32>,
+}
+
+impl NixReplTool {
+    pub fn call_tool(&self, session: &str) -> Result<CallToolResult, CallToolError> {
+        let timeout = Duration::from_secs(
+            self.timeout_seconds
+                .map(u64::from)
+                .unwrap_or(DEFAULT_REPL_TIMEOUT_SECS),
+        );
+
+        let text = match self.action.as_deref().unwrap_or("eval") {
+            "eval" => {
+                let input = self.input.as_deref().ok_or_else(|| {
+                    CallToolError::new(Error::other("input is required to eval"))
+                })?;
+                eval_in_session(session, input, timeout).map_err(CallToolError::new)?
+            }
+            action @ ("create" | "reset") => {
+                create_session(session).map_err(CallToolError::new)?;
+                match &self.input {
+                    Some(input) => {
+                        eval_in_session(session, input, timeout).map_err(CallToolError::new)?
+                    }
+                    None if action == "reset" => "Session reset.".to_string(),
+                    None => "Session created.".to_string(),
+                }
+            }
+            "close" => {
+                if close_session(session) {
+                    "Session closed.".to_string()
+                } else {
+                    "No session was open.".to_string()
+                }
+            }
+            other => {
+                return Err(CallToolError::new(Error::other(format!(
+                    r#"unknown action "{}": expected "eval", "create", "reset" or "close""#,
+                    other
+                ))));
+            }
+        };
+
+        Ok(CallToolResult::text_content(vec![TextContent::from(text)]))
+    }
+}
+
+#[mcp_tool(
+    name = "nix_build",
+    description = "Build an installable and return its output paths, or with `dry_run` report what would be built and what would be downloaded. Failed builds return the failing derivation, its log tail and error lines."
+)]
+#[derive(Debug, ::serde::Deserialize, ::serde::Serialize, JsonSchema)]
+pub struct NixBuildTool {
+    /// The installable to build.
+    ///
+    /// Examples: "nixpkgs#hello", ".#packages.x86_64-linux.default", "/nix/store/...-foo.drv^*", etc.
+    installable: String,
+
+    /// Only report what would be built and fetched (`--dry-run`). Allowed in
+    /// every profile; real builds need the build profile.
+    dry_run: Option<bool>,
+
+    /// Allow access to mutable paths and the environment (`--impure`).
+    impure: Option<bool>,
+
+    /// Flake inputs to override, as `input=flakeref` (`--override-input`).
+    override_input: Option<Vec<String>>,
+
+    /// Lines of the failed build's log to return (default: 50).
+    log_lines: Option<u32>,
+}
+
+impl NixBuildTool {
+    pub fn call_tool(&self) -> Result<CallToolResult, CallToolError> {
+        let dry_run = self.dry_run == Some(true);
+        if !dry_run {
+            config::require_profile(Profile::Build, "building").map_err(CallToolError::new)?;
+        }
+
+        // Run: nix build --json --no-link [--dry-run] <installable> [flags]
+        let mut command = nix::command();
+        command.args(["build", "--json", "--no-link", self.installable.as_str()]);
+        if dry_run {
+            command.args(["--dry-run"]);
+        }
+        if self.impure == Some(true) {
+            command.args(["--impure"]);
+        }
+        for override_input in self.override_input.iter().flatten() {
+            let Some((input, flake_ref)) = override_input.split_once('=') else {
+                return Err(CallToolError::new(Error::other(format!(
+                    r#"invalid override_input "{}": expected input=flakeref"#,
+                    override_input
+                ))));
+            };
+            command.args(["--override-input", input, flake_ref]);
+        }
+
+        if !dry_run {
+            progress::report(format!("building {}", self.installable));
+        }
+        let output = command.output().map_err(CallToolError::new)?;
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+
+        if !output.status.success() {
+            let log_lines = self.log_lines.map(|n| n as usize).unwrap_or(DEFAULT_BUILD_LOG_LINES);
+            let triage = triage(&stderr, output.status.code(), log_lines);
+            let pretty = serde_json::to_string_pretty(&triage).map_err(CallToolError::new)?;
+            return Err(CallToolError::new(Error::other(pretty)));
+        }
+
+        let stdout = String::from_utf8(output.stdout).map_err(CallToolError::new)?;
+        let built: serde_json::Value =
+            serde_json::from_str(&stdout).map_err(CallToolError::new)?;
+
+        let result = if dry_run {
+            let mut report = parse_dry_run(&stderr);
+            report["outputs"] = built;
+            report
+        } else {
+            serde_json::json!({ "success": true, "outputs": built })
+        };
+
+        let pretty = serde_json::to_string_pretty(&result).map_err(CallToolError::new)?;
+        Ok(CallToolResult::text_content(vec![TextContent::from(pretty)]))
+    }
+}
+
+#[mcp_tool(
+    name = "nix_hash_mismatch",
+    description = "Extract the specified and actual hashes from a fixed-output hash mismatch and return the edit that fixes the hash in the package's source."
+)]
+#[derive(Debug, ::serde::Deserialize, ::serde::Serialize, JsonSchema)]
+pub struct NixHashMismatchTool {
+    /// Output of a failed build that reported a hash mismatch.
+    output: Option<String>,
+
+    /// The installable whose build fails with a hash mismatch. Its source is
+    /// located via the position of `src` or `meta.position`. Without `output`
+    /// it is built to obtain the mismatch, which needs the build profile.
+    ///
+    /// Examples: ".#my-package", "nixpkgs#hello", etc.
+    installable: Option<String>,
+
+    /// The file holding the hash, if it cannot be located from `installable`.
+    file: Option<String>,
+}
+
+impl NixHashMismatchTool {
+    pub fn call_tool(&self) -> Result<CallToolResult, CallToolError> {
+        let mismatches = match (&self.output, &self.installable) {
+            (Some(output), _) => {
+                let mismatches = parse_mismatches(output);
+                if mismatches.is_empty() {
+                    return Err(CallToolError::new(Error::other(
+                        "no hash mismatch found in the build output",
+                    )));
+                }
+                mismatches
+            }
+            (None, Some(installable)) => {
+                config::require_profile(Profile::Build, "building")
+                    .map_err(CallToolError::new)?;
+                // Run: nix build --no-link <installable>
+                let output = nix::command()
+                    .args(["build", "--no-link", installable.as_str()])
+                    .output()
+                    .map_err(CallToolError::new)?;
+                if output.status.success() {
+                    return Ok(CallToolResult::text_content(vec![TextContent::from(
+                        format!("{} builds without a hash mismatch.", installable),
+                    )]));
+                }
+                let mismatches = parse_mismatches(&String::from_utf8_lossy(&output.stderr));
+                if mismatches.is_empty() {
+                    // The build failed for another reason.
+                    return Err(CallToolError::new(nix_failure("nix build", &output)));
+                }
+                mismatches
+            }
+            (None, None) => {
+                return Err(CallToolError::new(Error::other(
+                    "one of output or installable is required",
+                )));
+            }
+        };
+
+        let mismatch = &mismatches[0];
+
+        let position = match (&self.file, &self.installable) {
+            (Some(file), _) => Some((nix::resolve_path(file), None)),
+            (None, Some(installable)) => {
+                source_position(installable).map_err(CallToolError::new)?
+            }
+            (None, None) => None,
+        };
+        let edit = match &position {
+            Some((file, line)) => find_edit(file, *line, mismatch).map_err(CallToolError::new)?,
+            None => None,
+        };
+
+        let result = serde_json::json!({
+            "mismatches": mismatches,
+            "source": position.map(|(file, line)| match line {
+                Some(line) => format!("{}:{}", file.display(), line),
+                None => file.display().to_string(),
+            }),
+            "edit": edit,
+        });
+        let pretty = serde_json::to_string_pretty(&result).map_err(CallToolError::new)?;
+        Ok(CallToolResult::text_content(vec![TextContent::from(pretty)]))
+    }
+}
+
+#[mcp_tool(
+    name = "nix_prefetch",
+    description = "Fetch a URL, GitHub repository, git repository, crate or flake reference into the store and return its SRI hash with a ready-to-paste fetcher expression."
+)]
+#[derive(Debug, ::serde::Deserialize, ::serde::Serialize, JsonSchema)]
+pub struct NixPrefetchTool {
+    /// A URL to fetch with `fetchurl`, or with `unpack` as `fetchzip`.
+    ///
+    /// Examples: "https://example.org/foo-1.0.tar.gz", "file:///tmp/foo.tar.gz", etc.
+    url: Option<String>,
+
+    /// Unpack the archive at `url` and hash its contents.
+    unpack: Option<bool>,
+
+    /// A GitHub repository as `owner/repo`, for `fetchFromGitHub`.
+    ///
+    /// Examples: "NixOS/nix", "nix-community/home-manager", etc.
+    github: Option<String>,
+
+    /// A git repository URL, for `fetchgit`.
+    ///
+    /// Examples: "https://git.example.org/foo.git", etc.
+    git_url: Option<String>,
+
+    /// The tag, branch or commit of `github` or `git_url` (default: HEAD).
+    /// Tags and branches are resolved to the commit they point at.
+    rev: Option<String>,
+
+    /// A crate name on crates.io, for `fetchCrate`. Needs `version`.
+    crate_name: Option<String>,
+
+    /// The version of `crate_name`.
+    version: Option<String>,
+
+    /// Any other flake reference, for `builtins.fetchTree`.
+    ///
+    /// Examples: "path:/tmp/src", "tarball+https://example.org/foo.tar.gz", etc.
+    flake_ref: Option<String>,
+}
+
+impl NixPrefetchTool {
+    pub fn call_tool(&self) -> Result<CallToolResult, CallToolError> {
+        let mut sources = Vec::new();
+        if let Some(url) = &self.url {
+            sources.push(Source::Url {
+                url: url.clone(),
+                unpack: self.unpack == Some(true),
+            });
+        }
+        if let Some(github) = &self.github {
+            let Some((owner, repo)) = github.split_once('/') else {
+                return Err(CallToolError::new(Error::other(format!(
+                    r#"invalid github "{}": expected owner/repo"#,
+                    github
+                ))));
+            };
+            sources.push(Source::GitHub {
+                owner: owner.to_string(),
+                repo: repo.to_string(),
+                rev: self.rev.clone(),
+            });
+        }
+        if let Some(url) = &self.git_url {
+            sources.push(Source::Git {
+                url: url.clone(),
+                rev: self.rev.clone(),
+            });
+        }
+        if let Some(name) = &self.crate_name {
+            let version = self.version.clone().ok_or_else(|| {
+                CallToolError::new(Error::other("crate_name needs a version"))
+            })?;
+            sources.push(Source::Crate {
+                name: name.clone(),
+                version,
+            });
+        }
+        if let Some(flake_ref) = &self.flake_ref {
+            sources.push(Source::Flake(flake_ref.clone()));
+        }
+
+        let [source] = &sources[..] else {
+            return Err(CallToolError::new(Error::other(
+                "exactly one of url, github, git_url, crate_name or flake_ref is required",
+            )));
+        };
+        let result = prefetch(source).map_err(CallToolError::new)?;
+
+        let pretty = serde_json::to_string_pretty(&result).map_err(CallToolError::new)?;
+        Ok(CallToolResult::text_content(vec![TextContent::from(pretty)]))
+    }
+}
+
+#[mcp_tool(
+    name = "nix_hash_convert",
+    description = "Convert a hash between base16, nix32 (base32), base64 and SRI formats without running nix."
+)]
+#[derive(Debug, ::serde::Deserialize, ::serde::Serialize, JsonSchema)]
+pub struct NixHashConvertTool {
+    /// The hash in any format: SRI, `<algo>:<digest>` or a bare digest.
+    ///
+    /// Examples: "sha256-3OqA/5kH4IyUL0PzLvgKCm2Bw3H1FtbpwZTXdgzQIo0=", "sha256:1b8m03r63zqhnjf7l5wnldhh7c134ap5vpj0850ymkq1iyzicy5s", etc.
+    hash: String,
+
+    /// The hash algorithm, needed only for bare digests of ambiguous length:
+    /// "md5", "sha1", "sha256" or "sha512".
+    algorithm: Option<String>,
+}
+
+impl NixHashConvertTool {
+    pub fn call_tool(&self) -> Result<CallToolResult, CallToolError> {
+        let converted =
+            hash::convert(&self.hash, self.algorithm.as_deref()).map_err(CallToolError::new)?;
+        let pretty = serde_json::to_string_pretty(&converted).map_err(CallToolError::new)?;
+        Ok(CallToolResult::text_content(vec![TextContent::from(pretty)]))
+    }
+}
+
+#[mcp_tool(
+    name = "nix_store_path_parse",
+    description = "Split a store path into store directory, hash, name, package name, version and output using Nix's own name-splitting rules, without running nix."
+)]
+#[derive(Debug, ::serde::Deserialize, ::serde::Serialize, JsonSchema)]
+pub struct NixStorePathParseTool {
+    /// The store path, optionally with a path below it.
+    ///
+    /// Examples: "/nix/store/3qcd8f6r7w3ylsmr01f2s2f0hka2m9m5-hello-2.12.1", "/nix/store/...-openssl-3.0.13-dev/include", etc.
+    path: String,
+}
+
+impl NixStorePathParseTool {
+    pub fn call_tool(&self) -> Result<CallToolResult, CallToolError> {
+        let parsed = store_path::parse(&self.path).map_err(CallToolError::new)?;
+        let pretty = serde_json::to_string_pretty(&parsed).map_err(CallToolError::new)?;
+        Ok(CallToolResult::text_content(vec![TextContent::from(pretty)]))
+    }
+}
+
+#[mcp_tool(
+    name = "nix_derivation_show",
+    description = "Show a summary of the derivation of an installable or `.drv` path: builder, system, outputs, input derivations grouped by package, input sources, environment variables and the stdenv phases that run."
+)]
+#[derive(Debug, ::serde::Deserialize, ::serde::Serialize, JsonSchema)]
+pub struct NixDerivationShowTool {
+    /// The installable or `.drv` path.
+    ///
+    /// Examples: "nixpkgs#hello", "/nix/store/...-hello-2.12.1.drv", etc.
+    installable: String,
+
+    /// Include environment values, not just their names (default: false).
+    env_values: Option<bool>,
+
+    /// The number of characters after which environment values and builder
+    /// arguments such as `buildCommand` are cut off (default: 200).
+    max_value_length: Option<u32>,
+
+    /// Return the derivation exactly as `nix derivation show` prints it
+    /// instead of the summary (default: false).
+    raw: Option<bool>,
+}
+
+impl NixDerivationShowTool {
+    pub fn call_tool(&self) -> Result<CallToolResult, CallToolError> {
+        let derivations =
+            derivation::show_derivations(&self.installable, false).map_err(CallToolError::new)?;
+
+        let result = if self.raw == Some(true) {
+            serde_json::Value::Object(derivations)
+        } else {
+            let max_value_length =
+                self.max_value_length.unwrap_or(DEFAULT_DRV_VALUE_LENGTH) as usize;
+            let summaries: Vec<serde_json::Value> = derivations
+                .iter()
+                .map(|(path, drv)| {
+                    derivation::summarize(
+                        path,
+                        drv,
+                        self.env_values == Some(true),
+                        max_value_length,
+                    )
+                })
+                .collect();
+            match <[serde_json::Value; 1]>::try_from(summaries) {
+                Ok([summary]) => summary,
+                Err(summaries) => serde_json::Value::Array(summaries),
+            }
+        };
+
+        let pretty = serde_json::to_string_pretty(&result).map_err(CallToolError::new)?;
+        Ok(CallToolResult::text_content(vec![TextContent::from(pretty)]))
+    }
+}
+
+#[mcp_tool(
+    name = "nix_derivation_diff",
+    description = "Explain why two installables or `.drv` paths differ: recursively compare their derivations and return a tree of root causes, such as changed environment variables, sources, builder arguments and added or removed inputs."
+)]
+#[derive(Debug, ::serde::Deserialize, ::serde::Serialize, JsonSchema)]
+pub struct NixDerivationDiffTool {
+    /// The first installable or `.drv` path.
+    ///
+    /// Examples: "nixpkgs#hello", "github:NixOS/nixpkgs/nixos-24.11#hello", "/nix/store/...-hello-2.12.1.drv", etc.
+    left: String,
+
+    /// The second installable or `.drv` path.
+    ///
+    /// Examples: "nixpkgs#hello", "github:NixOS/nixpkgs/nixos-25.05#hello", etc.
+    right: String,
+
+    /// The number of characters after which differing values are cut off
+    /// (default: 200).
+    max_value_length: Option<u32>,
+}
+
+impl NixDerivationDiffTool {
+    pub fn call_tool(&self) -> Result<CallToolResult, CallToolError> {
+        let max_value_length = self.max_value_length.unwrap_or(DEFAULT_DRV_VALUE_LENGTH) as usize;
+        let diff = derivation::diff(&self.left, &self.right, max_value_length)
+            .map_err(CallToolError::new)?;
+        let pretty = serde_json::to_string_pretty(&diff).map_err(CallToolError::new)?;
+        Ok(CallToolResult::text_content(vec![TextContent::from(pretty)]))
+    }
+}
+
+#[mcp_tool(
+    name = "nix_path_info",
+    description = "Show the store paths of an installable or store path with their NAR size, closure size, references, deriver, signatures and content address, plus the largest dependencies in the closure."
+)]
+#[derive(Debug, ::serde::Deserialize, ::serde::Serialize, JsonSchema)]
+pub struct NixPathInfoTool {
+    /// The installable or store path.
+    ///
+    /// Examples: "nixpkgs#hello", "/nix/store/...-hello-2.12.1", "/run/current-system", etc.
+    installable: String,
+
+    /// How many of the largest dependencies to list (default: 10, 0 to skip
+    /// querying the closure).
+    top: Option<u32>,
+
+    /// Also list the paths referring to each store path, searched within the
+    /// closure of this installable or store path.
+    ///
+    /// Examples: "/run/current-system", "nixpkgs#hello", etc.
+    referrers_in: Option<String>,
+}
+
+impl NixPathInfoTool {
+    pub fn call_tool(&self) -> Result<CallToolResult, CallToolError> {
+        let roots = path_info::path_info(&self.installable, false).map_err(CallToolError::new)?;
+        let top = self.top.unwrap_or(DEFAULT_LARGEST_PATHS) as usize;
+
+        let mut result: Vec<serde_json::Value> = roots
+            .iter()
+            .map(|(path, info)| path_info::summarize(path, info))
+            .collect();
+
+        if top > 0 {
+            let closure =
+                path_info::path_info(&self.installable, true).map_err(CallToolError::new)?;
+            let root_paths = roots.keys().map(String::as_str).collect();
+            let largest = path_info::largest(&closure, &root_paths, top);
+            for summary in &mut result {
+                summary["closure_paths"] = closure.len().into();
+                summary["largest_dependencies"] = largest.clone().into();
+            }
+        }
+
+        if let Some(scope) = &self.referrers_in {
+            let closure = path_info::path_info(scope, true).map_err(CallToolError::new)?;
+            for (summary, path) in result.iter_mut().zip(roots.keys()) {
+                summary["referrers"] = path_info::referrers(&closure, path).into();
+            }
+        }
+
+        let result = match <[serde_json::Value; 1]>::try_from(result) {
+            Ok([summary]) => summary,
+            Err(summaries) => serde_json::Value::Array(summaries),
+        };
+        let pretty = serde_json::to_string_pretty(&result).map_err(CallToolError::new)?;
+        Ok(CallToolResult::text_content(vec![TextContent::from(pretty)]))
+    }
+}
+
+#[mcp_tool(
+    name = "nix_closure_graph",
+    description = "Export the runtime dependency graph of an installable or store path as a nix-tree-like text tree of what adds most to the closure, JSON nodes and edges, or Graphviz DOT, with per-path and closure sizes."
+)]
+#[derive(Debug, ::serde::Deserialize, ::serde::Serialize, JsonSchema)]
+pub struct NixClosureGraphTool {
+    /// The installable or store path.
+    ///
+    /// Examples: "nixpkgs#hello", "/nix/store/...-docker-image-foo.tar.gz", "/run/current-system", etc.
+    installable: String,
+
+    /// The output format: "tree" (default), "json" or "dot".
+    format: Option<String>,
+
+    /// How many levels of dependencies to include (default: 3 for "tree",
+    /// unlimited otherwise).
+    max_depth: Option<u32>,
+}
+
+impl NixClosureGraphTool {
+    pub fn call_tool(&self) -> Result<CallToolResult, CallToolError> {
+        let format = self.format.as_deref().unwrap_or("tree");
+        if !["tree", "json", "dot"].contains(&format) {
+            return Err(CallToolError::new(Error::other(format!(
+                r#"unknown format "{}": expected "tree", "json" or "dot""#,
+                format
+            ))));
+        }
+
+        let roots = path_info::path_info(&self.installable, false).map_err(CallToolError::new)?;
+        let closure = path_info::path_info(&self.installable, true).map_err(CallToolError::new)?;
+        let roots: Vec<&str> = roots.keys().map(String::as_str).collect();
+        let graph = closure::Graph::new(&closure, &roots);
+
+        let max_depth = self.max_depth.map(|depth| depth as usize);
+        let text = match format {
+            "json" => serde_json::to_string_pretty(&graph.to_json(max_depth))
+                .map_err(CallToolError::new)?,
+            "dot" => graph.to_dot(max_depth),
+            _ => graph.to_tree(Some(max_depth.unwrap_or(DEFAULT_TREE_DEPTH as usize))),
+        };
+        Ok(CallToolResult::text_content(vec![TextContent::from(text)]))
+    }
+}
+
+#[mcp_tool(
+    name = "nix_store_diff_closures",
+    description = "Compare two closures, such as two system generations or two package versions, and return the added and removed packages, version changes and size deltas as JSON."
+)]
+#[derive(Debug, ::serde::Deserialize, ::serde::Serialize, JsonSchema)]
+pub struct NixStoreDiffClosuresTool {
+    /// The old installable, store path or generation number of `profile`.
+    ///
+    /// Examples: "41", "/nix/var/nix/profiles/system-41-link", "nixpkgs/nixos-24.11#hello", etc.
+    before: String,
+
+    /// The new installable, store path or generation number of `profile`.
+    ///
+    /// Examples: "42", "/run/current-system", "nixpkgs/nixos-25.05#hello", etc.
+    after: String,
+
+    /// The profile whose generations `before` and `after` number (default:
+    /// "/nix/var/nix/profiles/system").
+    ///
+    /// Examples: "/nix/var/nix/profiles/per-user/alice/home-manager", "~/.local/state/nix/profiles/profile", etc.
+    profile: Option<String>,
+}
+
+impl NixStoreDiffClosuresTool {
+    pub fn call_tool(&self) -> Result<CallToolResult, CallToolError> {
+        // Generations are numbered links next to the profile, e.g.
+        // `/nix/var/nix/profiles/system-42-link`.
+        let resolve = |closure: &str| -> String {
+            if !closure.is_empty() && closure.chars().all(|c| c.is_ascii_digit()) {
+                let profile = self.profile.as_deref().unwrap_or(DEFAULT_SYSTEM_PROFILE);
+                let profile = match (profile.strip_prefix("~/"), std::env::var("HOME")) {
+                    (Some(rest), Ok(home)) => format!("{}/{}", home, rest),
+                    _ => profile.to_string(),
+                };
+                format!("{}-{}-link", profile, closure)
+            } else {
+                closure.to_string()
+            }
+        };
+        let (before, after) = (resolve(&self.before), resolve(&self.after));
+
+        let before_closure = path_info::path_info(&before, true).map_err(CallToolError::new)?;
+        let after_closure = path_info::path_info(&after, true).map_err(CallToolError::new)?;
+        let mut diff = closure::diff(&before_closure, &after_closure);
+        diff["before"] = before.into();
+        diff["after"] = after.into();
+
+        let pretty = serde_json::to_string_pretty(&diff).map_err(CallToolError::new)?;
+        Ok(CallToolResult::text_content(vec![TextContent::from(pretty)]))
+    }
+}
+
+#[mcp_tool(
+    name = "nix_store_ls",
+    description = "List the files inside the store path of an installable or store path, such as the binaries in `bin/` or the contents of `share/`, with their type, size and symlink target."
+)]
 #[derive(Debug, ::serde::Deserialize, ::serde::Serialize, JsonSchema)]
-pub struct NixEvaluateTool {
-    /// The Nix expression to evaluate.
+pub struct NixStoreLsTool {
+    /// The installable or store path.
     ///
-    /// Examples: "nixpkgs#lib.version", etc.
-    expression: String,
+    /// Examples: "nixpkgs#hello", "/nix/store/...-hello-2.12.1", etc.
+    installable: String,
+
+    /// The path inside the store path to list (default: its root).
+    ///
+    /// Examples: "share/man", "lib/pkgconfig", etc.
+    path: Option<String>,
+
+    /// List the `bin/` directory (default: false).
+    bin: Option<bool>,
+
+    /// Show the main program in `bin/`, from `meta.mainProgram` or the
+    /// package name (default: false).
+    main_program: Option<bool>,
+
+    /// List all subdirectories too (default: false).
+    recursive: Option<bool>,
+
+    /// How many entries to return at most (default: 500).
+    max_entries: Option<u32>,
+
+    /// Build or substitute the package if it is not in the store yet, which
+    /// needs the `build` profile (default: false).
+    realise: Option<bool>,
 }
 
-impl NixEvaluateTool {
+impl NixStoreLsTool {
     pub fn call_tool(&self) -> Result<CallToolResult, CallToolError> {
-        // Run: nix eval --json <expression>
-        let output = Command::new("nix")
-            .args([
-                "--extra-experimental-features",
-                "nix-command flakes",
-                "eval",
-                "--json",
-                self.expression.as_str(),
-            ])
-            .output()
+        let path = store_files::locate(
+            &self.installable,
+            self.path.as_deref(),
+            self.bin == Some(true),
+            self.main_program == Some(true),
+            self.realise == Some(true),
+        )
+        .map_err(CallToolError::new)?;
+        let max_entries = self.max_entries.unwrap_or(DEFAULT_LS_ENTRIES) as usize;
+        let listing = store_files::list(&path, self.recursive == Some(true), max_entries)
             .map_err(CallToolError::new)?;
 
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-            let err = Error::other(format!(
-                r#"nix eval (status: {}): {}"#,
-                output.status, stderr
-            ));
-            return Err(CallToolError::new(err));
-        }
+        let pretty = serde_json::to_string_pretty(&listing).map_err(CallToolError::new)?;
+        Ok(CallToolResult::text_content(vec![TextContent::from(pretty)]))
+    }
+}
 
-        let stdout = String::from_utf8(output.stdout).map_err(CallToolError::new)?;
-        let json_val: serde_json::Value =
-            serde_json::from_str(&stdout).map_err(CallToolError::new)?;
+#[mcp_tool(
+    name = "nix_store_cat",
+    description = "Read a file inside the store path of an installable or store path, such as a wrapper script, desktop file or default configuration."
+)]
+#[derive(Debug, ::serde::Deserialize, ::serde::Serialize, JsonSchema)]
+pub struct NixStoreCatTool {
+    /// The installable or store path.
+    ///
+    /// Examples: "nixpkgs#hello", "/nix/store/...-hello-2.12.1", etc.
+    installable: String,
 
-        let pretty = serde_json::to_string_pretty(&json_val).map_err(CallToolError::new)?;
+    /// The file inside the store path to read. Not needed with
+    /// `main_program`.
+    ///
+    /// Examples: "share/applications/firefox.desktop", "bin/firefox", etc.
+    path: Option<String>,
 
-        Ok(CallToolResult::text_content(vec![TextContent::from(
-            pretty,
-        )]))
+    /// Read the main program in `bin/`, from `meta.mainProgram` or the
+    /// package name, e.g. to inspect a wrapper script (default: false).
+    main_program: Option<bool>,
+
+    /// How many bytes to return at most (default: 65536).
+    max_bytes: Option<u32>,
+
+    /// Build or substitute the package if it is not in the store yet, which
+    /// needs the `build` profile (default: false).
+    realise: Option<bool>,
+}
+
+impl NixStoreCatTool {
+    pub fn call_tool(&self) -> Result<CallToolResult, CallToolError> {
+        let main_program = self.main_program == Some(true);
+        if self.path.is_none() && !main_program {
+            return Err(CallToolError::new(Error::other(
+                "either path or main_program is required",
+            )));
+        }
+        let path = store_files::locate(
+            &self.installable,
+            self.path.as_deref(),
+            false,
+            main_program,
+            self.realise == Some(true),
+        )
+        .map_err(CallToolError::new)?;
+        let max_bytes = self.max_bytes.unwrap_or(DEFAULT_CAT_BYTES) as usize;
+        let contents = store_files::read(&path, max_bytes).map_err(CallToolError::new)?;
+
+        Ok(CallToolResult::text_content(vec![TextContent::from(contents)]))
     }
 }
 
-#[mcp_tool(name = "nix_log", description = "Evaluate a Nix expression.")]
+#[mcp_tool(
+    name = "nix_cache_check",
+    description = "Check whether an installable is available from the binary caches or would be built from source, by querying the substituters for `.narinfo` files. Reports download and NAR sizes and which derivations would be built locally."
+)]
 #[derive(Debug, ::serde::Deserialize, ::serde::Serialize, JsonSchema)]
-pub struct NixLogTool {
-    /// The name of the installable to get the build log for.
+pub struct NixCacheCheckTool {
+    /// The installable or `.drv` path.
     ///
-    /// Examples: "nixpkgs", "github:owner/repo", "gitlab:owner/repo", etc.
+    /// Examples: "nixpkgs#hello", "github:NixOS/nixpkgs/nixos-25.05#firefox", etc.
     installable: String,
+
+    /// The binary caches to query (default: the configured `substituters`).
+    /// `file://`, `http://` and `https://` caches are supported.
+    ///
+    /// Examples: ["https://cache.nixos.org"], ["file:///tmp/cache"], etc.
+    substituters: Option<Vec<String>>,
+
+    /// How many store paths to look up at most (default: 2000).
+    max_queries: Option<u32>,
 }
 
-impl NixLogTool {
+impl NixCacheCheckTool {
     pub fn call_tool(&self) -> Result<CallToolResult, CallToolError> {
-        // Run: nix search --json <installable> <regex>
-        let output = Command::new("nix")
-            .args([
-                "--extra-experimental-features",
-                "nix-command flakes",
-                "log",
-                self.installable.as_str(),
-            ])
-            .output()
+        let substituters = match &self.substituters {
+            Some(substituters) => substituters.clone(),
+            None => binary_cache::configured_substituters().map_err(CallToolError::new)?,
+        };
+        let max_queries = self.max_queries.unwrap_or(DEFAULT_CACHE_QUERIES) as usize;
+        let result = binary_cache::check(&self.installable, &substituters, max_queries)
             .map_err(CallToolError::new)?;
 
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-            let err = Error::other(format!(
-                r#"nix log (status: {}): {}"#,
-                output.status, stderr
-            ));
-            return Err(CallToolError::new(err));
-        }
-
-        let stdout = String::from_utf8(output.stdout).map_err(CallToolError::new)?;
-        let json_val: serde_json::Value =
-            serde_json::from_str(&stdout).map_err(CallToolError::new)?;
-
-        let pretty = serde_json::to_string_pretty(&json_val).map_err(CallToolError::new)?;
-
-        Ok(CallToolResult::text_content(vec![TextContent::from(
-            pretty,
-        )]))
+        let pretty = serde_json::to_string_pretty(&result).map_err(CallToolError::new)?;
+        Ok(CallToolResult::text_content(vec![TextContent::from(pretty)]))
     }
 }
 
@@ -125,37 +1024,41 @@ pub struct NixPackagesSearchTool {
 
 impl NixPackagesSearchTool {
     pub fn call_tool(&self) -> Result<CallToolResult, CallToolError> {
+        // A prebuilt index only stands in for a live search: offline, or when
+        // nix search fails.
+        let indexed = || search_indexed_packages(&self.installable, &self.regex);
+        let offline_result = if config::get().offline { indexed() } else { None };
+        let json_val = match offline_result {
+            Some(result) => result,
+            None => self.search().or_else(|e| indexed().unwrap_or(Err(e))),
+        }
+        .map_err(CallToolError::new)?;
+
+        let pretty = serde_json::to_string_pretty(&json_val).map_err(CallToolError::new)?;
+
+        Ok(CallToolResult::text_content(vec![TextContent::from(
+            pretty,
+        )]))
+    }
+
+    fn search(&self) -> Result<serde_json::Value, Error> {
         // Run: nix search --json <installable> <regex>
-        let output = Command::new("nix")
+        let output = nix::command()
             .args([
-                "--extra-experimental-features",
-                "nix-command flakes",
                 "search",
                 "--json",
                 self.installable.as_str(),
                 self.regex.as_str(),
             ])
-            .output()
-            .map_err(CallToolError::new)?;
+            .output()?;
 
         if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-            let err = Error::other(format!(
-                r#"nix search failed (status: {}): {}"#,
-                output.status, stderr
-            ));
-            return Err(CallToolError::new(err));
+            return Err(nix_failure("nix search", &output));
         }
 
-        let stdout = String::from_utf8(output.stdout).map_err(CallToolError::new)?;
-        let json_val: serde_json::Value =
-            serde_json::from_str(&stdout).map_err(CallToolError::new)?;
-
-        let pretty = serde_json::to_string_pretty(&json_val).map_err(CallToolError::new)?;
-
-        Ok(CallToolResult::text_content(vec![TextContent::from(
-            pretty,
-        )]))
+        let stdout = String::from_utf8(output.stdout).map_err(Error::other)?;
+        serde_json::from_str(&stdout)
+            .map_err(|e| Error::other(format!("failed to parse nix output: {}", e)))
     }
 }
 
@@ -179,10 +1082,8 @@ pub struct NixPackagesWhyDepends {
 impl NixPackagesWhyDepends {
     pub fn call_tool(&self) -> Result<CallToolResult, CallToolError> {
         // Run: nix why-depends --all <package> <dependency>
-        let output = Command::new("nix")
+        let output = nix::command()
             .args([
-                "--extra-experimental-features",
-                "nix-command flakes",
                 "why-depends",
                 "--all",
                 self.package.as_str(),
@@ -192,12 +1093,7 @@ impl NixPackagesWhyDepends {
             .map_err(CallToolError::new)?;
 
         if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-            let err = Error::other(format!(
-                r#"nix why-depends failed (status: {}): {}"#,
-                output.status, stderr
-            ));
-            return Err(CallToolError::new(err));
+            return Err(CallToolError::new(nix_failure("nix why-depends", &output)));
         }
 
         let stdout = String::from_utf8(output.stdout).map_err(CallToolError::new)?;
@@ -209,7 +1105,7 @@ impl NixPackagesWhyDepends {
 
 #[mcp_tool(
     name = "nix_flakes_show",
-    description = "Show the outputs provided by a given flake."
+    description = "Show the outputs provided by a given flake as a compact list of attribute paths with their type and, for packages, name and description. Can show only the outputs below an attribute path, one level at a time (which also works for legacyPackages), or for a single system."
 )]
 #[derive(Debug, ::serde::Deserialize, ::serde::Serialize, JsonSchema)]
 pub struct NixFlakesShowTool {
@@ -217,41 +1113,44 @@ pub struct NixFlakesShowTool {
     ///
     /// Examples: "github:neuro-soup/evochi", "/path/to/nixos/flake/dir", etc.
     flake: String,
-}
 
-impl NixFlakesShowTool {
-    pub fn call_tool(&self) -> Result<CallToolResult, CallToolError> {
-        // Run: nix flake show --json <flake>
-        let output = Command::new("nix")
-            .args([
-                "--extra-experimental-features",
-                "nix-command flakes",
-                "flake",
-                "show",
-                "--json",
-                self.flake.as_str(),
-            ])
-            .output()
-            .map_err(CallToolError::new)?;
+    /// Only show the outputs below this attribute path. Only this attribute
+    /// is evaluated, so this is fast even for large package sets.
+    ///
+    /// Examples: "packages.x86_64-linux", "legacyPackages.x86_64-linux.python3Packages", etc.
+    attr_path: Option<String>,
 
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-            let err = Error::other(format!(
-                r#"nix flake show (status: {}): {}"#,
-                output.status, stderr
-            ));
-            return Err(CallToolError::new(err));
-        }
+    /// Only show outputs for this system.
+    ///
+    /// Examples: "x86_64-linux", "aarch64-darwin", etc.
+    system: Option<String>,
 
-        let stdout = String::from_utf8(output.stdout).map_err(CallToolError::new)?;
-        let json_val: serde_json::Value =
-            serde_json::from_str(&stdout).map_err(CallToolError::new)?;
+    /// Show outputs for all systems, not only the current one (default: false).
+    all_systems: Option<bool>,
 
-        let pretty = serde_json::to_string_pretty(&json_val).map_err(CallToolError::new)?;
+    /// How many levels of attributes to list; deeper attribute sets are
+    /// summarized by their number of attributes (default: 1 with
+    /// `attr_path`, unlimited otherwise).
+    max_depth: Option<u32>,
 
-        Ok(CallToolResult::text_content(vec![TextContent::from(
-            pretty,
-        )]))
+    /// Also show the contents of `legacyPackages` (default: false). This is
+    /// very slow for nixpkgs; prefer `attr_path`.
+    legacy: Option<bool>,
+}
+
+impl NixFlakesShowTool {
+    pub fn call_tool(&self) -> Result<CallToolResult, CallToolError> {
+        let options = ShowOptions {
+            attr_path: self.attr_path.clone().filter(|path| !path.is_empty()),
+            system: self.system.clone(),
+            all_systems: self.all_systems == Some(true),
+            max_depth: self.max_depth.map(|depth| depth as usize),
+            legacy: self.legacy == Some(true),
+        };
+        let outputs = flake_show::show(&self.flake, &options).map_err(CallToolError::new)?;
+
+        let pretty = serde_json::to_string_pretty(&outputs).map_err(CallToolError::new)?;
+        Ok(CallToolResult::text_content(vec![TextContent::from(pretty)]))
     }
 }
 
@@ -267,10 +1166,8 @@ pub struct NixFlakesMetadataTool {
 impl NixFlakesMetadataTool {
     pub fn call_tool(&self) -> Result<CallToolResult, CallToolError> {
         // Run: nix flake metadata --json <flake>
-        let output = Command::new("nix")
+        let output = nix::command()
             .args([
-                "--extra-experimental-features",
-                "nix-command flakes",
                 "flake",
                 "metadata",
                 "--json",
@@ -280,12 +1177,7 @@ impl NixFlakesMetadataTool {
             .map_err(CallToolError::new)?;
 
         if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-            let err = Error::other(format!(
-                r#"nix flake metadata (status: {}): {}"#,
-                output.status, stderr
-            ));
-            return Err(CallToolError::new(err));
+            return Err(CallToolError::new(nix_failure("nix flake metadata", &output)));
         }
 
         let stdout = String::from_utf8(output.stdout).map_err(CallToolError::new)?;
@@ -300,6 +1192,205 @@ impl NixFlakesMetadataTool {
     }
 }
 
+#[mcp_tool(
+    name = "nix_flakes_check",
+    description = "Run `nix flake check` on a flake and return the result of every check, grouped by flake output, with the errors and build log tails of failed checks. Only evaluates the checks unless `build` is set."
+)]
+#[derive(Debug, ::serde::Deserialize, ::serde::Serialize, JsonSchema)]
+pub struct NixFlakesCheckTool {
+    /// The flake to check (default: ".").
+    ///
+    /// Examples: ".", "/path/to/flake", "github:owner/repo", etc.
+    flake: Option<String>,
+
+    /// Build the checks too, which needs the `build` profile (default: false,
+    /// only evaluate them).
+    build: Option<bool>,
+
+    /// The system to check outputs for (default: the current system).
+    ///
+    /// Examples: "x86_64-linux", "aarch64-darwin", etc.
+    system: Option<String>,
+
+    /// Check the outputs of all systems (default: false).
+    all_systems: Option<bool>,
+
+    /// Continue after the first failed check (default: true).
+    keep_going: Option<bool>,
+
+    /// How many lines of a failed build's log to return (default: 50).
+    log_lines: Option<u32>,
+}
+
+impl NixFlakesCheckTool {
+    pub fn call_tool(&self) -> Result<CallToolResult, CallToolError> {
+        let build = self.build == Some(true);
+        if build {
+            config::require_profile(Profile::Build, "building flake checks")
+                .map_err(CallToolError::new)?;
+        }
+
+        let flake = self.flake.as_deref().unwrap_or(".");
+        let options = CheckOptions {
+            build,
+            system: self.system.clone(),
+            all_systems: self.all_systems == Some(true),
+            keep_going: self.keep_going != Some(false),
+            log_lines: self.log_lines.map_or(DEFAULT_BUILD_LOG_LINES, |n| n as usize),
+        };
+        progress::report(format!("checking {}", flake));
+        let result = flake_check::check(flake, &options).map_err(CallToolError::new)?;
+
+        let pretty = serde_json::to_string_pretty(&result).map_err(CallToolError::new)?;
+        if result["success"] != true {
+            return Err(CallToolError::new(Error::other(pretty)));
+        }
+        Ok(CallToolResult::text_content(vec![TextContent::from(pretty)]))
+    }
+}
+
+#[mcp_tool(
+    name = "nix_flakes_lock",
+    description = "Inspect a flake's lock file: every input with its locked revision and age, resolved `follows`, duplicate copies of the same flake (such as several nixpkgs), and the `follows` lines that would remove them."
+)]
+#[derive(Debug, ::serde::Deserialize, ::serde::Serialize, JsonSchema)]
+pub struct NixFlakesLockTool {
+    /// The flake whose lock file to inspect (default: ".").
+    ///
+    /// Examples: ".", "/path/to/flake", "github:owner/repo", etc.
+    flake: Option<String>,
+
+    /// Mark inputs last modified more than this many days ago as stale
+    /// (default: 30).
+    stale_after_days: Option<u32>,
+}
+
+impl NixFlakesLockTool {
+    pub fn call_tool(&self) -> Result<CallToolResult, CallToolError> {
+        let flake = self.flake.as_deref().unwrap_or(".");
+        let lock = flake_lock::read_lock(flake).map_err(CallToolError::new)?;
+        let stale_after_days = self.stale_after_days.unwrap_or(DEFAULT_STALE_DAYS);
+        let report =
+            flake_lock::inspect(&lock, u64::from(stale_after_days)).map_err(CallToolError::new)?;
+
+        let pretty = serde_json::to_string_pretty(&report).map_err(CallToolError::new)?;
+        Ok(CallToolResult::text_content(vec![TextContent::from(pretty)]))
+    }
+}
+
+#[mcp_tool(
+    name = "nix_flakes_update_preview",
+    description = "Preview updating a flake's inputs without touching its lock file: the old and new revision and date of every changed input, with GitHub compare URLs. Optionally compares the name, store path and closure of a flake output before and after."
+)]
+#[derive(Debug, ::serde::Deserialize, ::serde::Serialize, JsonSchema)]
+pub struct NixFlakesUpdatePreviewTool {
+    /// The flake whose inputs to update (default: ".").
+    ///
+    /// Examples: ".", "/path/to/flake", "github:owner/repo", etc.
+    flake: Option<String>,
+
+    /// The inputs to update (default: all inputs).
+    ///
+    /// Examples: ["nixpkgs"], ["home-manager", "nixpkgs"], etc.
+    inputs: Option<Vec<String>>,
+
+    /// A flake output attribute to compare before and after the update.
+    ///
+    /// Examples: "packages.x86_64-linux.default",
+    /// "nixosConfigurations.host.config.system.build.toplevel", etc.
+    output: Option<String>,
+
+    /// Also realise both versions of `output` and compare their closures
+    /// (default: false). Needs the `build` profile.
+    closure: Option<bool>,
+}
+
+impl NixFlakesUpdatePreviewTool {
+    pub fn call_tool(&self) -> Result<CallToolResult, CallToolError> {
+        let flake = self.flake.as_deref().unwrap_or(".");
+        let inputs = self.inputs.clone().unwrap_or_default();
+        let preview = flake_update::preview(
+            flake,
+            &inputs,
+            self.output.as_deref(),
+            self.closure == Some(true),
+        )
+        .map_err(CallToolError::new)?;
+
+        let pretty = serde_json::to_string_pretty(&preview).map_err(CallToolError::new)?;
+        Ok(CallToolResult::text_content(vec![TextContent::from(pretty)]))
+    }
+}
+
+#[mcp_tool(
+    name = "nix_registry_list",
+    description = "List the entries of the user, system and global flake registries, marking entries hidden by a registry nix consults first."
+)]
+#[derive(Debug, ::serde::Deserialize, ::serde::Serialize, JsonSchema)]
+pub struct NixRegistryListTool {
+    /// Only list the entries of this registry: "user", "system" or "global".
+    registry: Option<String>,
+}
+
+impl NixRegistryListTool {
+    pub fn call_tool(&self) -> Result<CallToolResult, CallToolError> {
+        let mut entries = registry::list().map_err(CallToolError::new)?;
+        if let Some(name) = &self.registry {
+            entries.retain(|entry| entry.registry == *name);
+        }
+
+        let pretty = serde_json::to_string_pretty(&entries).map_err(CallToolError::new)?;
+        Ok(CallToolResult::text_content(vec![TextContent::from(pretty)]))
+    }
+}
+
+#[mcp_tool(
+    name = "nix_registry_resolve",
+    description = "Resolve a flake reference such as `nixpkgs` through the flake registries: the registry entry used, the URL it resolves to and the revision and date it locks to."
+)]
+#[derive(Debug, ::serde::Deserialize, ::serde::Serialize, JsonSchema)]
+pub struct NixRegistryResolveTool {
+    /// The flake reference to resolve.
+    ///
+    /// Examples: "nixpkgs", "home-manager", "nixpkgs/nixos-24.05", etc.
+    reference: String,
+}
+
+impl NixRegistryResolveTool {
+    pub fn call_tool(&self) -> Result<CallToolResult, CallToolError> {
+        let resolved = registry::resolve(&self.reference).map_err(CallToolError::new)?;
+
+        let pretty = serde_json::to_string_pretty(&resolved).map_err(CallToolError::new)?;
+        Ok(CallToolResult::text_content(vec![TextContent::from(pretty)]))
+    }
+}
+
+#[mcp_tool(
+    name = "nix_registry_pin",
+    description = "Pin a flake reference in the user registry to its current revision, or to a given flake reference, with `nix registry pin`. Needs the `write` profile."
+)]
+#[derive(Debug, ::serde::Deserialize, ::serde::Serialize, JsonSchema)]
+pub struct NixRegistryPinTool {
+    /// The flake reference to pin.
+    ///
+    /// Examples: "nixpkgs", "home-manager", etc.
+    reference: String,
+
+    /// The flake reference to pin it to (default: its current revision).
+    ///
+    /// Examples: "github:NixOS/nixpkgs/nixos-24.05", etc.
+    to: Option<String>,
+}
+
+impl NixRegistryPinTool {
+    pub fn call_tool(&self) -> Result<CallToolResult, CallToolError> {
+        let pinned = registry::pin(&self.reference, self.to.as_deref()).map_err(CallToolError::new)?;
+
+        let pretty = serde_json::to_string_pretty(&pinned).map_err(CallToolError::new)?;
+        Ok(CallToolResult::text_content(vec![TextContent::from(pretty)]))
+    }
+}
+
 #[mcp_tool(
     name = "nix_config_check",
     description = "Check your system for potential problems and print a PASS or FAIL for each check."
@@ -310,10 +1401,8 @@ pub struct NixConfigCheckTool {}
 impl NixConfigCheckTool {
     pub fn call_tool(&self) -> Result<CallToolResult, CallToolError> {
         // Run: nix config check --json <flake>
-        let output = Command::new("nix")
+        let output = nix::command()
             .args([
-                "--extra-experimental-features",
-                "nix-command flakes",
                 "config",
                 "check",
             ])
@@ -321,12 +1410,7 @@ impl NixConfigCheckTool {
             .map_err(CallToolError::new)?;
 
         if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-            let err = Error::other(format!(
-                r#"nix config check (status: {}): {}"#,
-                output.status, stderr
-            ));
-            return Err(CallToolError::new(err));
+            return Err(CallToolError::new(nix_failure("nix config check", &output)));
         }
 
         let stdout = String::from_utf8(output.stdout).map_err(CallToolError::new)?;
@@ -343,10 +1427,8 @@ pub struct NixConfigShowTool {}
 impl NixConfigShowTool {
     pub fn call_tool(&self) -> Result<CallToolResult, CallToolError> {
         // Run: nix config show
-        let output = Command::new("nix")
+        let output = nix::command()
             .args([
-                "--extra-experimental-features",
-                "nix-command flakes",
                 "config",
                 "show",
             ])
@@ -354,12 +1436,7 @@ impl NixConfigShowTool {
             .map_err(CallToolError::new)?;
 
         if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-            let err = Error::other(format!(
-                r#"nix config show (status: {}): {}"#,
-                output.status, stderr
-            ));
-            return Err(CallToolError::new(err));
+            return Err(CallToolError::new(nix_failure("nix config show", &output)));
         }
 
         let stdout = String::from_utf8(output.stdout).map_err(CallToolError::new)?;
@@ -377,8 +1454,12 @@ impl NixConfigShowTool {
 pub struct NixOSChannelsTool {}
 
 impl NixOSChannelsTool {
-    fn is_available(pattern: &str) -> Result<Option<u64>, CallToolError> {
+    /// Returns the number of documents in the index for `pattern`, or `None`
+    /// if the index does not exist or is empty. Fails if the search backend
+    /// cannot be reached.
+    fn is_available(pattern: &str) -> Result<Option<u64>, Error> {
         let url = format!("{}/{}/_count", NIXOS_API_BASE, pattern);
+        let _permit = limits::http().acquire()?;
         let resp = ureq::post(&url)
             .set("Authorization", AUTH_BASIC_B64)
             .set("Content-Type", "application/json")
@@ -397,27 +1478,45 @@ impl NixOSChannelsTool {
                 }
                 Ok(None)
             }
-            Err(_) => Ok(None),
+            // Indices of channels that do not exist answer with an error status.
+            Err(ureq::Error::Status(_, _)) => Ok(None),
+            Err(e) => Err(Error::other(e.to_string())),
         }
     }
 
     pub fn call_tool(&self) -> Result<CallToolResult, CallToolError> {
-        let mut available: BTreeMap<String, u64> = BTreeMap::new();
-        for &generation in &NIXOS_GENERATIONS {
-            for &version in &NIXOS_VERSIONS {
-                let pattern = format!("latest-{generation}-nixos-{version}");
-                if let Ok(Some(count)) = Self::is_available(&pattern) {
-                    available.insert(pattern, count);
+        let pretty = cached("nixos_channels", "available", || {
+            let mut available: BTreeMap<String, u64> = BTreeMap::new();
+            let mut answered = false;
+            let mut last_error = None;
+            for &generation in &NIXOS_GENERATIONS {
+                for &version in &NIXOS_VERSIONS {
+                    let pattern = format!("latest-{generation}-nixos-{version}");
+                    match Self::is_available(&pattern) {
+                        Ok(count) => {
+                            answered = true;
+                            if let Some(count) = count {
+                                available.insert(pattern, count);
+                            }
+                        }
+                        Err(e) => last_error = Some(e),
+                    }
                 }
             }
-        }
+            // Without a single answer nothing is known about the channels;
+            // failing keeps the last cached answer.
+            if !answered && let Some(e) = last_error {
+                return Err(e);
+            }
 
-        let result = serde_json::json!({
-            "available": available,
-            "generations": NIXOS_GENERATIONS,
-            "versions": NIXOS_VERSIONS
-        });
-        let pretty = serde_json::to_string_pretty(&result).map_err(CallToolError::new)?;
+            let result = serde_json::json!({
+                "available": available,
+                "generations": NIXOS_GENERATIONS,
+                "versions": NIXOS_VERSIONS
+            });
+            serde_json::to_string_pretty(&result).map_err(Error::other)
+        })
+        .map_err(CallToolError::new)?;
 
         Ok(CallToolResult::text_content(vec![TextContent::from(
             pretty,
@@ -434,65 +1533,7 @@ pub struct NixManualListTool {}
 
 impl NixManualListTool {
     pub fn call_tool(&self) -> Result<CallToolResult, CallToolError> {
-        let tree_url = "https://api.github.com/repos/NixOS/nix/git/trees/master?recursive=1";
-        let tree_resp = ureq::get(tree_url)
-            .set(
-                "User-Agent",
-                "rime/1.0 (+https://github.com/lukasl-dev/rime)",
-            )
-            .call()
-            .map_err(CallToolError::new)?;
-
-        let status = tree_resp.status();
-        let status_text = tree_resp.status_text().to_string();
-        let tree_body = tree_resp.into_string().map_err(CallToolError::new)?;
-
-        let tree_json: serde_json::Value = match serde_json::from_str(&tree_body) {
-            Ok(v) => v,
-            Err(e) => {
-                let preview = if tree_body.len() > 500 {
-                    &tree_body[..500]
-                } else {
-                    &tree_body
-                };
-                let err = Error::other(format!(
-                    "GitHub trees API returned invalid json (status {} {}): {}. Body preview: {}",
-                    status, status_text, e, preview
-                ));
-                return Err(CallToolError::new(err));
-            }
-        };
-
-        let Some(items) = tree_json.get("tree").and_then(|v| v.as_array()) else {
-            let pretty =
-                serde_json::to_string_pretty(&tree_json).unwrap_or_else(|_| tree_body.clone());
-            let err = Error::other(format!(
-                "GitHub trees API response missing 'tree' array. Response: {}",
-                pretty
-            ));
-            return Err(CallToolError::new(err));
-        };
-
-        let prefix = "doc/manual/source/";
-        let mut md_files: Vec<String> = items
-            .iter()
-            .filter_map(|item| {
-                let path = item.get("path")?.as_str()?;
-                let kind = item.get("type")?.as_str()?;
-                if kind == "blob" && path.starts_with(prefix) && path.ends_with(".md") {
-                    // Strip prefix and extension
-                    let without_prefix = &path[prefix.len()..];
-                    let without_ext = without_prefix.strip_suffix(".md").unwrap_or(without_prefix);
-                    Some(without_ext.to_string())
-                } else {
-                    None
-                }
-            })
-            .collect();
-
-        md_files.sort();
-        md_files.dedup();
-
+        let md_files = list_nix_manual().map_err(CallToolError::new)?;
         let pretty = serde_json::to_string_pretty(&md_files).map_err(CallToolError::new)?;
         Ok(CallToolResult::text_content(vec![TextContent::from(
             pretty,
@@ -514,14 +1555,7 @@ pub struct NixManualReadTool {
 
 impl NixManualReadTool {
     pub fn call_tool(&self) -> Result<CallToolResult, CallToolError> {
-        let url = format!(
-            "https://raw.githubusercontent.com/NixOS/nix/master/doc/manual/source/{}.md",
-            self.path
-        );
-
-        let resp = ureq::get(&url).call().map_err(CallToolError::new)?;
-        let body = resp.into_string().map_err(CallToolError::new)?;
-
+        let body = read_nix_manual(&self.path).map_err(CallToolError::new)?;
         Ok(CallToolResult::text_content(vec![TextContent::from(body)]))
     }
 }
@@ -544,15 +1578,18 @@ pub struct NixOSWikiSearchTool {
 impl NixOSWikiSearchTool {
     pub fn call_tool(&self) -> Result<CallToolResult, CallToolError> {
         // GET https://wiki.nixos.org/w/api.php?action=query&list=search&srsearch=<query>&format=json
-        let resp = ureq::get("https://wiki.nixos.org/w/api.php")
-            .query("action", "query")
-            .query("list", "search")
-            .query("srsearch", &self.query)
-            .query("format", "json")
-            .call()
-            .map_err(CallToolError::new)?;
-
-        let body = resp.into_string().map_err(CallToolError::new)?;
+        let body = cached("nixos_wiki_search", &self.query, || {
+            let _permit = limits::http().acquire()?;
+            let resp = ureq::get("https://wiki.nixos.org/w/api.php")
+                .query("action", "query")
+                .query("list", "search")
+                .query("srsearch", &self.query)
+                .query("format", "json")
+                .call()
+                .map_err(Error::other)?;
+            resp.into_string()
+        })
+        .map_err(CallToolError::new)?;
         Ok(CallToolResult::text_content(vec![TextContent::from(body)]))
     }
 }
@@ -601,10 +1638,28 @@ impl NixOSWikiReadTool {
             encoded_title
         );
 
-        let resp = ureq::get(&url).call().map_err(CallToolError::new)?;
-        let status = resp.status();
-        let status_text = resp.status_text().to_string();
-        let body = resp.into_string().map_err(CallToolError::new)?;
+        let body = cached("nixos_wiki_page", &self.title, || {
+            let _permit = limits::http().acquire()?;
+            let resp = ureq::get(&url).call().map_err(Error::other)?;
+            let status = resp.status();
+            let status_text = resp.status_text().to_string();
+            let body = resp.into_string()?;
+
+            // Validate before caching so that error pages never end up in the cache.
+            if let Err(e) = serde_json::from_str::<serde_json::Value>(&body) {
+                let preview = if body.len() > 500 {
+                    &body[..500]
+                } else {
+                    &body
+                };
+                return Err(Error::other(format!(
+                    "wiki returned invalid json (status {} {}): {}. Body preview: {}",
+                    status, status_text, e, preview
+                )));
+            }
+            Ok(body)
+        })
+        .map_err(CallToolError::new)?;
 
         match serde_json::from_str::<serde_json::Value>(&body) {
             Ok(val) => {
@@ -621,18 +1676,7 @@ impl NixOSWikiReadTool {
                     Err(CallToolError::new(err))
                 }
             }
-            Err(e) => {
-                let preview = if body.len() > 500 {
-                    &body[..500]
-                } else {
-                    &body
-                };
-                let err = Error::other(format!(
-                    "wiki returned invalid json (status {} {}): {}. Body preview: {}",
-                    status, status_text, e, preview
-                ));
-                Err(CallToolError::new(err))
-            }
+            Err(e) => Err(CallToolError::new(e)),
         }
     }
 }
@@ -654,10 +1698,8 @@ pub struct ManixSearchTool {
 impl ManixSearchTool {
     pub fn call_tool(&self) -> Result<CallToolResult, CallToolError> {
         // Run: nix run nixpkgs#manix -- <query>
-        let output = Command::new("nix")
+        let output = nix::command()
             .args([
-                "--extra-experimental-features",
-                "nix-command flakes",
                 "run",
                 "nixpkgs#manix",
                 "--",
@@ -667,12 +1709,7 @@ impl ManixSearchTool {
             .map_err(CallToolError::new)?;
 
         if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-            let err = Error::other(format!(
-                r#"manix failed (status: {}): {}"#,
-                output.status, stderr
-            ));
-            return Err(CallToolError::new(err));
+            return Err(CallToolError::new(nix_failure("manix", &output)));
         }
 
         let stdout = String::from_utf8(output.stdout).map_err(CallToolError::new)?;
@@ -700,27 +1737,27 @@ impl NixHubPackageVersionsTool {
         let url = format!(
             "https://www.nixhub.io/packages/{package}?_data=routes%2F_nixhub.packages.%24pkg._index"
         );
-        let resp = ureq::get(&url)
-            .set(
-                "User-Agent",
-                "rime/1.0 (+https://github.com/lukasl-dev/rime)",
-            )
-            .set("Accept", "application/json")
-            .call()
-            .map_err(CallToolError::new)?;
-
-        if resp.status() == 404 {
-            return Err(CallToolError::new(Error::other(
-                "package not found on nixhub",
-            )));
-        }
-        if resp.status() >= 500 {
-            return Err(CallToolError::new(Error::other(
-                "nixhub service temporarily unavailable",
-            )));
-        }
+        let body = cached("nixhub", package, || {
+            let _permit = limits::http().acquire()?;
+            let resp = ureq::get(&url)
+                .set(
+                    "User-Agent",
+                    "rime/1.0 (+https://github.com/lukasl-dev/rime)",
+                )
+                .set("Accept", "application/json")
+                .call()
+                .map_err(Error::other)?;
+
+            if resp.status() == 404 {
+                return Err(Error::other("package not found on nixhub"));
+            }
+            if resp.status() >= 500 {
+                return Err(Error::other("nixhub service temporarily unavailable"));
+            }
 
-        let body = resp.into_string().map_err(CallToolError::new)?;
+            resp.into_string()
+        })
+        .map_err(CallToolError::new)?;
         Ok(CallToolResult::text_content(vec![TextContent::from(body)]))
     }
 }
@@ -789,10 +1826,30 @@ tool_box!(
     [
         NixEvaluateTool,
         NixLogTool,
+        NixReplTool,
+        NixBuildTool,
+        NixHashMismatchTool,
+        NixPrefetchTool,
+        NixHashConvertTool,
+        NixStorePathParseTool,
+        NixDerivationShowTool,
+        NixDerivationDiffTool,
+        NixPathInfoTool,
+        NixClosureGraphTool,
+        NixStoreDiffClosuresTool,
+        NixStoreLsTool,
+        NixStoreCatTool,
+        NixCacheCheckTool,
         NixPackagesSearchTool,
         NixPackagesWhyDepends,
         NixFlakesShowTool,
         NixFlakesMetadataTool,
+        NixFlakesCheckTool,
+        NixFlakesLockTool,
+        NixFlakesUpdatePreviewTool,
+        NixRegistryListTool,
+        NixRegistryResolveTool,
+        NixRegistryPinTool,
         NixConfigCheckTool,
         NixConfigShowTool,
         NixManualListTool,